@@ -1,10 +1,11 @@
 use std::{
     cell::Cell,
-    mem::{size_of, MaybeUninit},
+    ffi::{CStr, CString, NulError},
+    mem::size_of,
     os::raw::*,
+    path::PathBuf,
 };
 
-use crate::constants::*;
 use bitvec::prelude::*;
 use crossbeam_utils::sync::Parker;
 use eyre::{eyre, Result, WrapErr};
@@ -12,6 +13,9 @@ use libretro_defs as lr;
 use once_cell::sync::OnceCell;
 use smallvec::SmallVec;
 
+#[cfg(test)]
+pub mod mock;
+
 const fn make_keyboard_descriptor(
     id: lr::retro_key,
     description: *const c_char,
@@ -25,8 +29,87 @@ const fn make_keyboard_descriptor(
     }
 }
 
+/// The keyboard key and description for each Chip-8 key, indexed by Chip-8 key.
+const KEYBOARD_KEYS: [(lr::retro_key, *const c_char); 16] = [
+    (lr::retro_key::RETROK_0, c_str!("0")),
+    (lr::retro_key::RETROK_1, c_str!("1")),
+    (lr::retro_key::RETROK_2, c_str!("2")),
+    (lr::retro_key::RETROK_3, c_str!("3")),
+    (lr::retro_key::RETROK_4, c_str!("4")),
+    (lr::retro_key::RETROK_5, c_str!("5")),
+    (lr::retro_key::RETROK_6, c_str!("6")),
+    (lr::retro_key::RETROK_7, c_str!("7")),
+    (lr::retro_key::RETROK_8, c_str!("8")),
+    (lr::retro_key::RETROK_9, c_str!("9")),
+    (lr::retro_key::RETROK_a, c_str!("a")),
+    (lr::retro_key::RETROK_b, c_str!("b")),
+    (lr::retro_key::RETROK_c, c_str!("c")),
+    (lr::retro_key::RETROK_d, c_str!("d")),
+    (lr::retro_key::RETROK_e, c_str!("e")),
+    (lr::retro_key::RETROK_f, c_str!("f")),
+];
+
+/// Input descriptors for [KEYBOARD_KEYS], built at compile time so that the entry with a null
+/// description which must terminate the array can't be left out.
+const INPUT_DESCRIPTORS: [lr::retro_input_descriptor; KEYBOARD_KEYS.len() + 1] = {
+    let mut descriptors = [lr::retro_input_descriptor {
+        port: 0,
+        device: 0,
+        index: 0,
+        id: 0,
+        description: std::ptr::null(),
+    }; KEYBOARD_KEYS.len() + 1];
+    let mut i = 0;
+    while i < KEYBOARD_KEYS.len() {
+        let (id, description) = KEYBOARD_KEYS[i];
+        descriptors[i] = make_keyboard_descriptor(id, description);
+        i += 1;
+    }
+    descriptors
+};
+
 static INPUT_KEY_IDS: OnceCell<SmallVec<[c_uint; 16]>> = OnceCell::new();
 
+/// The Chip-8 key pressed by each joypad button, indexed by `RETRO_DEVICE_ID_JOYPAD_*`. The d-pad
+/// maps to 2/4/6/8, which is what most games use for movement.
+const JOYPAD_KEYS: [usize; 16] = [
+    0x0, // B
+    0x3, // Y
+    0xE, // Select
+    0xF, // Start
+    0x2, // Up
+    0x8, // Down
+    0x4, // Left
+    0x6, // Right
+    0x5, // A
+    0x1, // X
+    0x7, // L
+    0x9, // R
+    0xA, // L2
+    0xB, // R2
+    0xC, // L3
+    0xD, // R3
+];
+
+/// Wrapper that allows LibRetro structs containing pointers to be stored in statics.
+struct StaticFfi<T>(T);
+
+// SAFETY: Only used for structs whose pointers point to immutable static data
+unsafe impl<T> Sync for StaticFfi<T> {}
+
+/// Device types that can be plugged into the controller port. The frontend may hold on to these
+/// pointers, so they must be statically allocated.
+static CONTROLLER_DESCRIPTIONS: StaticFfi<[lr::retro_controller_description; 2]> = StaticFfi([
+    lr::retro_controller_description {
+        desc: c_str!("Chip-8 Keypad"),
+        id: lr::RETRO_DEVICE_KEYBOARD,
+    },
+    lr::retro_controller_description {
+        desc: c_str!("Joypad"),
+        id: lr::RETRO_DEVICE_JOYPAD,
+    },
+]);
+
 thread_local! {
     static ENVIRONMENT: Cell<lr::retro_environment_t> = Cell::new(None);
     static VIDEO_REFRESH: Cell<lr::retro_video_refresh_t> = Cell::new(None);
@@ -34,6 +117,9 @@ thread_local! {
     static AUDIO_SAMPLE_BATCH: Cell<lr::retro_audio_sample_batch_t> = Cell::new(None);
     static INPUT_POLL: Cell<lr::retro_input_poll_t> = Cell::new(None);
     static INPUT_STATE: Cell<lr::retro_input_state_t> = Cell::new(None);
+    static INPUT_BITMASKS: Cell<bool> = const { Cell::new(false) };
+    /// Device type plugged into the controller port, one of [CONTROLLER_DESCRIPTIONS]
+    static CONTROLLER_DEVICE: Cell<c_uint> = const { Cell::new(lr::RETRO_DEVICE_JOYPAD) };
 }
 
 // Initializers
@@ -92,10 +178,24 @@ unsafe fn env_raw<T>(cmd: c_uint, data: *mut T) -> Result<()> {
 
 // SAFETY: Caller needs to ensure that the return type T is the appropriate
 // type associated with `cmd`.
-pub unsafe fn env_get<T>(cmd: c_uint) -> Result<T> {
-    let mut wrapper = MaybeUninit::uninit();
-    env_raw(cmd, wrapper.as_mut_ptr())?;
-    Ok(wrapper.assume_init())
+//
+// The frontend writes into `initial`, which must be a valid value (e.g. null or zero) so that a
+// frontend which reports success without writing all of it can't leave uninitialized memory behind.
+unsafe fn env_get<T>(cmd: c_uint, initial: T) -> Result<T> {
+    let mut value = initial;
+    env_raw(cmd, &mut value)?;
+    Ok(value)
+}
+
+/// Gets the frontend's logging interface.
+pub fn env_get_log_interface() -> Result<lr::retro_log_callback> {
+    unsafe {
+        env_get(
+            lr::RETRO_ENVIRONMENT_GET_LOG_INTERFACE,
+            lr::retro_log_callback { log: None },
+        )
+        .wrap_err("failed to get retro log interface")
+    }
 }
 
 pub fn env_set_pixel_format(mut pixel_format: lr::retro_pixel_format) -> Result<()> {
@@ -105,13 +205,250 @@ pub fn env_set_pixel_format(mut pixel_format: lr::retro_pixel_format) -> Result<
     }
 }
 
+/// Gets the refresh rate of the frontend's display, in Hz.
+pub fn env_get_target_refresh_rate() -> Result<f32> {
+    unsafe {
+        env_get(lr::RETRO_ENVIRONMENT_GET_TARGET_REFRESH_RATE, 0.0f32)
+            .wrap_err("failed to get target refresh rate")
+    }
+    .and_then(|rate| match rate.is_finite() && rate > 0.0 {
+        true => Ok(rate),
+        false => Err(eyre!("invalid target refresh rate {rate}")),
+    })
+}
+
+/// Gets the frontend's system directory, where BIOS files and other core assets are kept.
+pub fn env_get_system_directory() -> Option<PathBuf> {
+    unsafe { env_get_directory(lr::RETRO_ENVIRONMENT_GET_SYSTEM_DIRECTORY) }
+}
+
+/// Gets the frontend's save directory, where save files and other game data are written.
+pub fn env_get_save_directory() -> Option<PathBuf> {
+    unsafe { env_get_directory(lr::RETRO_ENVIRONMENT_GET_SAVE_DIRECTORY) }
+}
+
+// SAFETY: `cmd` must be an environment command which gets a path string.
+unsafe fn env_get_directory(cmd: c_uint) -> Option<PathBuf> {
+    // The frontend may fail the command or succeed but leave the path null/empty if unset
+    let path = env_get(cmd, std::ptr::null::<c_char>()).ok()?;
+    if path.is_null() {
+        return None;
+    }
+    let path = CStr::from_ptr(path).to_string_lossy();
+    (!path.is_empty()).then(|| PathBuf::from(&*path))
+}
+
+/// Registers core options with the frontend.
+///
+/// Each variable is a (key, "Description; value1|value2|...") pair.
+pub fn env_set_variables(variables: &[(&str, &str)]) -> Result<()> {
+    let c_strings = variables
+        .iter()
+        .map(|&(key, value)| Ok((CString::new(key)?, CString::new(value)?)))
+        .collect::<Result<Vec<_>, NulError>>()
+        .wrap_err("invalid core option definition")?;
+
+    // The array must end in an entry containing a null key and value
+    let mut retro_variables: Vec<lr::retro_variable> = c_strings
+        .iter()
+        .map(|(key, value)| lr::retro_variable {
+            key: key.as_ptr(),
+            value: value.as_ptr(),
+        })
+        .chain(std::iter::once(lr::retro_variable {
+            key: std::ptr::null(),
+            value: std::ptr::null(),
+        }))
+        .collect();
+
+    unsafe {
+        env_raw(
+            lr::RETRO_ENVIRONMENT_SET_VARIABLES,
+            retro_variables.as_mut_ptr(),
+        )
+        .wrap_err("failed to set core option variables")
+    }
+}
+
+/// Gets the version of the core options interface supported by the frontend.
+///
+/// Returns 0 if only [env_set_variables] is supported.
+pub fn env_get_core_options_version() -> c_uint {
+    unsafe { env_get::<c_uint>(lr::RETRO_ENVIRONMENT_GET_CORE_OPTIONS_VERSION, 0).unwrap_or(0) }
+}
+
+/// A core option definition for [env_set_core_options_v2].
+pub struct CoreOptionV2<'a> {
+    pub key: &'a str,
+    /// Key of the category the option is shown in, if any
+    pub category: Option<&'a str>,
+    pub desc: &'a str,
+    /// Possible values of the option, the first of which is the default
+    pub values: Vec<&'a str>,
+}
+
+/// Registers core options, grouped into categories, with the frontend.
+///
+/// Each category is a (key, description) pair. This requires core options version 2 or later
+/// (see [env_get_core_options_version]).
+pub fn env_set_core_options_v2(
+    categories: &[(&str, &str)],
+    definitions: &[CoreOptionV2],
+) -> Result<()> {
+    // Owns the strings pointed to by the structs below until the frontend has copied them
+    let mut strings: Vec<CString> = Vec::new();
+    let mut c_str = |s: &str| -> Result<*const c_char> {
+        strings.push(CString::new(s).wrap_err("invalid core option definition")?);
+        Ok(strings.last().unwrap().as_ptr())
+    };
+
+    // Both arrays must end in an entry containing a null key
+    let mut retro_categories = categories
+        .iter()
+        .map(|&(key, desc)| {
+            Ok(lr::retro_core_option_v2_category {
+                key: c_str(key)?,
+                desc: c_str(desc)?,
+                info: std::ptr::null(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    retro_categories.push(lr::retro_core_option_v2_category {
+        key: std::ptr::null(),
+        desc: std::ptr::null(),
+        info: std::ptr::null(),
+    });
+
+    let null_value = lr::retro_core_option_value {
+        value: std::ptr::null(),
+        label: std::ptr::null(),
+    };
+    let mut retro_definitions = Vec::with_capacity(definitions.len() + 1);
+    for definition in definitions.iter() {
+        // The values array must also end in an entry containing a null value
+        let mut values = [null_value; lr::RETRO_NUM_CORE_OPTION_VALUES_MAX as usize];
+        if definition.values.len() >= values.len() {
+            return Err(eyre!("core option {} has too many values", definition.key));
+        }
+        for (value, &name) in values.iter_mut().zip(definition.values.iter()) {
+            value.value = c_str(name)?;
+        }
+
+        retro_definitions.push(lr::retro_core_option_v2_definition {
+            key: c_str(definition.key)?,
+            desc: c_str(definition.desc)?,
+            desc_categorized: std::ptr::null(),
+            info: std::ptr::null(),
+            info_categorized: std::ptr::null(),
+            category_key: definition
+                .category
+                .map_or(Ok(std::ptr::null()), &mut c_str)?,
+            values,
+            default_value: values[0].value,
+        });
+    }
+    retro_definitions.push(lr::retro_core_option_v2_definition {
+        key: std::ptr::null(),
+        desc: std::ptr::null(),
+        desc_categorized: std::ptr::null(),
+        info: std::ptr::null(),
+        info_categorized: std::ptr::null(),
+        category_key: std::ptr::null(),
+        values: [null_value; lr::RETRO_NUM_CORE_OPTION_VALUES_MAX as usize],
+        default_value: std::ptr::null(),
+    });
+
+    let mut options = lr::retro_core_options_v2 {
+        categories: retro_categories.as_mut_ptr(),
+        definitions: retro_definitions.as_mut_ptr(),
+    };
+    unsafe {
+        env_raw(lr::RETRO_ENVIRONMENT_SET_CORE_OPTIONS_V2, &mut options)
+            .wrap_err("failed to set core options")
+    }
+}
+
+/// Gets the current value of a core option from the frontend, if it has one.
+pub fn env_get_variable(key: &str) -> Result<Option<String>> {
+    let c_key = CString::new(key).wrap_err("invalid core option key")?;
+    let mut variable = lr::retro_variable {
+        key: c_key.as_ptr(),
+        value: std::ptr::null(),
+    };
+
+    unsafe {
+        env_raw(lr::RETRO_ENVIRONMENT_GET_VARIABLE, &mut variable)
+            .wrap_err_with(|| format!("failed to get core option {key}"))?;
+
+        // The value string is owned by the frontend, so copy it out right away
+        Ok(match variable.value.is_null() {
+            true => None,
+            false => Some(
+                CStr::from_ptr(variable.value)
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+        })
+    }
+}
+
+/// Returns true if any core option has changed since the last call to [env_get_variable].
+pub fn env_get_variable_update() -> bool {
+    unsafe { env_get(lr::RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE, false).unwrap_or(false) }
+}
+
+/// Shows a message on screen for the given number of frames, if the frontend supports it.
+pub fn env_set_message(message: &str, frames: usize) {
+    let Ok(c_message) = CString::new(message) else {
+        tracing::warn!("invalid on-screen message {message:?}");
+        return;
+    };
+    let mut retro_message = lr::retro_message {
+        msg: c_message.as_ptr(),
+        frames: frames as c_uint,
+    };
+
+    unsafe {
+        if let Err(e) = env_raw(lr::RETRO_ENVIRONMENT_SET_MESSAGE, &mut retro_message) {
+            tracing::debug!("{:#}", e);
+        }
+    }
+}
+
+/// Registers a callback that the frontend calls before each frame with the time elapsed since the
+/// previous one, given the `reference` frame time in microseconds for the nominal frame rate.
+pub fn env_set_frame_time_callback(
+    callback: unsafe extern "C" fn(lr::retro_usec_t),
+    reference: lr::retro_usec_t,
+) -> Result<()> {
+    let mut frame_time_callback = lr::retro_frame_time_callback {
+        callback: Some(callback),
+        reference,
+    };
+
+    unsafe {
+        env_raw(
+            lr::RETRO_ENVIRONMENT_SET_FRAME_TIME_CALLBACK,
+            &mut frame_time_callback,
+        )
+        .wrap_err("failed to set frame time callback")
+    }
+}
+
 /// Instruct the frontend to shutdown.
 ///
-/// This is useful to more gracefully shutdown everything in case of an unrecoverable error.
+/// This is useful to more gracefully shutdown everything in case of an unrecoverable error. Panics
+/// with the message if the frontend can't be told to shut down. In tests, it also panics once the
+/// mock frontend has been told, rather than parking the test's thread forever.
 pub fn env_shutdown<S: AsRef<str>>(message: S) -> ! {
     tracing::error!("{}", message.as_ref());
     unsafe {
-        env_raw::<c_void>(lr::RETRO_ENVIRONMENT_SHUTDOWN, std::ptr::null_mut()).unwrap();
+        if let Err(e) = env_raw::<c_void>(lr::RETRO_ENVIRONMENT_SHUTDOWN, std::ptr::null_mut()) {
+            panic!("{}: {:#}", message.as_ref(), e);
+        }
+    }
+    if cfg!(test) {
+        panic!("{}: shutdown requested", message.as_ref());
     }
     // Park this thread
     let p = Parker::new();
@@ -119,16 +456,17 @@ pub fn env_shutdown<S: AsRef<str>>(message: S) -> ! {
     panic!("thread unparked spontaneously");
 }
 
-pub fn video_refresh<T: AsRef<[u16; NUM_PIXELS]>>(buffer: &T) {
+pub fn video_refresh(buffer: &[u16], width: usize, height: usize) {
+    debug_assert_eq!(buffer.len(), width * height, "framebuffer size mismatch");
     unsafe {
         let func = VIDEO_REFRESH
             .with(|cell| cell.get())
             .expect("VIDEO_REFRESH callback not initialized");
         func(
-            buffer.as_ref().as_ptr() as *const c_void,
-            SCREEN_WIDTH as c_uint,
-            SCREEN_HEIGHT as c_uint,
-            (SCREEN_WIDTH * size_of::<u16>()) as lr::size_t,
+            buffer.as_ptr() as *const c_void,
+            width as c_uint,
+            height as c_uint,
+            (width * size_of::<u16>()) as lr::size_t,
         );
     }
 }
@@ -142,7 +480,7 @@ pub fn audio_sample_batch(sample_data: &[i16]) {
 
         // `sample_data` is composed of pairs of left and right samples.
         // One audio frame is 2 samples (left and right).
-        assert_eq!(sample_data.len() % 2, 0);
+        debug_assert_eq!(sample_data.len() % 2, 0);
         let num_audio_frames = (sample_data.len() / 2) as lr::size_t;
         func(sample_data.as_ptr(), num_audio_frames);
     }
@@ -159,40 +497,10 @@ pub fn input_poll() {
 
 /// Set libretro input descriptors
 pub fn env_set_input_descriptors() {
-    type TrustyChipInputDescriptors = [lr::retro_input_descriptor; 17];
-    let mut input_descriptors: Box<TrustyChipInputDescriptors> = Box::new([
-        make_keyboard_descriptor(lr::retro_key::RETROK_0, c_str!("0")),
-        make_keyboard_descriptor(lr::retro_key::RETROK_1, c_str!("1")),
-        make_keyboard_descriptor(lr::retro_key::RETROK_2, c_str!("2")),
-        make_keyboard_descriptor(lr::retro_key::RETROK_3, c_str!("3")),
-        make_keyboard_descriptor(lr::retro_key::RETROK_4, c_str!("4")),
-        make_keyboard_descriptor(lr::retro_key::RETROK_5, c_str!("5")),
-        make_keyboard_descriptor(lr::retro_key::RETROK_6, c_str!("6")),
-        make_keyboard_descriptor(lr::retro_key::RETROK_7, c_str!("7")),
-        make_keyboard_descriptor(lr::retro_key::RETROK_8, c_str!("8")),
-        make_keyboard_descriptor(lr::retro_key::RETROK_9, c_str!("9")),
-        make_keyboard_descriptor(lr::retro_key::RETROK_a, c_str!("a")),
-        make_keyboard_descriptor(lr::retro_key::RETROK_b, c_str!("b")),
-        make_keyboard_descriptor(lr::retro_key::RETROK_c, c_str!("c")),
-        make_keyboard_descriptor(lr::retro_key::RETROK_d, c_str!("d")),
-        make_keyboard_descriptor(lr::retro_key::RETROK_e, c_str!("e")),
-        make_keyboard_descriptor(lr::retro_key::RETROK_f, c_str!("f")),
-        lr::retro_input_descriptor {
-            port: 0,
-            device: 0,
-            index: 0,
-            id: 0,
-            description: std::ptr::null(),
-        },
-    ]);
-
-    assert!(
-        input_descriptors.last().unwrap().description.is_null(),
-        "input descriptors array must end in entry containing null description"
-    );
+    let mut input_descriptors = Box::new(INPUT_DESCRIPTORS);
 
     // Ignore the Result as an Err just means that this was already initialized
-    let _ = INPUT_KEY_IDS.set(input_descriptors.iter().take(16).map(|d| d.id).collect());
+    let _ = INPUT_KEY_IDS.set(KEYBOARD_KEYS.iter().map(|&(id, _)| id as c_uint).collect());
 
     unsafe {
         env_raw(
@@ -203,15 +511,283 @@ pub fn env_set_input_descriptors() {
     }
 }
 
+/// Describe the supported controller types to the frontend.
+pub fn env_set_controller_info() -> Result<()> {
+    let mut controller_info = controller_info();
+
+    unsafe {
+        env_raw(
+            lr::RETRO_ENVIRONMENT_SET_CONTROLLER_INFO,
+            controller_info.as_mut_ptr(),
+        )
+        .wrap_err("failed to set controller info")
+    }
+}
+
+/// Returns the controller info array, with one entry per port terminated by an entry with null
+/// types.
+fn controller_info() -> [lr::retro_controller_info; 2] {
+    [
+        lr::retro_controller_info {
+            types: CONTROLLER_DESCRIPTIONS.0.as_ptr(),
+            num_types: CONTROLLER_DESCRIPTIONS.0.len() as c_uint,
+        },
+        lr::retro_controller_info {
+            types: std::ptr::null(),
+            num_types: 0,
+        },
+    ]
+}
+
+/// Selects the device type plugged into the controller port, as chosen by the user in the frontend.
+///
+/// With the Chip-8 keypad selected only the keyboard is read, so that stray joypad input can't
+/// press keys. With the joypad selected both are read, as libretro doesn't tie the keyboard to a
+/// port.
+pub fn set_controller_device(port: c_uint, device: c_uint) {
+    if port != 0 {
+        tracing::debug!("ignoring device {device} for unused controller port {port}");
+        return;
+    }
+    if !CONTROLLER_DESCRIPTIONS
+        .0
+        .iter()
+        .any(|desc| desc.id == device)
+    {
+        tracing::warn!("unsupported controller device {device}; keeping the current device");
+        return;
+    }
+
+    tracing::debug!("controller device set to {device}");
+    CONTROLLER_DEVICE.with(|cell| cell.set(device));
+}
+
+/// Checks whether the frontend can report all joypad buttons at once as a bitmask.
+pub fn init_input_bitmasks() {
+    let supported = unsafe {
+        env_raw::<c_void>(
+            lr::RETRO_ENVIRONMENT_GET_INPUT_BITMASKS,
+            std::ptr::null_mut(),
+        )
+        .is_ok()
+    };
+    tracing::debug!("frontend input bitmask support: {supported}");
+    INPUT_BITMASKS.with(|cell| cell.set(supported));
+}
+
+/// Returns the pressed state of each Chip-8 key, combining the keyboard and (if it's the selected
+/// controller device) the joypad.
 pub fn get_input_states() -> BitVec {
     let input_state = INPUT_STATE
         .with(|cell| cell.get())
         .expect("INPUT_STATE callback not initialized");
 
-    INPUT_KEY_IDS
+    let mut states: BitVec = INPUT_KEY_IDS
         .get()
         .expect("INPUT_KEY_IDS not initialized")
         .iter()
         .map(|&id| unsafe { input_state(0, lr::RETRO_DEVICE_KEYBOARD, 0, id) != 0 })
-        .collect()
+        .collect();
+
+    if CONTROLLER_DEVICE.with(|cell| cell.get()) != lr::RETRO_DEVICE_JOYPAD {
+        return states;
+    }
+
+    let buttons = get_joypad_buttons(input_state);
+    for (button, &key) in JOYPAD_KEYS.iter().enumerate() {
+        if buttons & (1 << button) != 0 {
+            states.set(key, true);
+        }
+    }
+
+    states
+}
+
+/// Returns the position of the frontend's pointer (e.g. a touch) if it's pressed, in coordinates
+/// ranging from -0x7fff to 0x7fff across the screen.
+pub fn get_pointer() -> Option<(i16, i16)> {
+    let input_state = INPUT_STATE
+        .with(|cell| cell.get())
+        .expect("INPUT_STATE callback not initialized");
+    let query = |id| unsafe { input_state(0, lr::RETRO_DEVICE_POINTER, 0, id) };
+
+    (query(lr::RETRO_DEVICE_ID_POINTER_PRESSED) != 0).then(|| {
+        (
+            query(lr::RETRO_DEVICE_ID_POINTER_X),
+            query(lr::RETRO_DEVICE_ID_POINTER_Y),
+        )
+    })
+}
+
+/// Returns the joypad buttons pressed as a bitmask indexed by `RETRO_DEVICE_ID_JOYPAD_*`.
+///
+/// Uses a single bitmask query if the frontend supports it, falling back to one query per button.
+fn get_joypad_buttons(
+    input_state: unsafe extern "C" fn(c_uint, c_uint, c_uint, c_uint) -> i16,
+) -> u16 {
+    unsafe {
+        if INPUT_BITMASKS.with(|cell| cell.get()) {
+            input_state(
+                0,
+                lr::RETRO_DEVICE_JOYPAD,
+                0,
+                lr::RETRO_DEVICE_ID_JOYPAD_MASK,
+            ) as u16
+        } else {
+            (0..JOYPAD_KEYS.len() as c_uint)
+                .filter(|&id| input_state(0, lr::RETRO_DEVICE_JOYPAD, 0, id) != 0)
+                .fold(0, |buttons, id| buttons | 1 << id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn controller_info_is_well_formed() {
+        let [port, terminator] = controller_info();
+
+        assert_eq!(port.num_types as usize, CONTROLLER_DESCRIPTIONS.0.len());
+        let types = unsafe { std::slice::from_raw_parts(port.types, port.num_types as usize) };
+        for description in types {
+            assert!(!description.desc.is_null());
+            let name = unsafe { CStr::from_ptr(description.desc) };
+            assert!(!name.to_bytes().is_empty());
+        }
+        assert!(types
+            .iter()
+            .any(|desc| desc.id == lr::RETRO_DEVICE_KEYBOARD));
+        assert!(types.iter().any(|desc| desc.id == lr::RETRO_DEVICE_JOYPAD));
+
+        assert!(terminator.types.is_null());
+        assert_eq!(terminator.num_types, 0);
+    }
+
+    #[test]
+    fn joypad_bitmask_matches_per_button_queries() {
+        let _guard = mock::install();
+        let input_state = INPUT_STATE.with(|cell| cell.get()).unwrap();
+
+        for buttons in [0, 1, 1 << 8 | 1 << 4, 0x8001, 0xFFFF] {
+            mock::set_buttons(buttons);
+            INPUT_BITMASKS.with(|cell| cell.set(false));
+            let per_button = get_joypad_buttons(input_state);
+            INPUT_BITMASKS.with(|cell| cell.set(true));
+            let bitmask = get_joypad_buttons(input_state);
+
+            assert_eq!(per_button, buttons);
+            assert_eq!(bitmask, per_button);
+        }
+    }
+
+    #[test]
+    fn selected_controller_device_is_honored() {
+        let _guard = mock::install();
+        // A maps to key 5, and keyboard key 3 is held
+        mock::set_buttons(1 << 8);
+        mock::set_keys(1 << 3);
+
+        let pressed = |states: BitVec| states.iter_ones().collect::<Vec<_>>();
+        assert_eq!(pressed(get_input_states()), [3, 5]);
+
+        set_controller_device(0, lr::RETRO_DEVICE_KEYBOARD);
+        assert_eq!(pressed(get_input_states()), [3]);
+
+        // Unsupported devices and other ports are ignored
+        set_controller_device(0, lr::RETRO_DEVICE_POINTER);
+        set_controller_device(1, lr::RETRO_DEVICE_JOYPAD);
+        assert_eq!(pressed(get_input_states()), [3]);
+
+        set_controller_device(0, lr::RETRO_DEVICE_JOYPAD);
+        assert_eq!(pressed(get_input_states()), [3, 5]);
+    }
+
+    #[test]
+    fn directories_are_read_from_the_frontend() {
+        let _guard = mock::install();
+        assert_eq!(env_get_system_directory(), None);
+        assert_eq!(env_get_save_directory(), None);
+
+        // The save directory is reported as set but left null, and an empty path means unset too
+        mock::set_environment(|cmd, data| unsafe {
+            match cmd {
+                lr::RETRO_ENVIRONMENT_GET_SYSTEM_DIRECTORY => {
+                    *(data as *mut *const c_char) = c_str!("/home/user/retroarch/system");
+                    true
+                }
+                lr::RETRO_ENVIRONMENT_GET_SAVE_DIRECTORY => true,
+                _ => false,
+            }
+        });
+        assert_eq!(
+            env_get_system_directory(),
+            Some(PathBuf::from("/home/user/retroarch/system"))
+        );
+        assert_eq!(env_get_save_directory(), None);
+
+        mock::set_environment(|cmd, data| unsafe {
+            *(data as *mut *const c_char) = c_str!("");
+            cmd == lr::RETRO_ENVIRONMENT_GET_SYSTEM_DIRECTORY
+        });
+        assert_eq!(env_get_system_directory(), None);
+    }
+
+    #[test]
+    fn input_descriptors_are_terminated_and_cover_every_key() {
+        let _guard = mock::install();
+        let sent = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        mock::set_environment({
+            let sent = sent.clone();
+            move |cmd, data| unsafe {
+                if cmd != lr::RETRO_ENVIRONMENT_SET_INPUT_DESCRIPTORS {
+                    return false;
+                }
+                // Read the array as a frontend would, up to the entry with a null description
+                let descriptors = data as *const lr::retro_input_descriptor;
+                let mut sent = sent.borrow_mut();
+                for i in 0.. {
+                    let descriptor = *descriptors.add(i);
+                    if descriptor.description.is_null() {
+                        break;
+                    }
+                    let description = CStr::from_ptr(descriptor.description);
+                    sent.push((
+                        descriptor.port,
+                        descriptor.device,
+                        descriptor.id,
+                        description.to_string_lossy().into_owned(),
+                    ));
+                }
+                true
+            }
+        });
+        env_set_input_descriptors();
+
+        let sent = sent.borrow();
+        assert_eq!(sent.len(), 16);
+        for (key, (port, device, id, description)) in sent.iter().enumerate() {
+            assert_eq!((*port, *device), (0, lr::RETRO_DEVICE_KEYBOARD));
+            assert_eq!(*id, KEYBOARD_KEYS[key].0 as c_uint);
+            assert_eq!(*description, format!("{key:x}"));
+        }
+        assert_eq!(INPUT_KEY_IDS.get().unwrap().len(), 16);
+        assert!(INPUT_DESCRIPTORS[16].description.is_null());
+    }
+
+    #[test]
+    fn commands_which_succeed_without_writing_give_defaults() {
+        let _guard = mock::install();
+        // A frontend which reports success for everything but never writes anything back
+        mock::set_environment(|_, _| true);
+
+        assert!(env_get_log_interface().unwrap().log.is_none());
+        assert!(env_get_target_refresh_rate().is_err());
+        assert_eq!(env_get_system_directory(), None);
+        assert_eq!(env_get_save_directory(), None);
+        assert_eq!(env_get_core_options_version(), 0);
+        assert!(!env_get_variable_update());
+        assert_eq!(env_get_variable("trustychip_invert").unwrap(), None);
+    }
 }
@@ -0,0 +1,177 @@
+//! A mock frontend for unit tests.
+//!
+//! [install] points the callbacks at functions which stand in for a frontend. The emulator state is
+//! global, so tests which use it must hold the guard returned by [install] until they finish.
+
+use std::{
+    cell::{Cell, RefCell},
+    mem,
+};
+
+use parking_lot::{const_mutex, Mutex, MutexGuard};
+
+use super::*;
+use crate::options;
+
+static LOCK: Mutex<()> = const_mutex(());
+
+thread_local! {
+    static KEYS: Cell<u16> = const { Cell::new(0) };
+    static BUTTONS: Cell<u16> = const { Cell::new(0) };
+    static POINTER: Cell<Option<(i16, i16)>> = const { Cell::new(None) };
+    static FRAMES: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+    static SAMPLES: RefCell<Vec<i16>> = const { RefCell::new(Vec::new()) };
+    static SHUTDOWN_REQUESTED: Cell<bool> = const { Cell::new(false) };
+    static ENVIRONMENT_HANDLER: RefCell<Option<Box<EnvironmentHandler>>> =
+        const { RefCell::new(None) };
+}
+
+/// Handles an environment command given its `cmd` and `data`, returning whether it succeeded.
+pub type EnvironmentHandler = dyn FnMut(c_uint, *mut c_void) -> bool;
+
+/// A video frame sent by the core.
+pub struct Frame {
+    pub pixels: Vec<u16>,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Installs the mock callbacks with no input, nothing recorded, default options, the default frame
+/// rate and no frame time reported, and returns a guard which serializes tests using the global
+/// emulator state.
+pub fn install() -> MutexGuard<'static, ()> {
+    let guard = LOCK.lock();
+
+    init_environment_cb(Some(environment));
+    init_video_refresh_cb(Some(video_refresh));
+    init_audio_sample_cb(Some(audio_sample));
+    init_audio_sample_batch_cb(Some(audio_sample_batch));
+    init_input_poll_cb(Some(input_poll));
+    init_input_state_cb(Some(input_state));
+    INPUT_BITMASKS.with(|cell| cell.set(false));
+    CONTROLLER_DEVICE.with(|cell| cell.set(lr::RETRO_DEVICE_JOYPAD));
+    let _ = INPUT_KEY_IDS.set(KEYBOARD_KEYS.iter().map(|&(id, _)| id as c_uint).collect());
+    KEYS.with(|cell| cell.set(0));
+    BUTTONS.with(|cell| cell.set(0));
+    POINTER.with(|cell| cell.set(None));
+    FRAMES.with(|cell| cell.borrow_mut().clear());
+    SAMPLES.with(|cell| cell.borrow_mut().clear());
+    SHUTDOWN_REQUESTED.with(|cell| cell.set(false));
+    ENVIRONMENT_HANDLER.with(|cell| *cell.borrow_mut() = None);
+    options::reset();
+    crate::core::select_frame_rate(None);
+    crate::core::register_frame_time_callback();
+
+    guard
+}
+
+/// Sets the keyboard keys held down, as a bitmask indexed by Chip-8 key.
+pub fn set_keys(keys: u16) {
+    KEYS.with(|cell| cell.set(keys));
+}
+
+/// Sets the joypad buttons held down, as a bitmask indexed by `RETRO_DEVICE_ID_JOYPAD_*`.
+pub fn set_buttons(buttons: u16) {
+    BUTTONS.with(|cell| cell.set(buttons));
+}
+
+/// Sets the position of the pointer if it's pressed, in the frontend's pointer coordinates which
+/// range from -0x7fff to 0x7fff across the screen.
+pub fn set_pointer(pointer: Option<(i16, i16)>) {
+    POINTER.with(|cell| cell.set(pointer));
+}
+
+/// Makes environment commands other than shutting down go to `handler`, which otherwise all fail.
+///
+/// The handler is called from an `extern "C"` function, so it must not panic: a panic there aborts
+/// the test run instead of failing the test. Record what it was given and check that afterwards.
+pub fn set_environment(handler: impl FnMut(c_uint, *mut c_void) -> bool + 'static) {
+    ENVIRONMENT_HANDLER.with(|cell| *cell.borrow_mut() = Some(Box::new(handler)));
+}
+
+/// Returns the video frames sent since the last call.
+pub fn take_frames() -> Vec<Frame> {
+    FRAMES.with(|cell| mem::take(&mut *cell.borrow_mut()))
+}
+
+/// Returns the interleaved stereo audio samples sent since the last call.
+pub fn take_samples() -> Vec<i16> {
+    SAMPLES.with(|cell| mem::take(&mut *cell.borrow_mut()))
+}
+
+/// Returns whether the core has asked the frontend to shut down.
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.with(|cell| cell.get())
+}
+
+unsafe extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    if cmd == lr::RETRO_ENVIRONMENT_SHUTDOWN {
+        SHUTDOWN_REQUESTED.with(|cell| cell.set(true));
+        return true;
+    }
+
+    ENVIRONMENT_HANDLER.with(|cell| {
+        cell.borrow_mut()
+            .as_mut()
+            .is_some_and(|handler| handler(cmd, data))
+    })
+}
+
+unsafe extern "C" fn video_refresh(
+    data: *const c_void,
+    width: c_uint,
+    height: c_uint,
+    pitch: lr::size_t,
+) {
+    let (width, height) = (width as usize, height as usize);
+    assert_eq!(pitch as usize, width * size_of::<u16>());
+    let pixels = std::slice::from_raw_parts(data as *const u16, width * height).to_vec();
+    FRAMES.with(|cell| {
+        cell.borrow_mut().push(Frame {
+            pixels,
+            width,
+            height,
+        })
+    });
+}
+
+unsafe extern "C" fn audio_sample(left: i16, right: i16) {
+    SAMPLES.with(|cell| cell.borrow_mut().extend([left, right]));
+}
+
+unsafe extern "C" fn audio_sample_batch(data: *const i16, frames: lr::size_t) -> lr::size_t {
+    let samples = std::slice::from_raw_parts(data, frames as usize * 2);
+    SAMPLES.with(|cell| cell.borrow_mut().extend_from_slice(samples));
+    frames
+}
+
+unsafe extern "C" fn input_poll() {}
+
+unsafe extern "C" fn input_state(port: c_uint, device: c_uint, _index: c_uint, id: c_uint) -> i16 {
+    if port != 0 {
+        return 0;
+    }
+
+    match device {
+        lr::RETRO_DEVICE_KEYBOARD => KEYBOARD_KEYS
+            .iter()
+            .position(|&(key, _)| key as c_uint == id)
+            .map_or(0, |key| (KEYS.with(|cell| cell.get()) >> key & 1) as i16),
+        lr::RETRO_DEVICE_JOYPAD => {
+            let buttons = BUTTONS.with(|cell| cell.get());
+            match id {
+                lr::RETRO_DEVICE_ID_JOYPAD_MASK => buttons as i16,
+                id if id < 16 => (buttons >> id & 1) as i16,
+                _ => 0,
+            }
+        }
+        lr::RETRO_DEVICE_POINTER => match (POINTER.with(|cell| cell.get()), id) {
+            (None, _) => 0,
+            (Some(_), lr::RETRO_DEVICE_ID_POINTER_PRESSED) => 1,
+            (Some((x, _)), lr::RETRO_DEVICE_ID_POINTER_X) => x,
+            (Some((_, y)), lr::RETRO_DEVICE_ID_POINTER_Y) => y,
+            _ => 0,
+        },
+        _ => 0,
+    }
+}
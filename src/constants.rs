@@ -1,4 +1,4 @@
-use static_assertions::const_assert_eq;
+use static_assertions::const_assert;
 
 /// Total Chip-8 memory available
 pub const TOTAL_MEMORY: usize = 0x1000;
@@ -13,6 +13,10 @@ pub const GAME_ADDRESS: usize = 0x200;
 /// Maximum size of Chip-8 game (calculated from [TOTAL_MEMORY] and [GAME_ADDRESS])
 pub const MAX_GAME_SIZE: usize = TOTAL_MEMORY - GAME_ADDRESS;
 
+/// Maximum size of content accepted from the frontend, as a sanity check on the reported size.
+/// This is well above [MAX_GAME_SIZE] to allow for multi-game files (whose offsets are 16-bit).
+pub const MAX_CONTENT_SIZE: usize = 2 * 0x10000;
+
 /// Screen width
 pub const SCREEN_WIDTH: usize = 64;
 
@@ -22,8 +26,22 @@ pub const SCREEN_HEIGHT: usize = 32;
 /// Number of pixels
 pub const NUM_PIXELS: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
 
-/// Video frame rate
-pub const FRAME_RATE: usize = 60;
+/// Largest factor the video output can be scaled by (see the internal scale core option)
+pub const MAX_INTERNAL_SCALE: usize = 4;
+
+/// Shader preset suggested by the shader hint core option, as found in the common libretro shader
+/// collections
+pub const CRT_SHADER_PRESET: &str = "crt/crt-easymode";
+
+/// Default video frame rate
+pub const DEFAULT_FRAME_RATE: usize = 60;
+
+/// Video frame rates the core can run at (each must evenly divide the timer rate)
+pub const SUPPORTED_FRAME_RATES: [usize; 4] = [60, 30, 20, 15];
+
+/// Largest number of frames the beep gap bridge can keep the buzzer sounding for (see the beep gap
+/// bridge core option)
+pub const MAX_BEEP_GAP_BRIDGE: usize = 8;
 
 /// Chip-8 timer cycle rate (this is always 60 Hz)
 pub const TIMER_CYCLE_RATE: usize = 60;
@@ -31,17 +49,42 @@ pub const TIMER_CYCLE_RATE: usize = 60;
 /// Audio samples per second
 pub const AUDIO_SAMPLE_RATE: usize = 18000;
 
-/// Chip-8 timer cycles per frame
-pub const TIMER_CYCLES_PER_FRAME: usize = TIMER_CYCLE_RATE / FRAME_RATE;
-
-/// Audio frames per video frame (calculated from [AUDIO_SAMPLE_RATE] and [FRAME_RATE])
-pub const AUDIO_FRAMES_PER_VIDEO_FRAME: usize = AUDIO_SAMPLE_RATE / FRAME_RATE;
-
 /// Buzzer frequency
 pub const BUZZER_FREQ: usize = 400;
 
-// Various compile-time assertions to make things work well/easily:
-const_assert_eq!(TIMER_CYCLE_RATE % FRAME_RATE, 0);
-const_assert_eq!(AUDIO_SAMPLE_RATE % FRAME_RATE, 0);
-const_assert_eq!(AUDIO_SAMPLE_RATE % TIMER_CYCLE_RATE, 0);
-const_assert_eq!(AUDIO_SAMPLE_RATE % BUZZER_FREQ, 0);
+/// Frequency of the error beep played on an invalid instruction (see the error beep core option)
+pub const ERROR_BEEP_FREQ: usize = 150;
+
+/// Length of the error beep in audio frames
+pub const ERROR_BEEP_FRAMES: usize = AUDIO_SAMPLE_RATE / 4;
+
+/// Number of seconds after which the draw watchdog warns if nothing has been drawn
+pub const DRAW_WATCHDOG_SECONDS: usize = 5;
+
+/// Milliseconds per video frame spent executing instructions at an unlimited CPU speed, unless a
+/// tick budget is set (see the CPU speed core option)
+pub const UNLIMITED_TICK_BUDGET_MS: u64 = 8;
+
+/// Number of seconds between logs of the clipped sprite tally (see the wrap diagnostic core option)
+pub const WRAP_DIAG_SECONDS: usize = 10;
+
+// Compile-time assertions for the relationships between rates that are actually required.
+//
+// The timers are decremented a whole number of times per video frame, so every frame rate must
+// divide the timer rate. The audio rates need no such relationship: fractional audio frames per
+// video frame are carried over between frames, and the buzzer phase wraps once per second, which
+// is seamless for any whole-Hz frequency. An error beep frequency that doesn't divide the sample
+// rate just has its half period truncated to whole samples.
+const_assert!(frame_rates_divide_evenly());
+
+const fn frame_rates_divide_evenly() -> bool {
+    let mut i = 0;
+    while i < SUPPORTED_FRAME_RATES.len() {
+        let frame_rate = SUPPORTED_FRAME_RATES[i];
+        if TIMER_CYCLE_RATE % frame_rate != 0 {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
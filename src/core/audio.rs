@@ -0,0 +1,238 @@
+use crate::constants::*;
+
+/// Buzzer audio generation state.
+///
+/// This lives in the emulator state (rather than in statics) so that the waveform phase is part
+/// of the machine state and so that a panic while generating audio can't leave a poisoned lock
+/// behind for every subsequent frame.
+#[derive(Default)]
+pub struct AudioState {
+    buffer: Vec<i16>,
+    /// Position in the buzzer waveform's cycle, in audio frames
+    pub(super) step: usize,
+    /// Video frames the buzzer keeps sounding for after the sound timer runs out
+    pub(super) holdover_frames: usize,
+    /// Audio frames owed from previous video frames, in units of 1/frame_rate of an audio frame
+    pub(super) frame_remainder: usize,
+    /// Audio frames of the error beep still to be output
+    error_beep_remaining: usize,
+}
+
+impl AudioState {
+    /// Returns whether the buzzer should sound for the current frame.
+    ///
+    /// The buzzer sounds whenever the sound timer is active. If `bridge_frames` is non-zero, it
+    /// also keeps sounding for up to that many frames after the sound timer runs out, so that
+    /// beeps separated by only a short gap merge into one continuous tone rather than a rattle.
+    pub fn buzzer_active(&mut self, sound_timer_active: bool, bridge_frames: usize) -> bool {
+        if sound_timer_active {
+            self.holdover_frames = bridge_frames;
+            true
+        } else if self.holdover_frames > 0 {
+            self.holdover_frames -= 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Restarts the buzzer waveform from the beginning of its cycle.
+    pub fn retrigger(&mut self) {
+        self.step = 0;
+    }
+
+    /// Starts playing the error beep, replacing the buzzer until it finishes.
+    pub fn start_error_beep(&mut self) {
+        self.error_beep_remaining = ERROR_BEEP_FRAMES;
+    }
+
+    /// Returns whether the error beep is still playing.
+    pub fn error_beep_active(&self) -> bool {
+        self.error_beep_remaining > 0
+    }
+
+    /// Generates `num_frames` audio frames of the error beep as interleaved stereo samples.
+    ///
+    /// The error beep is a low square wave, so that it can't be mistaken for the buzzer. Frames
+    /// past the end of the beep are silent.
+    pub fn generate_error_beep_batch(&mut self, num_frames: usize) -> &[i16] {
+        const HALF_PERIOD: usize = AUDIO_SAMPLE_RATE / (2 * ERROR_BEEP_FREQ);
+        const AMPLITUDE: i16 = i16::MAX / 4;
+
+        self.buffer.clear();
+        for _ in 0..num_frames {
+            let sample = match self.error_beep_remaining {
+                0 => 0,
+                remaining => {
+                    self.error_beep_remaining -= 1;
+                    match (ERROR_BEEP_FRAMES - remaining) / HALF_PERIOD % 2 {
+                        0 => AMPLITUDE,
+                        _ => -AMPLITUDE,
+                    }
+                }
+            };
+            self.buffer.extend([sample, sample]);
+        }
+
+        &self.buffer
+    }
+
+    /// Returns the number of audio frames to output for the current video frame.
+    ///
+    /// If the audio sample rate isn't a multiple of `frame_rate`, the fractional part is carried
+    /// over to later video frames, so that the long-run output rate matches the sample rate exactly
+    /// instead of drifting.
+    pub fn frames_for_video_frame(&mut self, frame_rate: usize) -> usize {
+        let total = AUDIO_SAMPLE_RATE + self.frame_remainder;
+        self.frame_remainder = total % frame_rate;
+        total / frame_rate
+    }
+
+    /// Generates `num_frames` audio frames of buzzer audio as interleaved stereo samples.
+    ///
+    /// `pan` positions the buzzer from -100 (left channel only) to 100 (right channel only). The
+    /// channel the buzzer is panned towards stays at full volume while the other is attenuated.
+    pub fn generate_audio_sample_batch(&mut self, num_frames: usize, pan: i32) -> &[i16] {
+        const OMEGA: f64 = 2.0 * std::f64::consts::PI * BUZZER_FREQ as f64;
+        const SCALE: f64 = 0.5 * i16::MAX as f64;
+
+        let pan = pan.clamp(-100, 100) as f64 / 100.0;
+        let left_gain = (1.0 - pan).min(1.0);
+        let right_gain = (1.0 + pan).min(1.0);
+
+        self.buffer.resize(num_frames * 2, 0);
+
+        for i in (0..num_frames * 2).step_by(2) {
+            let t = self.step as f64 / AUDIO_SAMPLE_RATE as f64;
+            let float_sample = SCALE * (OMEGA * t).sin();
+
+            self.buffer[i] = to_int_sample(left_gain * float_sample);
+            self.buffer[i + 1] = to_int_sample(right_gain * float_sample);
+            self.step += 1;
+        }
+        self.step %= AUDIO_SAMPLE_RATE;
+
+        &self.buffer
+    }
+}
+
+/// Rounds a sample to the i16 range, clamping it explicitly so that any future volume/envelope
+/// scaling can't overflow.
+fn to_int_sample(sample: f64) -> i16 {
+    sample.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns whether the buzzer sounds in each frame, given whether the sound timer was active
+    fn buzzer_frames(sound_timer: &[bool], bridge_frames: usize) -> Vec<bool> {
+        let mut audio = AudioState::default();
+        sound_timer
+            .iter()
+            .map(|&active| audio.buzzer_active(active, bridge_frames))
+            .collect()
+    }
+
+    #[test]
+    fn beep_gap_bridge_merges_short_gaps() {
+        let beeps = [true, false, false, true, false, false, false, false];
+
+        assert_eq!(
+            buzzer_frames(&beeps, 0),
+            [true, false, false, true, false, false, false, false],
+        );
+        assert_eq!(
+            buzzer_frames(&beeps, 2),
+            [true, true, true, true, true, true, false, false],
+        );
+        // A gap longer than the bridge still separates the beeps, just shortened
+        assert_eq!(
+            buzzer_frames(&beeps, 1),
+            [true, true, false, true, true, false, false, false],
+        );
+    }
+
+    #[test]
+    fn audio_frames_add_up_to_sample_rate_each_second() {
+        for frame_rate in SUPPORTED_FRAME_RATES {
+            let mut audio = AudioState::default();
+            let frames: Vec<_> = (0..frame_rate)
+                .map(|_| audio.frames_for_video_frame(frame_rate))
+                .collect();
+            assert_eq!(frames.iter().sum::<usize>(), AUDIO_SAMPLE_RATE);
+            assert!(
+                frames.iter().all(|&n| n > 0),
+                "empty batch at {frame_rate} fps"
+            );
+        }
+    }
+
+    #[test]
+    fn audio_frames_do_not_drift_when_the_frame_rate_does_not_divide_the_sample_rate() {
+        const FRAME_RATE: usize = 7;
+        assert_ne!(AUDIO_SAMPLE_RATE % FRAME_RATE, 0);
+
+        let mut audio = AudioState::default();
+        let mut total = 0;
+        for frame in 1..=10 * FRAME_RATE {
+            total += audio.frames_for_video_frame(FRAME_RATE);
+            // Never more than a frame behind the exact rate, and never ahead of it
+            assert_eq!(total, frame * AUDIO_SAMPLE_RATE / FRAME_RATE);
+        }
+        assert_eq!(total, 10 * AUDIO_SAMPLE_RATE);
+    }
+
+    #[test]
+    fn samples_are_clamped_to_the_i16_range() {
+        assert_eq!(to_int_sample(i16::MAX as f64), i16::MAX);
+        assert_eq!(to_int_sample(i16::MAX as f64 + 0.4), i16::MAX);
+        assert_eq!(to_int_sample(2.0 * i16::MAX as f64), i16::MAX);
+        assert_eq!(to_int_sample(f64::INFINITY), i16::MAX);
+        assert_eq!(to_int_sample(i16::MIN as f64), i16::MIN);
+        assert_eq!(to_int_sample(2.0 * i16::MIN as f64), i16::MIN);
+        assert_eq!(to_int_sample(f64::NEG_INFINITY), i16::MIN);
+        assert_eq!(to_int_sample(-0.4), 0);
+
+        // The buzzer peaks at half volume, well within range
+        let mut audio = AudioState::default();
+        let samples = audio.generate_audio_sample_batch(AUDIO_SAMPLE_RATE / BUZZER_FREQ, 0);
+        let peak = samples
+            .iter()
+            .map(|&sample| sample.unsigned_abs())
+            .max()
+            .unwrap();
+        assert!((16_000..=16_384).contains(&peak), "peak {peak}");
+    }
+
+    #[test]
+    fn pan_attenuates_the_opposite_channel() {
+        let generate = |pan| {
+            let mut audio = AudioState::default();
+            audio.generate_audio_sample_batch(100, pan).to_vec()
+        };
+        let centered = generate(0);
+        let channel = |samples: &[i16], offset| -> Vec<i16> {
+            samples.iter().skip(offset).step_by(2).copied().collect()
+        };
+        let (left, right) = (channel(&centered, 0), channel(&centered, 1));
+        assert_eq!(left, right);
+        assert!(left.iter().any(|&sample| sample != 0));
+
+        let hard_left = generate(-100);
+        assert_eq!(channel(&hard_left, 0), left);
+        assert!(channel(&hard_left, 1).iter().all(|&sample| sample == 0));
+
+        let hard_right = generate(100);
+        assert!(channel(&hard_right, 0).iter().all(|&sample| sample == 0));
+        assert_eq!(channel(&hard_right, 1), right);
+
+        // Halfway to the left, the right channel is at half volume
+        let half_left = generate(-50);
+        assert_eq!(channel(&half_left, 0), left);
+        for (&half, &full) in channel(&half_left, 1).iter().zip(&right) {
+            assert!((half as f64 - full as f64 / 2.0).abs() <= 1.0);
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! An optional splash shown after loading a game, scrolling a message spelled in the hex font
+//! across the screen.
+
+use super::state::{ChipScreen, FONT_DATA};
+use crate::{
+    constants::*,
+    options::{DrawMode, Palette},
+};
+
+/// Message the splash scrolls, which can only be spelled with hex digits and spaces
+const MESSAGE: &str = "C8 0AB1E";
+
+/// Horizontal distance between the left edges of neighboring digits
+const GLYPH_SPACING: usize = 5;
+
+/// Row of the top of the digits, which centers them vertically
+const GLYPH_TOP: usize = (SCREEN_HEIGHT - 5) / 2;
+
+/// Width of the banner spelling the message
+const BANNER_WIDTH: usize = MESSAGE.len() * GLYPH_SPACING;
+
+/// Distance in pixels the banner scrolls from entering at the right edge of the screen until it has
+/// left at the left edge, which is when the splash ends
+pub const SCROLL_DISTANCE: usize = SCREEN_WIDTH + BANNER_WIDTH;
+
+/// Renders the splash with the banner scrolled `scrolled` pixels to the left of its starting
+/// position just beyond the right edge of the screen.
+pub fn render(palette: &Palette, scrolled: usize) -> [u16; NUM_PIXELS] {
+    let mut screen = ChipScreen::default();
+    for (n, c) in MESSAGE.chars().enumerate() {
+        // Spaces just leave a gap
+        let Some(digit) = c.to_digit(16) else {
+            continue;
+        };
+        let glyph_left = (SCREEN_WIDTH + n * GLYPH_SPACING) as isize - scrolled as isize;
+
+        // Sprites are clipped at the right edge of the screen, but can't start left of it, so a
+        // glyph partly off the left edge is drawn at the edge with its hidden columns shifted out
+        let (x_pos, hidden_cols) = match usize::try_from(glyph_left) {
+            Ok(x_pos) => (x_pos, 0),
+            Err(_) => (0, glyph_left.unsigned_abs()),
+        };
+        if x_pos >= SCREEN_WIDTH || hidden_cols >= 8 {
+            continue;
+        }
+        let glyph = FONT_DATA[digit as usize].map(|row| row << hidden_cols);
+        screen.render_sprite(&glyph, x_pos as u8, GLYPH_TOP as u8, DrawMode::Overwrite);
+    }
+    screen.to_rgb565(palette)
+}
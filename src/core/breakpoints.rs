@@ -0,0 +1,74 @@
+//! Breakpoints on pc and watchpoints on memory accesses.
+//!
+//! Breakpoints are checked by [ChipState::tick](super::state::ChipState::tick). When one is hit,
+//! execution is paused (see [ChipState::paused](super::state::ChipState::paused)) until something
+//! clears the pause, such as the debug server's `continue` command.
+
+use std::{collections::BTreeSet, fs, ops::Range, path::Path};
+
+use eyre::{eyre, Result, WrapErr};
+
+/// File extension of the optional companion file that breakpoints are loaded from.
+///
+/// The file sits next to the ROM (e.g. `pong.breakpoints` for `pong.ch8`) and lists one
+/// breakpoint per line as `pc <addr>`, `read <addr>` or `write <addr>`, with addresses in hex.
+/// Blank lines and lines starting with `#` are ignored.
+pub const COMPANION_FILE_EXTENSION: &str = "breakpoints";
+
+#[derive(Default)]
+pub struct Breakpoints {
+    /// Pause before executing the instruction at any of these addresses
+    pub pc: BTreeSet<usize>,
+    /// Pause after any instruction that reads from one of these addresses
+    pub read: BTreeSet<usize>,
+    /// Pause after any instruction that writes to one of these addresses
+    pub write: BTreeSet<usize>,
+}
+
+impl Breakpoints {
+    /// Parses breakpoints from a companion file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read breakpoints file {}", path.display()))?;
+
+        let mut breakpoints = Self::default();
+        for (line_num, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            breakpoints.parse_line(line).wrap_err_with(|| {
+                format!("{}:{}: invalid breakpoint", path.display(), line_num + 1)
+            })?;
+        }
+        Ok(breakpoints)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<()> {
+        let (kind, addr) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| eyre!("expected a kind and an address"))?;
+        let addr = addr.trim();
+        let addr = usize::from_str_radix(addr.trim_start_matches("0x"), 16)
+            .wrap_err_with(|| format!("invalid address {addr:?}"))?;
+
+        let set = match kind {
+            "pc" => &mut self.pc,
+            "read" => &mut self.read,
+            "write" => &mut self.write,
+            _ => return Err(eyre!("unknown breakpoint kind {kind:?}")),
+        };
+        set.insert(addr);
+        Ok(())
+    }
+
+    /// Returns the first watched address read by an access to `addrs`, if any.
+    pub fn watched_read(&self, addrs: Range<usize>) -> Option<usize> {
+        self.read.range(addrs).next().copied()
+    }
+
+    /// Returns the first watched address written by an access to `addrs`, if any.
+    pub fn watched_write(&self, addrs: Range<usize>) -> Option<usize> {
+        self.write.range(addrs).next().copied()
+    }
+}
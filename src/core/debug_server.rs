@@ -0,0 +1,397 @@
+//! A local TCP debug server for inspecting the emulator from external tools.
+//!
+//! Only compiled with the `debug-server` cargo feature, and only listens while the
+//! `trustychip_debug_server` core option is enabled. The server is polled once per frame from
+//! [run](super::run), so it never accesses the emulator state concurrently with the CPU.
+//!
+//! Every message in either direction is a big-endian `u16` length followed by that many bytes.
+//! Requests are ASCII commands with hex arguments:
+//!
+//! - `mem <addr> <len>`: replies with `len` raw bytes of memory starting at `addr`
+//! - `regs`: replies with V0-VF, then I and pc (big-endian `u16`s), then the delay and sound timers
+//! - `screen`: replies with one byte (0 or 1) per pixel in row-major order
+//! - `break <addr>` / `unbreak <addr>`: adds/removes a breakpoint on pc, replies `ok`
+//! - `watch <r|w> <addr>` / `unwatch <r|w> <addr>`: adds/removes a memory read/write watchpoint,
+//!   replies `ok`
+//! - `continue`: resumes execution after hitting a breakpoint, replies `ok`
+//! - `json`: replies with the machine state as a JSON object (requires the `json-dump` feature)
+//!
+//! Errors are reported as a reply starting with `error:`.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeSet,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+};
+
+use super::state::ChipState;
+use crate::constants::*;
+use eyre::{eyre, Result, WrapErr};
+
+/// Port the debug server listens on (localhost only)
+const PORT: u16 = 6502;
+
+/// Most reply bytes to hold for a client which isn't reading them before disconnecting it
+const MAX_UNSENT: usize = 1 << 20;
+
+thread_local! {
+    static SERVER: RefCell<Option<DebugServer>> = const { RefCell::new(None) };
+}
+
+struct DebugServer {
+    listener: TcpListener,
+    client: Option<Client>,
+}
+
+struct Client {
+    stream: TcpStream,
+    pending: Vec<u8>,
+    /// Replies which the socket couldn't take yet, sent on later polls
+    unsent: Vec<u8>,
+}
+
+/// Starts or stops the server as needed and handles any pending client requests.
+pub fn poll(enabled: bool, emustate: &mut ChipState) {
+    SERVER.with(|cell| {
+        let mut server = cell.borrow_mut();
+
+        match (enabled, server.is_some()) {
+            (true, false) => match DebugServer::start() {
+                Ok(started) => *server = Some(started),
+                Err(e) => tracing::error!("{:#}", e),
+            },
+            (false, true) => {
+                tracing::info!("stopping debug server");
+                *server = None;
+            }
+            _ => (),
+        }
+
+        if let Some(server) = server.as_mut() {
+            server.handle_requests(emustate);
+        }
+    })
+}
+
+impl DebugServer {
+    fn start() -> Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, PORT))
+            .wrap_err_with(|| format!("failed to start debug server on port {PORT}"))?;
+        listener.set_nonblocking(true)?;
+        tracing::info!("debug server listening on port {PORT}");
+
+        Ok(Self {
+            listener,
+            client: None,
+        })
+    }
+
+    fn handle_requests(&mut self, emustate: &mut ChipState) {
+        if self.client.is_none() {
+            match self.listener.accept() {
+                // Replies are small and a client waits on each, so don't hold them back for
+                // Nagle's algorithm
+                Ok((stream, addr)) => match stream
+                    .set_nonblocking(true)
+                    .and_then(|()| stream.set_nodelay(true))
+                {
+                    Ok(()) => {
+                        tracing::info!("debug client connected from {addr}");
+                        self.client = Some(Client {
+                            stream,
+                            pending: Vec::new(),
+                            unsent: Vec::new(),
+                        });
+                    }
+                    Err(e) => tracing::error!("failed to set up debug client: {e}"),
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => tracing::error!("failed to accept debug client: {e}"),
+            }
+        }
+
+        if let Some(client) = self.client.as_mut() {
+            let result = client.receive().and_then(|requests| {
+                for request in &requests {
+                    let reply = self
+                        .execute(request, emustate)
+                        .unwrap_or_else(|e| format!("error: {e:#}").into_bytes());
+                    self.client.as_mut().unwrap().queue(&reply)?;
+                }
+                self.client.as_mut().unwrap().flush()
+            });
+
+            if let Err(e) = result {
+                tracing::info!("debug client disconnected: {:#}", e);
+                self.client = None;
+            }
+        }
+    }
+
+    fn execute(&mut self, request: &[u8], emustate: &mut ChipState) -> Result<Vec<u8>> {
+        let request = std::str::from_utf8(request).wrap_err("request is not valid ASCII")?;
+        let mut words = request.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let watch_kind = match command {
+            "watch" | "unwatch" => words.next(),
+            _ => None,
+        };
+        let mut next_arg = || -> Result<usize> {
+            let arg = words.next().ok_or_else(|| eyre!("missing argument"))?;
+            usize::from_str_radix(arg, 16).wrap_err_with(|| format!("invalid argument {arg:?}"))
+        };
+
+        match command {
+            "mem" => {
+                let addr = next_arg()?;
+                let len = next_arg()?;
+                emustate
+                    .mem
+                    .get(addr..addr.saturating_add(len))
+                    .map(<[u8]>::to_vec)
+                    .ok_or_else(|| eyre!("memory range out of bounds"))
+            }
+
+            "regs" => {
+                let mut reply = emustate.v.to_vec();
+                reply.extend_from_slice(&emustate.i.to_be_bytes());
+                reply.extend_from_slice(&(emustate.pc as u16).to_be_bytes());
+                reply.extend_from_slice(&[emustate.dt, emustate.st]);
+                Ok(reply)
+            }
+
+            "screen" => Ok(emustate
+                .screen
+                .iter()
+                .map(|&pixel| bool::from(pixel) as u8)
+                .collect()),
+
+            "break" | "unbreak" | "watch" | "unwatch" => {
+                let breakpoints = &mut emustate.breakpoints;
+                let set: &mut BTreeSet<usize> = match watch_kind {
+                    None => &mut breakpoints.pc,
+                    Some("r") => &mut breakpoints.read,
+                    Some("w") => &mut breakpoints.write,
+                    Some(kind) => return Err(eyre!("invalid watchpoint kind {kind:?}")),
+                };
+                let addr = next_arg()?;
+                if addr >= TOTAL_MEMORY {
+                    return Err(eyre!("address out of bounds"));
+                }
+                match command.starts_with("un") {
+                    false => set.insert(addr),
+                    true => set.remove(&addr),
+                };
+                Ok(b"ok".to_vec())
+            }
+
+            "continue" => {
+                emustate.paused = false;
+                Ok(b"ok".to_vec())
+            }
+
+            #[cfg(feature = "json-dump")]
+            "json" => super::json_dump::dump(emustate).map(String::into_bytes),
+
+            _ => Err(eyre!("unknown command {command:?}")),
+        }
+    }
+}
+
+impl Client {
+    /// Reads any available data and returns all complete messages received.
+    fn receive(&mut self) -> Result<Vec<Vec<u8>>> {
+        let mut buf = [0; 1024];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(eyre!("connection closed")),
+                Ok(n) => self.pending.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let mut messages = Vec::new();
+        while let [hi, lo, rest @ ..] = self.pending.as_slice() {
+            let len = u16::from_be_bytes([*hi, *lo]) as usize;
+            if rest.len() < len {
+                break;
+            }
+            messages.push(rest[..len].to_vec());
+            self.pending.drain(..2 + len);
+        }
+        Ok(messages)
+    }
+
+    /// Adds a message to the replies waiting to be sent.
+    fn queue(&mut self, message: &[u8]) -> Result<()> {
+        let len = u16::try_from(message.len()).wrap_err("reply too long")?;
+        if self.unsent.len() + 2 + message.len() > MAX_UNSENT {
+            return Err(eyre!("client isn't reading its replies"));
+        }
+        self.unsent.extend_from_slice(&len.to_be_bytes());
+        self.unsent.extend_from_slice(message);
+        Ok(())
+    }
+
+    /// Sends as much of the queued replies as the socket takes without blocking.
+    ///
+    /// The socket is non-blocking, so a reply can be cut off anywhere, even within its length. The
+    /// rest stays queued and is sent on later polls.
+    fn flush(&mut self) -> Result<()> {
+        while !self.unsent.is_empty() {
+            match self.stream.write(&self.unsent) {
+                Ok(0) => return Err(eyre!("connection closed")),
+                Ok(n) => drop(self.unsent.drain(..n)),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::options::Options;
+    use bitvec::prelude::*;
+
+    /// Sends a request to the server over a real connection and returns its reply.
+    fn request(
+        server: &mut DebugServer,
+        stream: &mut TcpStream,
+        emustate: &mut ChipState,
+        request: &str,
+    ) -> Vec<u8> {
+        stream
+            .write_all(&(request.len() as u16).to_be_bytes())
+            .unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+
+        // The server is polled, so keep polling until the request has arrived and been answered
+        let timeout = |duration| stream.set_read_timeout(Some(duration)).unwrap();
+        timeout(Duration::from_millis(10));
+        for _ in 0..500 {
+            server.handle_requests(emustate);
+            if stream.peek(&mut [0]).is_ok() {
+                break;
+            }
+        }
+
+        timeout(Duration::from_secs(5));
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).unwrap();
+        let mut reply = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut reply).unwrap();
+        reply
+    }
+
+    /// Returns a server listening on any free port and a client connected to it.
+    fn connect() -> (DebugServer, TcpStream) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        // Otherwise requests can be held back and arrive at the server in bursts
+        stream.set_nodelay(true).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let server = DebugServer {
+            listener,
+            client: None,
+        };
+        (server, stream)
+    }
+
+    #[test]
+    fn inspects_memory_and_sets_breakpoints() {
+        let (mut server, mut stream) = connect();
+
+        let mut emustate = ChipState {
+            pc: GAME_ADDRESS,
+            ..Default::default()
+        };
+        // 6001 6102 at the game address
+        emustate.mem[GAME_ADDRESS..GAME_ADDRESS + 4].copy_from_slice(&[0x60, 0x01, 0x61, 0x02]);
+        emustate.mem[0x300] = 0xAB;
+
+        let mut send = |emustate: &mut ChipState, message| {
+            request(&mut server, &mut stream, emustate, message)
+        };
+        assert_eq!(send(&mut emustate, "mem 300 2"), [0xAB, 0x00]);
+        assert_eq!(
+            send(&mut emustate, "mem fff 2"),
+            b"error: memory range out of bounds",
+        );
+
+        assert_eq!(send(&mut emustate, "break 202"), b"ok");
+        assert!(emustate.breakpoints.pc.contains(&0x202));
+        let opts = Options::default();
+        let no_keys = bitvec![0; 16];
+        emustate.tick(&no_keys, &opts);
+        emustate.tick(&no_keys, &opts);
+        assert!(emustate.paused);
+        assert_eq!((emustate.pc, emustate.v[1]), (0x202, 0));
+
+        assert_eq!(send(&mut emustate, "continue"), b"ok");
+        emustate.tick(&no_keys, &opts);
+        assert_eq!((emustate.pc, emustate.v[1]), (0x204, 2));
+    }
+
+    #[test]
+    fn replies_the_socket_cannot_take_are_sent_on_later_polls() {
+        let (mut server, mut stream) = connect();
+        let mut emustate = ChipState::default();
+        for (addr, byte) in emustate.mem.iter_mut().enumerate() {
+            *byte = addr as u8;
+        }
+
+        // Ask for the whole of memory without reading any replies, until the socket is full
+        let request = b"mem 0 1000";
+        let mut replies = 0;
+        while server
+            .client
+            .as_ref()
+            .map_or(true, |client| client.unsent.is_empty())
+        {
+            assert!(replies < 10_000, "the socket never filled up");
+            stream
+                .write_all(&(request.len() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(request).unwrap();
+            server.handle_requests(&mut emustate);
+            assert!(server.client.is_some(), "the client was disconnected");
+            replies += 1;
+        }
+
+        let mut expected = Vec::new();
+        for _ in 0..replies {
+            expected.extend_from_slice(&(TOTAL_MEMORY as u16).to_be_bytes());
+            expected.extend_from_slice(&emustate.mem[..]);
+        }
+        let mut received = Vec::new();
+        let mut buf = [0; 4096];
+        stream
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        for _ in 0..10_000 {
+            if received.len() >= expected.len() {
+                break;
+            }
+            server.handle_requests(&mut emustate);
+            match stream.read(&mut buf) {
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                // The read timed out, with nothing sent since the last poll
+                Err(e) => assert!(
+                    matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ),
+                    "{e}",
+                ),
+            }
+        }
+        assert!(received == expected, "replies were lost or reordered");
+    }
+}
@@ -0,0 +1,100 @@
+use crate::{constants::*, options::DisplayFilter};
+
+/// Post-processing applied to each RGB565 output frame to reduce flicker.
+///
+/// The logical [ChipScreen](super::state::ChipScreen) is never modified, only the frames sent to
+/// the frontend.
+#[derive(Default)]
+pub struct DisplayFilterState {
+    /// The previous unfiltered frame, if frame blending was active for it
+    previous_frame: Option<Box<[u16; NUM_PIXELS]>>,
+}
+
+impl DisplayFilterState {
+    /// Applies `filter` to `frame` in place.
+    pub fn apply(&mut self, filter: DisplayFilter, frame: &mut [u16; NUM_PIXELS]) {
+        match filter {
+            DisplayFilter::None => self.previous_frame = None,
+
+            // Average each pixel with the same pixel in the previous frame, so a pixel which is
+            // toggled every frame shows at half intensity instead of flickering
+            DisplayFilter::FrameBlend => {
+                let current = Box::new(*frame);
+                if let Some(previous) = self.previous_frame.as_deref() {
+                    for (pixel, &previous) in frame.iter_mut().zip(previous.iter()) {
+                        *pixel = blend_rgb565(*pixel, previous);
+                    }
+                }
+                self.previous_frame = Some(current);
+            }
+        }
+    }
+}
+
+/// Averages two RGB565 colors channel by channel.
+pub(super) fn blend_rgb565(a: u16, b: u16) -> u16 {
+    const CHANNELS: [(u32, u32); 3] = [(11, 0x1F), (5, 0x3F), (0, 0x1F)];
+
+    CHANNELS
+        .iter()
+        .map(|&(shift, mask)| {
+            let sum = ((a as u32 >> shift) & mask) + ((b as u32 >> shift) & mask);
+            let average = (sum + 1) / 2;
+            (average << shift) as u16
+        })
+        .fold(0, |color, channel| color | channel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns each channel of an RGB565 color as a fraction of its full intensity.
+    fn intensities(color: u16) -> [f64; 3] {
+        [
+            (color >> 11) as f64 / 31.0,
+            (color >> 5 & 0x3F) as f64 / 63.0,
+            (color & 0x1F) as f64 / 31.0,
+        ]
+    }
+
+    #[test]
+    fn blending_black_and_white_gives_half_intensity() {
+        for blended in [blend_rgb565(0xFFFF, 0x0000), blend_rgb565(0x0000, 0xFFFF)] {
+            for intensity in intensities(blended) {
+                assert!((intensity - 0.5).abs() < 0.02, "{blended:#06x}");
+            }
+        }
+        assert_eq!(blend_rgb565(0x1234, 0x1234), 0x1234);
+    }
+
+    #[test]
+    fn frame_blend_averages_with_the_previous_frame() {
+        let (black, white) = (0x0000, 0xFFFF);
+        let mut filter = DisplayFilterState::default();
+        // Pixel 0 flickers every frame while pixel 1 stays lit
+        let frame = |flicker| {
+            let mut frame = [black; NUM_PIXELS];
+            frame[0] = flicker;
+            frame[1] = white;
+            frame
+        };
+
+        let mut first = frame(white);
+        filter.apply(DisplayFilter::FrameBlend, &mut first);
+        assert_eq!(first[..2], [white, white]);
+
+        for flicker in [black, white, black] {
+            let mut next = frame(flicker);
+            filter.apply(DisplayFilter::FrameBlend, &mut next);
+            assert_eq!(next[0], blend_rgb565(black, white));
+            assert_eq!(next[1..3], [white, black]);
+        }
+
+        // Turning the filter off and on again doesn't blend with a stale frame
+        filter.apply(DisplayFilter::None, &mut frame(white));
+        let mut next = frame(black);
+        filter.apply(DisplayFilter::FrameBlend, &mut next);
+        assert_eq!(next[0], black);
+    }
+}
@@ -0,0 +1,123 @@
+//! Register values to start execution with, for debugging specific code paths in a ROM.
+
+use std::{fs, path::Path};
+
+use super::state::ChipState;
+use crate::constants::*;
+use eyre::{eyre, Result, WrapErr};
+
+/// File extension of the optional companion file that initial register values are loaded from.
+///
+/// The file sits next to the ROM (e.g. `pong.registers` for `pong.ch8`) and lists one register
+/// per line as `v0`-`vf`, `i` or `pc` followed by its value, both in hex (e.g. `v3 2a`). Blank lines
+/// and lines starting with `#` are ignored.
+pub const COMPANION_FILE_EXTENSION: &str = "registers";
+
+/// Register values which override the usual initial state. Registers left as None are unchanged.
+#[derive(Default)]
+pub struct InitialRegisters {
+    v: [Option<u8>; 16],
+    i: Option<u16>,
+    pc: Option<usize>,
+}
+
+impl InitialRegisters {
+    /// Parses initial register values from a companion file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read registers file {}", path.display()))?;
+
+        let mut registers = Self::default();
+        for (line_num, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            registers.parse_line(line).wrap_err_with(|| {
+                format!(
+                    "{}:{}: invalid register value",
+                    path.display(),
+                    line_num + 1
+                )
+            })?;
+        }
+        Ok(registers)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<()> {
+        let (register, value) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| eyre!("expected a register and a value"))?;
+        let value = value.trim();
+        let value = usize::from_str_radix(value.trim_start_matches("0x"), 16)
+            .wrap_err_with(|| format!("invalid value {value:?}"))?;
+
+        match register.to_ascii_lowercase().as_str() {
+            "i" if value < TOTAL_MEMORY => self.i = Some(value as u16),
+            // pc must leave room for a whole instruction
+            "pc" if value + 1 < TOTAL_MEMORY => self.pc = Some(value),
+            "i" | "pc" => return Err(eyre!("address {value:#x} out of bounds")),
+            register => {
+                let index = register
+                    .strip_prefix('v')
+                    .filter(|index| index.len() == 1)
+                    .and_then(|index| usize::from_str_radix(index, 16).ok())
+                    .ok_or_else(|| eyre!("unknown register {register:?}"))?;
+                let value = u8::try_from(value)
+                    .map_err(|_| eyre!("value {value:#x} too large for V{index:X}"))?;
+                self.v[index] = Some(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Overwrites the registers of `emustate` with the configured values.
+    pub fn apply(&self, emustate: &mut ChipState) {
+        for (reg, value) in emustate.v.iter_mut().zip(self.v) {
+            if let Some(value) = value {
+                *reg = value;
+            }
+        }
+        if let Some(i) = self.i {
+            emustate.i = i;
+        }
+        if let Some(pc) = self.pc {
+            emustate.pc = pc;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_from_file_are_applied() {
+        let path = std::env::temp_dir().join(format!(
+            "trustychip-test-{}.{COMPANION_FILE_EXTENSION}",
+            std::process::id()
+        ));
+        fs::write(&path, "# comment\n\nv0 12\nVF 0xff\ni 3a0\npc 0x300\n").unwrap();
+        let registers = InitialRegisters::from_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        let mut emustate = ChipState::default();
+        emustate.v[1] = 7;
+        registers.unwrap().apply(&mut emustate);
+        assert_eq!(emustate.v[0], 0x12);
+        assert_eq!(emustate.v[1], 7);
+        assert_eq!(emustate.v[0xF], 0xFF);
+        assert_eq!(emustate.i, 0x3A0);
+        assert_eq!(emustate.pc, 0x300);
+    }
+
+    #[test]
+    fn invalid_lines_are_rejected() {
+        for line in [
+            "v0", "vg 1", "v10 1", "v0 100", "i 1000", "pc fff", "sp 1", "v0 zz",
+        ] {
+            let mut registers = InitialRegisters::default();
+            assert!(registers.parse_line(line).is_err(), "{line:?} was accepted");
+        }
+    }
+}
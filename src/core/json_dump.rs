@@ -0,0 +1,89 @@
+//! A JSON dump of the machine state for external debuggers and for diffing test runs.
+//!
+//! Only compiled with the `json-dump` cargo feature, so that serde isn't a dependency by default.
+
+use serde::Serialize;
+
+use super::state::ChipState;
+use crate::constants::*;
+use eyre::{Result, WrapErr};
+
+#[derive(Serialize)]
+struct StateDump<'a> {
+    pc: usize,
+    i: u16,
+    v: [u8; 16],
+    dt: u8,
+    st: u8,
+    stack: &'a [usize],
+    /// One string per row, with `#` for lit pixels and `.` for unlit pixels
+    screen: Vec<String>,
+}
+
+/// Serializes the registers, timers, stack and screen as a JSON object.
+pub fn dump(emustate: &ChipState) -> Result<String> {
+    let screen: Vec<String> = emustate
+        .screen
+        .chunks_exact(SCREEN_WIDTH)
+        .map(|row| {
+            row.iter()
+                .map(|&pixel| match bool::from(pixel) {
+                    true => '#',
+                    false => '.',
+                })
+                .collect()
+        })
+        .collect();
+
+    let state = StateDump {
+        pc: emustate.pc,
+        i: emustate.i,
+        v: emustate.v,
+        dt: emustate.dt,
+        st: emustate.st,
+        stack: &emustate.stack,
+        screen,
+    };
+    serde_json::to_string(&state).wrap_err("failed to serialize state")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::state::PixelState;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn dump_parses_as_the_machine_state() {
+        let mut emustate = ChipState {
+            pc: 0x2A4,
+            i: 0x3F0,
+            dt: 30,
+            st: 4,
+            ..Default::default()
+        };
+        emustate.v[0] = 1;
+        emustate.v[0xF] = 0xFF;
+        emustate.stack.extend([0x202, 0x210]);
+        emustate.screen[0] = PixelState::White;
+        emustate.screen[SCREEN_WIDTH + 2] = PixelState::White;
+
+        let dump: Value = serde_json::from_str(&dump(&emustate).unwrap()).unwrap();
+        assert_eq!(dump["pc"], 0x2A4);
+        assert_eq!(dump["i"], 0x3F0);
+        assert_eq!(
+            dump["v"],
+            json!([1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255])
+        );
+        assert_eq!(dump["dt"], 30);
+        assert_eq!(dump["st"], 4);
+        assert_eq!(dump["stack"], json!([0x202, 0x210]));
+
+        let screen = dump["screen"].as_array().unwrap();
+        assert_eq!(screen.len(), SCREEN_HEIGHT);
+        let row = |y: usize| screen[y].as_str().unwrap();
+        assert_eq!(row(0), format!("#{}", ".".repeat(SCREEN_WIDTH - 1)));
+        assert_eq!(row(1), format!("..#{}", ".".repeat(SCREEN_WIDTH - 3)));
+        assert_eq!(row(2), ".".repeat(SCREEN_WIDTH));
+    }
+}
@@ -0,0 +1,116 @@
+//! On-screen hex keypad for touchscreens, pressed with the frontend's pointer device.
+//!
+//! The keypad is a 4x4 grid of keys in the COSMAC VIP layout, in a strip along the right edge of the
+//! screen so that touches elsewhere don't press keys. Each key's digit is blended into the output
+//! frame only, leaving the logical screen untouched.
+
+use super::{display_filter::blend_rgb565, state::FONT_DATA};
+use crate::{constants::*, options::Palette};
+
+/// The Chip-8 key in each cell of the grid, by row and then column.
+const LAYOUT: [[usize; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+/// Width and height of the font glyphs
+const GLYPH_SIZE: (usize, usize) = (4, 5);
+
+/// Cells are just wide enough for a glyph and a column of space, and tall enough to fill the screen.
+const CELL_WIDTH: usize = GLYPH_SIZE.0 + 1;
+const CELL_HEIGHT: usize = SCREEN_HEIGHT / 4;
+
+/// Column of the left edge of the keypad
+const STRIP_LEFT: usize = SCREEN_WIDTH - 4 * CELL_WIDTH;
+
+/// Returns the key under a pointer position, in the frontend's pointer coordinates which range from
+/// -0x7fff to 0x7fff across the screen. Returns None if the pointer is off screen or outside of the
+/// keypad.
+pub fn key_at(x: i16, y: i16) -> Option<usize> {
+    // The frontend reports -0x8000 for a pointer outside of the screen
+    if x == i16::MIN || y == i16::MIN {
+        return None;
+    }
+    let pixel = |coord: i16, size: usize| ((coord as i32 + 0x7FFF) as usize * size) >> 16;
+    let col = pixel(x, SCREEN_WIDTH).checked_sub(STRIP_LEFT)? / CELL_WIDTH;
+    let row = pixel(y, SCREEN_HEIGHT) / CELL_HEIGHT;
+    Some(LAYOUT[row][col])
+}
+
+/// Blends the digit of each key into the center of its cell in `frame`, in whichever of the
+/// foreground and background colors contrasts with the pixel underneath.
+pub fn render(frame: &mut [u16; NUM_PIXELS], palette: &Palette) {
+    let foreground = palette.color(Palette::FOREGROUND);
+    let background = palette.color(Palette::BACKGROUND);
+    let (glyph_width, glyph_height) = GLYPH_SIZE;
+
+    for (cell_row, keys) in LAYOUT.iter().enumerate() {
+        for (cell_col, &key) in keys.iter().enumerate() {
+            let left = STRIP_LEFT + cell_col * CELL_WIDTH + (CELL_WIDTH - glyph_width) / 2;
+            let top = cell_row * CELL_HEIGHT + (CELL_HEIGHT - glyph_height) / 2;
+
+            for (row, &bits) in FONT_DATA[key].iter().enumerate() {
+                for col in (0..glyph_width).filter(|&col| bits & (0x80 >> col) != 0) {
+                    let pixel = &mut frame[(top + row) * SCREEN_WIDTH + left + col];
+                    let contrast = match *pixel == foreground {
+                        true => background,
+                        false => foreground,
+                    };
+                    *pixel = blend_rgb565(*pixel, contrast);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the pointer coordinate of the center of pixel `pixel` of `size` across the screen.
+    fn pointer(pixel: usize, size: usize) -> i16 {
+        (((2 * pixel + 1) * 0xFFFE / (2 * size)) as i32 - 0x7FFF) as i16
+    }
+
+    #[test]
+    fn pointer_positions_map_to_keys_in_the_strip() {
+        for (row, keys) in LAYOUT.iter().enumerate() {
+            for (col, &key) in keys.iter().enumerate() {
+                // Every pixel of the cell, including its edges
+                for y in row * CELL_HEIGHT..(row + 1) * CELL_HEIGHT {
+                    let left = STRIP_LEFT + col * CELL_WIDTH;
+                    for x in left..left + CELL_WIDTH {
+                        let position = (pointer(x, SCREEN_WIDTH), pointer(y, SCREEN_HEIGHT));
+                        assert_eq!(key_at(position.0, position.1), Some(key), "{x}, {y}");
+                    }
+                }
+            }
+        }
+
+        // The corners of the screen's coordinate range
+        assert_eq!(key_at(0x7FFF, -0x7FFF), Some(0xC));
+        assert_eq!(key_at(0x7FFF, 0x7FFF), Some(0xF));
+        // Outside of the strip, and off screen
+        assert_eq!(key_at(-0x7FFF, -0x7FFF), None);
+        assert_eq!(key_at(pointer(STRIP_LEFT - 1, SCREEN_WIDTH), 0), None);
+        assert_eq!(key_at(i16::MIN, i16::MIN), None);
+    }
+
+    #[test]
+    fn overlay_is_drawn_only_in_the_strip() {
+        let palette = crate::options::Options::default().palette;
+        let background = palette.color(Palette::BACKGROUND);
+        let mut frame = [background; NUM_PIXELS];
+        render(&mut frame, &palette);
+
+        for (y, row) in frame.chunks_exact(SCREEN_WIDTH).enumerate() {
+            assert!(
+                row[..STRIP_LEFT].iter().all(|&p| p == background),
+                "row {y}"
+            );
+        }
+        assert!(frame.iter().any(|&p| p != background));
+    }
+}
@@ -1,20 +1,114 @@
+mod audio;
+mod boot_splash;
+mod breakpoints;
+#[cfg(feature = "debug-server")]
+mod debug_server;
+mod display_filter;
+mod initial_registers;
+#[cfg(feature = "json-dump")]
+mod json_dump;
+mod keypad_overlay;
+mod multi_rom;
+mod rom_analysis;
+mod save_state;
 pub mod state;
-pub use self::state::{deinit, init};
+mod test_pattern;
+mod timer_clock;
+pub use self::{
+    rom_analysis::analyze_rom,
+    save_state::SIZE as SAVE_STATE_SIZE,
+    state::{deinit, init},
+};
 
-use std::ops::{Deref, DerefMut};
+use std::{
+    fmt, mem,
+    path::Path,
+    sync::atomic::{AtomicI64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
 
-use crate::{callbacks as cb, constants::*};
+use crate::{callbacks as cb, constants::*, options, utils};
 use eyre::{eyre, Result};
-use once_cell::sync::Lazy;
-use parking_lot::{const_mutex, Mutex, MutexGuard};
+use libretro_defs as lr;
+use parking_lot::{const_mutex, Mutex};
+
+static FRAME_RATE: AtomicUsize = AtomicUsize::new(DEFAULT_FRAME_RATE);
+/// Time since the previous frame as last reported by the frontend in microseconds, or 0 if unknown
+static FRAME_TIME_USEC: AtomicI64 = AtomicI64::new(0);
+
+/// What this build of the core supports, for frontends and embedders that adapt to it.
+#[derive(Clone, Copy, Debug)]
+pub struct Capabilities {
+    pub chip8: bool,
+    pub schip: bool,
+    pub xo_chip: bool,
+    pub save_states: bool,
+    pub rewind: bool,
+    pub cheats: bool,
+    /// Configurable display colors
+    pub color: bool,
+    /// The `debug-server` cargo feature
+    pub debug_server: bool,
+    /// The `json-dump` cargo feature
+    pub json_dump: bool,
+}
+
+/// Lists the supported features, e.g. "chip8, color".
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let features: Vec<_> = [
+            (self.chip8, "chip8"),
+            (self.schip, "schip"),
+            (self.xo_chip, "xo-chip"),
+            (self.save_states, "save states"),
+            (self.rewind, "rewind"),
+            (self.cheats, "cheats"),
+            (self.color, "color"),
+            (self.debug_server, "debug server"),
+            (self.json_dump, "json dump"),
+        ]
+        .into_iter()
+        .filter_map(|(supported, name)| supported.then_some(name))
+        .collect();
+
+        write!(f, "{}", features.join(", "))
+    }
+}
+
+/// Returns the capabilities of this build of the core.
+pub const fn capabilities() -> Capabilities {
+    Capabilities {
+        chip8: true,
+        schip: false,
+        xo_chip: false,
+        save_states: true,
+        rewind: false,
+        cheats: false,
+        color: true,
+        debug_server: cfg!(feature = "debug-server"),
+        json_dump: cfg!(feature = "json-dump"),
+    }
+}
 
 pub fn load_game(game_data: &[u8]) -> Result<()> {
+    let opts = options::get();
+    let game_data = multi_rom::select_game(game_data, opts.game_select)?;
     match game_data.len() {
         0 => Err(eyre!("cannot load size 0 game")),
 
         len if len <= MAX_GAME_SIZE => {
+            // Frontends may load another game without unloading the previous one, so start from a
+            // clean machine rather than leaving its registers and ROM tail behind. The screen is
+            // kept until the new game draws if the user prefers that to a flash of background.
+            let previous_screen =
+                (!opts.clear_on_reset).then(|| state::with_mut(|emustate| emustate.screen.clone()));
+            state::init();
             state::with_mut(|emustate| {
+                if let Some(screen) = previous_screen {
+                    emustate.screen = screen;
+                }
                 emustate.mem[GAME_ADDRESS..GAME_ADDRESS + len].copy_from_slice(game_data);
+                emustate.rom = game_data.into();
             });
             Ok(())
         }
@@ -23,96 +117,1225 @@ pub fn load_game(game_data: &[u8]) -> Result<()> {
     }
 }
 
-pub fn unload_game() {
-    // TODO: clear memory
-    // TODO: reset other emulator state as necessary
-    // TODO: reinitialize font data below 0x200?
+/// Logs which Chip-8 variant the loaded game appears to target, and warns the user if it may need
+/// features this core doesn't emulate. Must be called after the frame rate is selected, since the
+/// warning is shown for a number of frames.
+pub fn report_rom_analysis() {
+    let analysis = state::with(|emustate| analyze_rom(&emustate.rom));
+    tracing::info!("game appears to target {analysis}");
+    if analysis.needs_unsupported_features() {
+        let message = format!(
+            "This game may need {} features, which are not supported",
+            analysis.suggested_profile(),
+        );
+        tracing::warn!("{message}");
+        cb::env_set_message(&message, 5 * frame_rate());
+    }
 }
 
-#[repr(C, align(16))]
-struct AudioBuffer<const N: usize> {
-    buf: [i16; N],
+/// Returns the video frame rate the core is running at.
+pub fn frame_rate() -> usize {
+    FRAME_RATE.load(Ordering::Relaxed)
 }
 
-impl<const N: usize> AudioBuffer<N> {
-    fn as_slice(&self) -> &[i16] {
-        &self.buf
-    }
+/// Selects the frame rate set in the core options, or otherwise the supported frame rate closest to
+/// the frontend's target display refresh rate, or the default frame rate if the target is unknown.
+///
+/// This must be done when loading a game, after any per-game options have been imported, as the
+/// frontend only reads the AV info once afterwards.
+pub fn select_frame_rate(target_refresh_rate: Option<f32>) {
+    let frame_rate = match (options::get().frame_rate, target_refresh_rate) {
+        (Some(frame_rate), _) => frame_rate,
+        (None, Some(target)) if target > 0.0 => SUPPORTED_FRAME_RATES
+            .into_iter()
+            .min_by(|&a, &b| {
+                (a as f32 - target)
+                    .abs()
+                    .total_cmp(&(b as f32 - target).abs())
+            })
+            .unwrap(),
+        _ => DEFAULT_FRAME_RATE,
+    };
+    tracing::info!("running at {frame_rate} fps (target refresh rate {target_refresh_rate:?})");
+    FRAME_RATE.store(frame_rate, Ordering::Relaxed);
 }
 
-impl<const N: usize> Default for AudioBuffer<N> {
-    fn default() -> AudioBuffer<N> {
-        AudioBuffer { buf: [0; N] }
+/// Asks the frontend to report the actual time elapsed between frames, which the real-time timers
+/// use instead of measuring it themselves. Must be called after the frame rate is selected.
+///
+/// If the frontend doesn't support this, the timers fall back to the wall clock.
+pub fn register_frame_time_callback() {
+    unsafe extern "C" fn frame_time_callback(usec: lr::retro_usec_t) {
+        FRAME_TIME_USEC.store(usec, Ordering::Relaxed);
     }
-}
 
-impl<const N: usize> Deref for AudioBuffer<N> {
-    type Target = [i16; N];
-
-    fn deref(&self) -> &Self::Target {
-        &self.buf
+    FRAME_TIME_USEC.store(0, Ordering::Relaxed);
+    let reference = 1_000_000 / frame_rate() as lr::retro_usec_t;
+    if let Err(e) = cb::env_set_frame_time_callback(frame_time_callback, reference) {
+        tracing::debug!("{:#}", e);
     }
 }
 
-impl<const N: usize> DerefMut for AudioBuffer<N> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.buf
-    }
+/// Returns the time since the previous frame as reported by the frontend, if known.
+fn frame_time() -> Option<Duration> {
+    let usec = FRAME_TIME_USEC.load(Ordering::Relaxed);
+    (usec > 0).then(|| Duration::from_micros(usec as u64))
 }
 
-type VidFrameAudioBuffer = AudioBuffer<{ AUDIO_FRAMES_PER_VIDEO_FRAME * 2 }>;
+/// Writes a save state of the machine into the start of `buf`, which must hold at least
+/// [SAVE_STATE_SIZE] bytes.
+pub fn serialize(buf: &mut [u8]) -> Result<()> {
+    state::with(|emustate| save_state::serialize(emustate, buf))
+}
 
-fn generate_audio_sample_batch() -> MutexGuard<'static, Box<VidFrameAudioBuffer>> {
-    static AUDIO_BUFFER: Lazy<Mutex<Box<VidFrameAudioBuffer>>> =
-        Lazy::new(|| Mutex::new(Box::new(Default::default())));
-    static STEP: Mutex<usize> = const_mutex(0);
+/// Restores the machine from a save state written by [serialize].
+pub fn unserialize(data: &[u8]) -> Result<()> {
+    state::with_mut(|emustate| save_state::unserialize(emustate, data))
+}
 
-    const OMEGA: f64 = 2.0 * std::f64::consts::PI * BUZZER_FREQ as f64;
-    const SCALE: f64 = 0.5 * i16::MAX as f64;
+/// Recommends a CRT shader preset to the user, if enabled.
+///
+/// Libretro has no environment command for a core to request a shader, so the best the core can do
+/// is log the suggestion and show it on screen. Must be called after the frame rate is selected.
+pub fn show_shader_hint() {
+    if !options::get().shader_hint {
+        return;
+    }
 
-    let mut buffer_guard = AUDIO_BUFFER.lock();
-    let mut step_guard = STEP.lock();
+    let message = format!("For a retro look, try the {CRT_SHADER_PRESET} shader preset");
+    tracing::info!("{message}");
+    cb::env_set_message(&message, 5 * frame_rate());
+}
 
-    for i in (0..AUDIO_FRAMES_PER_VIDEO_FRAME * 2).step_by(2) {
-        let t = *step_guard as f64 / AUDIO_SAMPLE_RATE as f64;
-        let float_sample = SCALE * (OMEGA * t).sin();
-        let int_sample = float_sample.round() as i16;
+/// Loads the optional debugging companion files (breakpoints and initial register values) next to
+/// the game at `game_path`. Must be called after the game is loaded, before the first frame runs.
+pub fn load_companion_files(game_path: &Path) {
+    let path = game_path.with_extension(breakpoints::COMPANION_FILE_EXTENSION);
+    if path.exists() {
+        match breakpoints::Breakpoints::from_file(&path) {
+            Ok(breakpoints) => {
+                tracing::info!("loaded breakpoints from {}", path.display());
+                state::with_mut(|emustate| emustate.breakpoints = breakpoints);
+            }
+            Err(e) => tracing::warn!("{:#}", e),
+        }
+    }
 
-        buffer_guard[i] = int_sample;
-        buffer_guard[i + 1] = int_sample;
-        *step_guard += 1;
+    let path = game_path.with_extension(initial_registers::COMPANION_FILE_EXTENSION);
+    if path.exists() {
+        match initial_registers::InitialRegisters::from_file(&path) {
+            Ok(registers) => {
+                tracing::info!("loaded initial register values from {}", path.display());
+                state::with_mut(|emustate| registers.apply(emustate));
+            }
+            Err(e) => tracing::warn!("{:#}", e),
+        }
     }
-    *step_guard %= AUDIO_SAMPLE_RATE;
+}
 
-    buffer_guard
+/// Resets the machine and restarts the loaded game from the beginning.
+pub fn reset() {
+    let clear_screen = options::get().clear_on_reset;
+    state::with_mut(|emustate| emustate.reset(clear_screen));
+    tracing::info!("reset the game");
 }
 
-pub fn run() {
-    // Will set this as a const for now, but this will need to be made adjustable at some point
-    // TODO: Need to make user-adjustable tick rate
-    const TICK_RATE: usize = 500; // Ticks per second
+pub fn unload_game() {
+    state::with(|emustate| log_opcode_stats(&emustate.opcode_counts));
+    // Leave a fresh machine with only the font in memory, so nothing of this game outlives it
+    state::init();
+}
 
-    // It's ok if this isn't evenly divisible, it'll be close enough
-    const TICKS_PER_TIMER_CYCLE: usize = TICK_RATE / TIMER_CYCLE_RATE;
+pub fn run() {
+    let opts = options::get();
+    let frame_rate = frame_rate();
+    let timer_cycles_per_frame = TIMER_CYCLE_RATE / frame_rate;
 
     cb::input_poll();
-    let user_input = cb::get_input_states();
+    let mut user_input = cb::get_input_states();
+    if opts.touch_keypad {
+        if let Some(key) = cb::get_pointer().and_then(|(x, y)| keypad_overlay::key_at(x, y)) {
+            user_input.set(key, true);
+        }
+    }
 
     state::with_mut(|emustate| {
-        if emustate.st > 0 {
-            let buffer_guard = generate_audio_sample_batch();
-            assert_eq!(buffer_guard.len(), AUDIO_FRAMES_PER_VIDEO_FRAME * 2);
-            cb::audio_sample_batch(buffer_guard.as_slice());
+        if emustate.logged_cpu_speed != Some(opts.cpu_speed) {
+            log_effective_tick_rate(opts.cpu_speed);
+            emustate.logged_cpu_speed = Some(opts.cpu_speed);
+        }
+
+        // The buzzer sounds for this frame if the sound timer was active at any point during it,
+        // including if it was set and then ran out before the end of the frame
+        let mut st_active = emustate.st > 0;
+
+        // At an unlimited CPU speed, instructions execute until each timer cycle's equal share of
+        // the tick budget runs out, which is expected rather than worth a warning
+        let (deadline, cycle_budget) = match opts.cpu_speed {
+            Some(_) => (
+                opts.tick_budget_ms
+                    .map(|ms| Instant::now() + Duration::from_millis(ms)),
+                None,
+            ),
+            None => {
+                let ms = opts.tick_budget_ms.unwrap_or(UNLIMITED_TICK_BUDGET_MS);
+                (
+                    None,
+                    Some(Duration::from_millis(ms) / timer_cycles_per_frame as u32),
+                )
+            }
+        };
+        let mut over_budget = false;
+
+        #[cfg(feature = "debug-server")]
+        debug_server::poll(opts.debug_server, emustate);
+
+        // Execution is frozen (including timers) while paused at a breakpoint or showing the test
+        // pattern, and doesn't start until the startup delay has passed to give the frontend's
+        // display time to settle, nor until the boot splash has finished scrolling by
+        let splash_scrolled = opts
+            .boot_splash
+            .then(|| emustate.frame_count * timer_cycles_per_frame)
+            .filter(|&scrolled| scrolled < boot_splash::SCROLL_DISTANCE);
+        let starting_up = emustate.frame_count < opts.startup_delay || splash_scrolled.is_some();
+
+        // By default the timers are decremented once per timer cycle. With real-time timers they
+        // are instead decremented as many times as 60 Hz cycles have actually elapsed, spread over
+        // the frame's timer cycles where possible.
+        let running = !(emustate.paused || starting_up || opts.test_pattern);
+        let timer_decrements = match (opts.realtime_timers, running) {
+            (false, _) => timer_cycles_per_frame,
+            (true, true) => emustate
+                .timer_clock
+                .elapsed_cycles(frame_time())
+                .min(u8::MAX as usize),
+            (true, false) => {
+                emustate.timer_clock.stop();
+                0
+            }
+        };
+
+        for cycle in 0..timer_cycles_per_frame {
+            if !running || emustate.paused {
+                break;
+            }
+
+            let ticks = match opts.cpu_speed {
+                Some(cpu_speed) => emustate.ticks_for_timer_cycle(cpu_speed),
+                None => usize::MAX,
+            };
+            let cycle_deadline = cycle_budget.map(|budget| Instant::now() + budget);
+
+            // Once over budget, skip the remaining ticks but keep decrementing the timers
+            for _ in 0..ticks {
+                if over_budget || deadline.is_some_and(|d| Instant::now() >= d) {
+                    over_budget = true;
+                    break;
+                }
+                if cycle_deadline.is_some_and(|d| Instant::now() >= d) {
+                    break;
+                }
+                emustate.tick(user_input.as_bitslice(), &opts);
+                // Nothing more executes this cycle, which matters at an unlimited CPU speed where
+                // the loop would otherwise spin until the budget runs out
+                if emustate.paused || emustate.halt.is_some() {
+                    break;
+                }
+            }
+
+            st_active |= emustate.st > 0;
+
+            // The timers keep counting down while Fx0A waits for a key press (as on the COSMAC
+            // VIP), since waiting only repeats that instruction and never skips this
+            if cycle < timer_decrements {
+                emustate.decrement_timers();
+            }
+        }
+
+        // Catch up on any real-time timer cycles that didn't fit within this frame
+        if !emustate.paused {
+            for _ in timer_cycles_per_frame..timer_decrements {
+                st_active |= emustate.st > 0;
+                emustate.decrement_timers();
+            }
+        }
+
+        // By default a re-set sound timer just sustains the current tone, avoiding a click
+        if mem::take(&mut emustate.sound_retriggered) && opts.beep_retrigger {
+            emustate.audio.retrigger();
+        }
+
+        let audio_frames = emustate.audio.frames_for_video_frame(frame_rate);
+        let buzzer_active = emustate
+            .audio
+            .buzzer_active(st_active, opts.beep_gap_bridge);
+        if emustate.audio.error_beep_active() {
+            let samples = emustate.audio.generate_error_beep_batch(audio_frames);
+            debug_assert_eq!(samples.len(), audio_frames * 2);
+            cb::audio_sample_batch(samples);
+        } else if buzzer_active {
+            let samples = emustate
+                .audio
+                .generate_audio_sample_batch(audio_frames, opts.pan);
+            debug_assert_eq!(samples.len(), audio_frames * 2);
+            cb::audio_sample_batch(samples);
         }
 
-        for _ in 0..TIMER_CYCLES_PER_FRAME {
-            for _ in 0..TICKS_PER_TIMER_CYCLE {
-                emustate.tick(user_input.as_bitslice());
+        let palette = match opts.invert {
+            false => opts.palette,
+            true => opts.palette.inverted(),
+        };
+        let mut frame = match (opts.test_pattern, splash_scrolled, emustate.halt) {
+            (true, _, _) => test_pattern::render(&palette),
+            (false, Some(scrolled), _) => boot_splash::render(&palette, scrolled),
+            (false, None, Some(halt)) => {
+                emustate.screen.with_halt_overlay(halt).to_rgb565(&palette)
             }
+            (false, None, None) => emustate.screen.to_rgb565(&palette),
+        };
+        emustate
+            .display_filter
+            .apply(opts.display_filter, &mut frame);
+        if opts.touch_keypad {
+            keypad_overlay::render(&mut frame, &palette);
+        }
+        utils::flip_framebuffer(
+            &mut frame,
+            SCREEN_WIDTH,
+            opts.flip_vertical,
+            opts.flip_horizontal,
+        );
+        match opts.internal_scale {
+            1 => cb::video_refresh(&frame, SCREEN_WIDTH, SCREEN_HEIGHT),
+            scale => {
+                utils::scale_framebuffer(&frame, SCREEN_WIDTH, scale, &mut emustate.scaled_frame);
+                cb::video_refresh(
+                    &emustate.scaled_frame,
+                    SCREEN_WIDTH * scale,
+                    SCREEN_HEIGHT * scale,
+                );
+            }
+        }
+
+        if over_budget {
+            warn_tick_budget_exceeded();
+        }
+
+        emustate.frame_count += 1;
+        if running {
+            emustate.executed_frames += 1;
+        }
+        if opts.log_frame_hash {
+            tracing::debug!(
+                "frame {} hash {:016x}",
+                emustate.frame_count,
+                emustate.frame_hash()
+            );
+        }
+
+        // Frames before the game starts don't count, as it can't have drawn anything yet
+        if opts.draw_watchdog
+            && !emustate.has_drawn
+            && !emustate.draw_watchdog_fired
+            && emustate.executed_frames >= DRAW_WATCHDOG_SECONDS * frame_rate
+        {
+            emustate.draw_watchdog_fired = true;
+            tracing::warn!(
+                "game has not drawn anything after {} seconds; it may need a different tick rate \
+                or target an unsupported Chip-8 variant",
+                DRAW_WATCHDOG_SECONDS,
+            );
+        }
 
-            emustate.dt = emustate.dt.saturating_sub(1);
-            emustate.st = emustate.st.saturating_sub(1);
+        if opts.wrap_diag && emustate.frame_count % (WRAP_DIAG_SECONDS * frame_rate) == 0 {
+            tracing::info!(
+                "{} of {} sprites drawn so far were clipped at the screen edge; a high share \
+                suggests the game expects sprites to wrap",
+                emustate.clipped_sprite_draws,
+                emustate.sprite_draws,
+            );
         }
-        cb::video_refresh(&emustate.screen);
     });
 }
+
+/// Returns the number of instructions executed over one second at `cpu_speed`, and the least and
+/// most executed in any one timer cycle, as (per second, min per cycle, max per cycle).
+fn effective_tick_rate(cpu_speed: usize) -> (usize, usize, usize) {
+    let mut accumulator = state::ChipState::default();
+    let ticks: Vec<_> = (0..TIMER_CYCLE_RATE)
+        .map(|_| accumulator.ticks_for_timer_cycle(cpu_speed))
+        .collect();
+    (
+        ticks.iter().sum(),
+        ticks.iter().copied().min().unwrap_or(0),
+        ticks.iter().copied().max().unwrap_or(0),
+    )
+}
+
+/// Logs how many instructions are executed per second and per timer cycle at `cpu_speed`, so that
+/// uneven pacing when it doesn't divide evenly into timer cycles isn't a mystery.
+fn log_effective_tick_rate(cpu_speed: Option<usize>) {
+    let Some(cpu_speed) = cpu_speed else {
+        tracing::info!("executing as many instructions as fit in the tick budget");
+        return;
+    };
+    let (per_second, min, max) = effective_tick_rate(cpu_speed);
+    if min == max {
+        tracing::info!("executing {per_second} instructions per second ({min} per timer cycle)");
+    } else {
+        tracing::info!(
+            "executing {per_second} instructions per second ({min} to {max} per timer cycle, as \
+            {cpu_speed} doesn't divide evenly into {TIMER_CYCLE_RATE} Hz timer cycles)"
+        );
+    }
+    if per_second != cpu_speed {
+        tracing::warn!("executing {per_second} instructions per second instead of {cpu_speed}");
+    }
+}
+
+/// Logs a histogram of executed instructions by opcode family, if any were counted.
+fn log_opcode_stats(opcode_counts: &[u64; 16]) {
+    let histogram = opcode_histogram(opcode_counts);
+    if histogram.is_empty() {
+        return;
+    }
+
+    let total: u64 = opcode_counts.iter().sum();
+    tracing::info!("executed {total} instructions:");
+    for line in histogram {
+        tracing::info!("{line}");
+    }
+}
+
+/// Formats one histogram line per opcode family, with a bar scaled to the most common family. Returns
+/// no lines if nothing was counted.
+fn opcode_histogram(opcode_counts: &[u64; 16]) -> Vec<String> {
+    const BAR_WIDTH: u64 = 40;
+
+    let total: u64 = opcode_counts.iter().sum();
+    let Some(&max) = opcode_counts.iter().max().filter(|&&max| max > 0) else {
+        return Vec::new();
+    };
+
+    opcode_counts
+        .iter()
+        .enumerate()
+        .map(|(family, &count)| {
+            format!(
+                "{:X}xxx {:>10} {:>5.1}% {}",
+                family,
+                count,
+                100.0 * count as f64 / total as f64,
+                "#".repeat((BAR_WIDTH * count / max) as usize),
+            )
+        })
+        .collect()
+}
+
+/// Logs that the tick loop ran out of time, at most once every few seconds.
+fn warn_tick_budget_exceeded() {
+    const WARNING_INTERVAL: Duration = Duration::from_secs(5);
+    static LAST_WARNING: Mutex<Option<Instant>> = const_mutex(None);
+
+    let mut last_warning = LAST_WARNING.lock();
+    if last_warning.map_or(true, |t| t.elapsed() >= WARNING_INTERVAL) {
+        tracing::warn!("tick loop exceeded its time budget; skipping remaining ticks this frame");
+        *last_warning = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{Options, Palette};
+    use std::{
+        cell::{Cell, RefCell},
+        rc::Rc,
+    };
+
+    /// A game which jumps to itself forever without drawing anything
+    const IDLE_LOOP: [u8; 2] = [0x12, 0x00];
+
+    #[test]
+    fn capabilities_match_the_build() {
+        let capabilities = capabilities();
+        assert_eq!(capabilities.debug_server, cfg!(feature = "debug-server"));
+        assert_eq!(capabilities.json_dump, cfg!(feature = "json-dump"));
+        assert_eq!(capabilities.save_states, SAVE_STATE_SIZE > 0);
+        assert!(capabilities.chip8 && capabilities.color);
+        assert!(!capabilities.schip && !capabilities.xo_chip);
+
+        let summary = capabilities.to_string();
+        assert!(summary.starts_with("chip8, save states, color"));
+        assert_eq!(
+            summary.contains("debug server"),
+            cfg!(feature = "debug-server")
+        );
+        assert_eq!(summary.contains("json dump"), cfg!(feature = "json-dump"));
+    }
+
+    #[test]
+    fn frame_rate_closest_to_target_refresh_rate_is_selected() {
+        let _guard = cb::mock::install();
+        let cases = [
+            (None, DEFAULT_FRAME_RATE),
+            (Some(60.0), 60),
+            (Some(59.94), 60),
+            (Some(144.0), 60),
+            (Some(50.0), 60),
+            (Some(30.0), 30),
+            (Some(24.0), 20),
+            (Some(0.0), DEFAULT_FRAME_RATE),
+        ];
+        for (target, expected) in cases {
+            select_frame_rate(target);
+            assert_eq!(frame_rate(), expected, "target refresh rate {target:?}");
+        }
+
+        // The frame rate option takes precedence
+        options::set(Options {
+            frame_rate: Some(15),
+            ..Options::default()
+        });
+        select_frame_rate(Some(60.0));
+        assert_eq!(frame_rate(), 15);
+    }
+
+    /// Runs a frame and returns the video frame it output.
+    fn run_frame() -> cb::mock::Frame {
+        run();
+        let mut frames = cb::mock::take_frames();
+        assert_eq!(frames.len(), 1);
+        frames.pop().unwrap()
+    }
+
+    #[test]
+    fn loading_again_without_unloading_starts_clean() {
+        let _guard = cb::mock::install();
+        // Draws the "5" glyph then loops, followed by a tail the next game doesn't overwrite
+        load_game(&[0x60, 0x05, 0xF0, 0x29, 0xD1, 0x15, 0x12, 0x06, 0xAA, 0xAA]).unwrap();
+        for _ in 0..3 {
+            run_frame();
+        }
+        state::with(|emustate| {
+            assert_eq!(emustate.v[0], 5);
+            assert!(emustate.screen.contains(&state::PixelState::White));
+        });
+
+        load_game(&IDLE_LOOP).unwrap();
+        state::with(|emustate| {
+            assert_eq!(emustate.pc, GAME_ADDRESS);
+            assert_eq!(emustate.v, [0; 16]);
+            assert_eq!(emustate.i, 0);
+            assert!(emustate.stack.is_empty());
+            assert!(emustate
+                .screen
+                .iter()
+                .all(|&p| p == state::PixelState::Black));
+            assert_eq!(&*emustate.rom, &IDLE_LOOP);
+            assert_eq!(&emustate.mem[GAME_ADDRESS..GAME_ADDRESS + 2], &IDLE_LOOP);
+            assert!(emustate.mem[GAME_ADDRESS + 2..]
+                .iter()
+                .all(|&byte| byte == 0));
+        });
+    }
+
+    #[test]
+    fn reset_clears_the_screen_when_enabled() {
+        let _guard = cb::mock::install();
+        load_game(&IDLE_LOOP).unwrap();
+        let white = |emustate: &mut state::ChipState| {
+            emustate.screen[0] = state::PixelState::White;
+        };
+
+        state::with_mut(white);
+        reset();
+        state::with(|emustate| {
+            assert!(emustate
+                .screen
+                .iter()
+                .all(|&p| p == state::PixelState::Black));
+        });
+        // The output matches the logical screen rather than just being blanked
+        let background = Options::default().palette.color(Palette::BACKGROUND);
+        assert!(run_frame().pixels.iter().all(|&p| p == background));
+
+        options::set(Options {
+            clear_on_reset: false,
+            ..Options::default()
+        });
+        state::with_mut(white);
+        reset();
+        state::with(|emustate| assert_eq!(emustate.screen[0], state::PixelState::White));
+    }
+
+    #[test]
+    fn boot_splash_scrolls_each_frame_before_the_game_starts() {
+        let _guard = cb::mock::install();
+        options::set(Options {
+            boot_splash: true,
+            ..Options::default()
+        });
+        load_game(&[0x60, 0x01, 0x12, 0x02]).unwrap();
+        let palette = options::get().palette;
+        let step = TIMER_CYCLE_RATE / frame_rate();
+        let frames = boot_splash::SCROLL_DISTANCE / step;
+
+        let mut previous = None;
+        for frame in 0..frames {
+            let pixels = run_frame().pixels;
+            assert_eq!(
+                pixels,
+                boot_splash::render(&palette, frame * step),
+                "frame {frame}"
+            );
+            assert_ne!(Some(&pixels), previous.as_ref(), "frame {frame}");
+            previous = Some(pixels);
+        }
+        state::with(|emustate| assert_eq!(emustate.pc, GAME_ADDRESS));
+
+        run_frame();
+        state::with(|emustate| assert_eq!(emustate.v[0], 1));
+    }
+
+    #[test]
+    fn loaded_rom_is_kept_until_unloaded() {
+        let _guard = cb::mock::install();
+        let game: Vec<u8> = (0..=0xFF).cycle().take(MAX_GAME_SIZE).collect();
+        load_game(&game).unwrap();
+        state::with_mut(|emustate| {
+            assert_eq!(emustate.rom.len(), game.len());
+            assert_eq!(*emustate.rom, *game);
+            // The stored copy is independent of memory the game may overwrite
+            emustate.mem[GAME_ADDRESS..].fill(0);
+        });
+        reset();
+        state::with(|emustate| assert_eq!(emustate.mem[GAME_ADDRESS..], *game));
+
+        unload_game();
+        state::with(|emustate| {
+            assert!(emustate.rom.is_empty());
+            assert!(emustate.mem[GAME_ADDRESS..].iter().all(|&byte| byte == 0));
+            assert_eq!(emustate.mem[FONT_ADDRESS], state::FONT_DATA[0][0]);
+        });
+    }
+
+    #[test]
+    fn touch_keypad_presses_the_key_under_the_pointer() {
+        let _guard = cb::mock::install();
+        // Sets V1 once key 5 is held
+        let game = [0x60, 0x05, 0xE0, 0x9E, 0x12, 0x02, 0x61, 0x01, 0x12, 0x08];
+        // The middle of the "5" key, second from the left in the second row of the keypad, and a
+        // touch on the game to the left of the keypad
+        let key_5 = (0x4E00, -0x2000);
+        let game_area = (0, -0x2000);
+        let pressed = |touch_keypad, pointer| {
+            options::set(Options {
+                touch_keypad,
+                ..Options::default()
+            });
+            load_game(&game).unwrap();
+            cb::mock::set_pointer(Some(pointer));
+            run_frame();
+            state::with(|emustate| emustate.v[1] == 1)
+        };
+
+        assert!(pressed(true, key_5));
+        assert!(!pressed(true, game_area));
+        assert!(!pressed(false, key_5));
+    }
+
+    #[test]
+    fn invert_swaps_foreground_and_background() {
+        let _guard = cb::mock::install();
+        let palette = Options::default().palette;
+        let foreground = palette.color(Palette::FOREGROUND);
+        let background = palette.color(Palette::BACKGROUND);
+        load_game(&IDLE_LOOP).unwrap();
+        state::with_mut(|emustate| emustate.screen[0] = state::PixelState::White);
+
+        let frame = run_frame();
+        assert_eq!((frame.width, frame.height), (SCREEN_WIDTH, SCREEN_HEIGHT));
+        assert_eq!(frame.pixels[0], foreground);
+        assert!(frame.pixels[1..].iter().all(|&pixel| pixel == background));
+
+        options::set(Options {
+            invert: true,
+            ..Options::default()
+        });
+        let frame = run_frame();
+        assert_eq!(frame.pixels[0], background);
+        assert!(frame.pixels[1..].iter().all(|&pixel| pixel == foreground));
+    }
+
+    #[test]
+    fn internal_scale_enlarges_the_video_output() {
+        let _guard = cb::mock::install();
+        options::set(Options {
+            internal_scale: 2,
+            ..Options::default()
+        });
+        load_game(&IDLE_LOOP).unwrap();
+        state::with_mut(|emustate| emustate.screen[1] = state::PixelState::White);
+
+        for _ in 0..2 {
+            let frame = run_frame();
+            assert_eq!(
+                (frame.width, frame.height),
+                (2 * SCREEN_WIDTH, 2 * SCREEN_HEIGHT)
+            );
+            let lit = Options::default().palette.color(Palette::FOREGROUND);
+            let lit_pixels: Vec<_> = (0..frame.pixels.len())
+                .filter(|&i| frame.pixels[i] == lit)
+                .collect();
+            let row = 2 * SCREEN_WIDTH;
+            assert_eq!(lit_pixels, [2, 3, row + 2, row + 3]);
+        }
+    }
+
+    #[test]
+    fn panic_while_generating_audio_does_not_break_later_frames() {
+        let _guard = cb::mock::install();
+        load_game(&IDLE_LOOP).unwrap();
+
+        let result = std::panic::catch_unwind(|| {
+            state::with_mut(|emustate| {
+                emustate.audio.generate_audio_sample_batch(10, 0);
+                panic!("failed while generating audio");
+            })
+        });
+        assert!(result.is_err());
+
+        state::with_mut(|emustate| emustate.st = 10);
+        run();
+        let samples = cb::mock::take_samples();
+        assert_eq!(samples.len(), 2 * AUDIO_SAMPLE_RATE / frame_rate());
+        assert!(samples.iter().any(|&sample| sample != 0));
+    }
+
+    #[test]
+    fn tick_budget_stops_the_tick_loop() {
+        let _guard = cb::mock::install();
+        // Far more instructions per frame than could run within the budget
+        options::set(Options {
+            tick_budget_ms: Some(4),
+            cpu_speed: Some(1_000_000_000),
+            opcode_stats: true,
+            ..Options::default()
+        });
+        load_game(&IDLE_LOOP).unwrap();
+        state::with_mut(|emustate| emustate.dt = 10);
+
+        let start = Instant::now();
+        run();
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        state::with(|emustate| {
+            assert!(emustate.opcode_counts[0x1] > 0);
+            assert!(emustate.opcode_counts[0x1] < 1_000_000_000 / TIMER_CYCLE_RATE as u64);
+            // The timers still count down when the loop is cut short
+            assert_eq!(emustate.dt, 9);
+        });
+    }
+
+    #[test]
+    fn startup_delay_holds_off_execution() {
+        let _guard = cb::mock::install();
+        for delay in [1, 5, 10] {
+            options::set(Options {
+                startup_delay: delay,
+                opcode_stats: true,
+                ..Options::default()
+            });
+            load_game(&IDLE_LOOP).unwrap();
+            state::with_mut(|emustate| emustate.dt = 100);
+
+            for _ in 0..delay {
+                run();
+            }
+            state::with(|emustate| {
+                assert_eq!(emustate.opcode_counts, [0; 16], "startup delay {delay}");
+                assert_eq!(emustate.dt, 100);
+                assert_eq!(emustate.executed_frames, 0);
+            });
+
+            run();
+            state::with(|emustate| {
+                assert!(emustate.opcode_counts[0x1] > 0, "startup delay {delay}");
+                assert!(emustate.dt < 100);
+            });
+        }
+    }
+
+    #[test]
+    fn timers_keep_running_while_waiting_for_a_key() {
+        let _guard = cb::mock::install();
+        // Fx0A - wait for a key press
+        load_game(&[0xF0, 0x0A]).unwrap();
+        state::with_mut(|emustate| {
+            emustate.dt = 10;
+            emustate.st = 10;
+        });
+
+        let timer_cycles_per_frame = TIMER_CYCLE_RATE / frame_rate();
+        for frame in 1..=3 {
+            run();
+            state::with(|emustate| {
+                assert_eq!(emustate.pc, GAME_ADDRESS);
+                assert_eq!(emustate.dt as usize, 10 - frame * timer_cycles_per_frame);
+                assert_eq!(emustate.st, emustate.dt);
+            });
+        }
+    }
+
+    #[test]
+    fn opcode_histogram_counts_each_family() {
+        let _guard = cb::mock::install();
+        options::set(Options {
+            opcode_stats: true,
+            ..Options::default()
+        });
+        // Set V0 three times and add to it once, then loop
+        load_game(&[0x60, 0x01, 0x60, 0x02, 0x60, 0x03, 0x70, 0x01, 0x12, 0x08]).unwrap();
+        state::with_mut(|emustate| {
+            let opts = options::get();
+            for _ in 0..10 {
+                emustate.tick(&cb::get_input_states(), &opts);
+            }
+            assert_eq!(emustate.opcode_counts[0x6], 3);
+            assert_eq!(emustate.opcode_counts[0x7], 1);
+            assert_eq!(emustate.opcode_counts[0x1], 6);
+            assert_eq!(emustate.opcode_counts.iter().sum::<u64>(), 10);
+        });
+
+        let histogram = opcode_histogram(&state::with(|emustate| emustate.opcode_counts));
+        assert_eq!(histogram.len(), 16);
+        assert_eq!(histogram[0x0], format!("0xxx {:>10}   0.0% ", 0));
+        assert_eq!(
+            histogram[0x1],
+            format!("1xxx {:>10}  60.0% {}", 6, "#".repeat(40))
+        );
+        assert_eq!(
+            histogram[0x6],
+            format!("6xxx {:>10}  30.0% {}", 3, "#".repeat(20))
+        );
+        assert_eq!(
+            histogram[0x7],
+            format!("7xxx {:>10}  10.0% {}", 1, "#".repeat(6))
+        );
+
+        assert!(opcode_histogram(&[0; 16]).is_empty());
+    }
+
+    #[test]
+    fn sound_timer_beeps_for_the_frames_it_is_active() {
+        let _guard = cb::mock::install();
+        for frame_rate in [60, 30] {
+            options::set(Options {
+                frame_rate: Some(frame_rate),
+                ..Options::default()
+            });
+            select_frame_rate(None);
+            let timer_cycles_per_frame = TIMER_CYCLE_RATE / frame_rate;
+
+            // Sound timer set to run out exactly at the end of the first frame
+            load_game(&IDLE_LOOP).unwrap();
+            state::with_mut(|emustate| emustate.st = timer_cycles_per_frame as u8);
+            let beeps: Vec<_> = (0..3)
+                .map(|_| {
+                    run();
+                    !cb::mock::take_samples().is_empty()
+                })
+                .collect();
+            assert_eq!(beeps, [true, false, false], "{frame_rate} fps");
+
+            // Sound timer set and run out by the game partway through the frame
+            load_game(&[0x61, 0x01, 0xF1, 0x18, 0x12, 0x04]).unwrap();
+            run();
+            assert_eq!(state::with(|emustate| emustate.st), 0);
+            let samples = cb::mock::take_samples();
+            assert_eq!(samples.len(), 2 * AUDIO_SAMPLE_RATE / frame_rate);
+            assert!(samples.iter().any(|&sample| sample != 0));
+        }
+    }
+
+    #[test]
+    fn resetting_the_sound_timer_sustains_the_tone() {
+        // Keeps setting the sound timer while it's running
+        const SOUND_LOOP: [u8; 6] = [0x61, 0x05, 0xF1, 0x18, 0x12, 0x00];
+        let _guard = cb::mock::install();
+        let frames = 3;
+        let audio_frames = AUDIO_SAMPLE_RATE / frame_rate();
+
+        load_game(&SOUND_LOOP).unwrap();
+        for _ in 0..frames {
+            run();
+        }
+        let continuous = audio::AudioState::default()
+            .generate_audio_sample_batch(frames * audio_frames, 0)
+            .to_vec();
+        assert_eq!(cb::mock::take_samples(), continuous);
+
+        // Retriggering restarts the waveform every frame instead
+        options::set(Options {
+            beep_retrigger: true,
+            ..Options::default()
+        });
+        load_game(&SOUND_LOOP).unwrap();
+        for _ in 0..frames {
+            run();
+        }
+        let samples = cb::mock::take_samples();
+        let first_frame = &continuous[..2 * audio_frames];
+        assert_eq!(samples.len(), continuous.len());
+        assert!(samples
+            .chunks_exact(2 * audio_frames)
+            .all(|frame| frame == first_frame));
+    }
+
+    #[test]
+    fn beep_continues_from_its_phase_after_restoring() {
+        let _guard = cb::mock::install();
+        let audio_frames = AUDIO_SAMPLE_RATE / frame_rate();
+        let continuous = audio::AudioState::default()
+            .generate_audio_sample_batch(2 * audio_frames, 0)
+            .to_vec();
+
+        load_game(&IDLE_LOOP).unwrap();
+        state::with_mut(|emustate| emustate.st = 60);
+        run();
+        let mut buf = vec![0; SAVE_STATE_SIZE];
+        serialize(&mut buf).unwrap();
+        assert_ne!(state::with(|emustate| emustate.audio.step), 0);
+        cb::mock::take_samples();
+
+        // Restored into a freshly loaded game, the next frame carries on with the waveform
+        load_game(&IDLE_LOOP).unwrap();
+        unserialize(&buf).unwrap();
+        run();
+        let samples = cb::mock::take_samples();
+        assert_eq!(samples, continuous[2 * audio_frames..]);
+        assert_ne!(samples, continuous[..2 * audio_frames]);
+    }
+
+    #[test]
+    fn gap_bridge_makes_every_other_frame_beeps_continuous() {
+        let _guard = cb::mock::install();
+        let frames = 8;
+        let audio_frames = AUDIO_SAMPLE_RATE / frame_rate();
+        // Returns whether each frame beeped, and all of the audio, with the sound timer set to 1
+        // (which runs out within the frame) every other frame
+        let beeps = |beep_gap_bridge| {
+            options::set(Options {
+                beep_gap_bridge,
+                ..Options::default()
+            });
+            load_game(&IDLE_LOOP).unwrap();
+            let mut audio = Vec::new();
+            let beeped: Vec<_> = (0..frames)
+                .map(|frame| {
+                    if frame % 2 == 0 {
+                        state::with_mut(|emustate| emustate.st = 1);
+                    }
+                    run();
+                    let samples = cb::mock::take_samples();
+                    audio.extend_from_slice(&samples);
+                    samples.iter().any(|&sample| sample != 0)
+                })
+                .collect();
+            (beeped, audio)
+        };
+
+        let (beeped, _) = beeps(0);
+        assert_eq!(beeped, [true, false].repeat(frames / 2));
+
+        let (beeped, audio) = beeps(1);
+        assert!(beeped.iter().all(|&beeped| beeped));
+        let continuous = audio::AudioState::default()
+            .generate_audio_sample_batch(frames * audio_frames, 0)
+            .to_vec();
+        assert_eq!(audio, continuous);
+    }
+
+    #[test]
+    fn realtime_timers_count_the_reported_frame_time() {
+        let _guard = cb::mock::install();
+        let registered = Rc::new(Cell::new(None));
+        cb::mock::set_environment({
+            let registered = registered.clone();
+            move |cmd, data| {
+                if cmd != lr::RETRO_ENVIRONMENT_SET_FRAME_TIME_CALLBACK {
+                    return false;
+                }
+                let callback = unsafe { *(data as *const lr::retro_frame_time_callback) };
+                registered.set(Some((callback.callback, callback.reference)));
+                true
+            }
+        });
+        options::set(Options {
+            realtime_timers: true,
+            ..Options::default()
+        });
+        register_frame_time_callback();
+        let (callback, reference) = registered.get().expect("callback was not registered");
+        assert_eq!(reference, 1_000_000 / frame_rate() as lr::retro_usec_t);
+        let report_frame_time = |usec| unsafe { callback.unwrap()(usec) };
+
+        load_game(&IDLE_LOOP).unwrap();
+        state::with_mut(|emustate| emustate.dt = 100);
+        // The first frame only starts the clock
+        report_frame_time(reference);
+        run();
+        assert_eq!(state::with(|emustate| emustate.dt), 100);
+
+        // Half a second passes, however little wall-clock time the test takes
+        report_frame_time(500_000);
+        run();
+        assert_eq!(state::with(|emustate| emustate.dt), 70);
+        report_frame_time(50_000);
+        run();
+        assert_eq!(state::with(|emustate| emustate.dt), 67);
+    }
+
+    #[test]
+    fn effective_tick_rate_matches_the_cpu_speed() {
+        for cpu_speed in [500, 420, 600, 700, 1000, 1500, 2000, 7, 59, 61, 119] {
+            let (per_second, min, max) = effective_tick_rate(cpu_speed);
+            assert_eq!(per_second, cpu_speed);
+            assert_eq!(min, cpu_speed / TIMER_CYCLE_RATE, "{cpu_speed}");
+            assert!(max - min <= 1, "{cpu_speed}");
+        }
+        assert_eq!(effective_tick_rate(480), (480, 8, 8));
+    }
+
+    #[test]
+    fn instructions_per_second_match_the_cpu_speed() {
+        let _guard = cb::mock::install();
+        for frame_rate in [60, 30] {
+            for cpu_speed in [500, 420, 1000, 7] {
+                options::set(Options {
+                    frame_rate: Some(frame_rate),
+                    cpu_speed: Some(cpu_speed),
+                    opcode_stats: true,
+                    ..Options::default()
+                });
+                select_frame_rate(None);
+                load_game(&IDLE_LOOP).unwrap();
+                for _ in 0..frame_rate {
+                    run();
+                }
+                let ticks = state::with(|emustate| emustate.opcode_counts.iter().sum::<u64>());
+                assert!(
+                    ticks.abs_diff(cpu_speed as u64) <= 1,
+                    "{ticks} instructions at {cpu_speed} per second and {frame_rate} fps",
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unlimited_cpu_speed_runs_for_the_tick_budget() {
+        let _guard = cb::mock::install();
+        options::set(Options {
+            cpu_speed: None,
+            tick_budget_ms: Some(2),
+            opcode_stats: true,
+            ..Options::default()
+        });
+        load_game(&IDLE_LOOP).unwrap();
+        state::with_mut(|emustate| emustate.dt = 10);
+
+        let start = Instant::now();
+        run();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(2));
+        assert!(elapsed < Duration::from_millis(500));
+        state::with(|emustate| {
+            // Far more than the 8 or 9 of the default speed, even in a debug build
+            assert!(emustate.opcode_counts[0x1] > 100);
+            assert_eq!(emustate.dt, 9);
+        });
+    }
+
+    #[test]
+    fn halted_machine_does_not_use_the_unlimited_tick_budget() {
+        let _guard = cb::mock::install();
+        options::set(Options {
+            cpu_speed: None,
+            tick_budget_ms: Some(16),
+            invalid_instruction: options::InvalidInstructionAction::Pause,
+            ..Options::default()
+        });
+        // An invalid 8xyF
+        load_game(&[0x80, 0x1F]).unwrap();
+        run();
+        assert!(state::with(|emustate| emustate.halt.is_some()));
+
+        let start = Instant::now();
+        run();
+        assert!(start.elapsed() < Duration::from_millis(4));
+    }
+
+    #[test]
+    fn effective_tick_rate_is_logged_when_the_cpu_speed_changes() {
+        let _guard = cb::mock::install();
+        load_game(&IDLE_LOOP).unwrap();
+        assert_eq!(state::with(|emustate| emustate.logged_cpu_speed), None);
+
+        run();
+        assert_eq!(
+            state::with(|emustate| emustate.logged_cpu_speed),
+            Some(Some(500))
+        );
+
+        options::set(Options {
+            cpu_speed: None,
+            ..Options::default()
+        });
+        run();
+        assert_eq!(
+            state::with(|emustate| emustate.logged_cpu_speed),
+            Some(None)
+        );
+
+        // A newly loaded game logs it again
+        load_game(&IDLE_LOOP).unwrap();
+        assert_eq!(state::with(|emustate| emustate.logged_cpu_speed), None);
+    }
+
+    #[test]
+    fn error_beep_is_queued_on_invalid_instructions() {
+        const AMPLITUDE: i16 = i16::MAX / 4;
+        // An invalid 8xyF followed by an idle loop
+        const INVALID: [u8; 4] = [0x80, 0x1F, 0x12, 0x02];
+        let _guard = cb::mock::install();
+        options::set(Options {
+            error_beep: true,
+            invalid_instruction: options::InvalidInstructionAction::Skip,
+            ..Options::default()
+        });
+        load_game(&INVALID).unwrap();
+
+        let mut samples = Vec::new();
+        for _ in 0..2 * ERROR_BEEP_FRAMES * frame_rate() / AUDIO_SAMPLE_RATE {
+            run();
+            samples.extend(cb::mock::take_samples());
+        }
+        assert!(samples.len() >= 2 * ERROR_BEEP_FRAMES);
+        let (beep, after) = samples.split_at(2 * ERROR_BEEP_FRAMES);
+        assert!(beep.iter().all(|&sample| sample.abs() == AMPLITUDE));
+        assert!(beep.contains(&AMPLITUDE) && beep.contains(&-AMPLITUDE));
+        assert!(after.iter().all(|&sample| sample == 0));
+
+        // When shutting down, the whole beep is sent right away
+        options::set(Options {
+            error_beep: true,
+            ..Options::default()
+        });
+        load_game(&INVALID).unwrap();
+        let result = std::panic::catch_unwind(run);
+        assert!(result.is_err());
+        let samples = cb::mock::take_samples();
+        assert_eq!(samples.len(), 2 * ERROR_BEEP_FRAMES);
+        assert!(samples.iter().all(|&sample| sample.abs() == AMPLITUDE));
+    }
+
+    /// Records the text and duration of each message the core shows on screen.
+    fn record_messages() -> Rc<RefCell<Vec<(String, u32)>>> {
+        let messages = Rc::new(RefCell::new(Vec::new()));
+        cb::mock::set_environment({
+            let messages = messages.clone();
+            move |cmd, data| {
+                if cmd != lr::RETRO_ENVIRONMENT_SET_MESSAGE {
+                    return false;
+                }
+                let message = unsafe { *(data as *const lr::retro_message) };
+                let text = unsafe { std::ffi::CStr::from_ptr(message.msg) };
+                messages
+                    .borrow_mut()
+                    .push((text.to_string_lossy().into_owned(), message.frames));
+                true
+            }
+        });
+        messages
+    }
+
+    #[test]
+    fn shader_hint_is_shown_when_enabled() {
+        let _guard = cb::mock::install();
+        let messages = record_messages();
+
+        show_shader_hint();
+        assert!(messages.borrow().is_empty());
+
+        options::set(Options {
+            shader_hint: true,
+            frame_rate: Some(30),
+            ..Options::default()
+        });
+        select_frame_rate(None);
+        show_shader_hint();
+        let messages = messages.borrow();
+        assert_eq!(messages.len(), 1);
+        let (text, frames) = &messages[0];
+        assert!(text.contains(CRT_SHADER_PRESET), "{text:?}");
+        assert_eq!(*frames, 5 * 30);
+    }
+
+    #[test]
+    fn unsupported_features_warning_lasts_five_seconds_at_the_selected_frame_rate() {
+        let _guard = cb::mock::install();
+        let messages = record_messages();
+
+        // Switches to hires mode (SCHIP) and loops
+        options::set(Options {
+            frame_rate: Some(20),
+            ..Options::default()
+        });
+        load_game(&[0x00, 0xFF, 0x12, 0x02]).unwrap();
+        assert!(messages.borrow().is_empty());
+
+        select_frame_rate(None);
+        report_rom_analysis();
+        let messages = messages.borrow();
+        assert_eq!(messages.len(), 1);
+        let (text, frames) = &messages[0];
+        assert!(text.contains("SCHIP"), "{text:?}");
+        assert_eq!(*frames, 5 * 20);
+    }
+
+    #[test]
+    fn draw_watchdog_counts_executed_frames_only() {
+        let _guard = cb::mock::install();
+        options::set(Options {
+            draw_watchdog: true,
+            startup_delay: 10,
+            ..Options::default()
+        });
+        load_game(&IDLE_LOOP).unwrap();
+
+        let watchdog_frames = DRAW_WATCHDOG_SECONDS * frame_rate();
+        for _ in 0..10 + watchdog_frames - 1 {
+            run();
+        }
+        assert!(!state::with(|emustate| emustate.draw_watchdog_fired));
+
+        run();
+        assert!(state::with(|emustate| emustate.draw_watchdog_fired));
+    }
+
+    #[test]
+    fn draw_watchdog_fires_when_frame_count_skips_past_threshold() {
+        let _guard = cb::mock::install();
+        options::set(Options {
+            draw_watchdog: true,
+            ..Options::default()
+        });
+        load_game(&IDLE_LOOP).unwrap();
+
+        // e.g. if the watchdog was enabled partway through the game
+        state::with_mut(|emustate| emustate.executed_frames = 10 * frame_rate());
+        run();
+        assert!(state::with(|emustate| emustate.draw_watchdog_fired));
+    }
+}
@@ -0,0 +1,98 @@
+//! A simple container format bundling several games into one file, like a multi-game cartridge.
+//!
+//! The container starts with the magic bytes [MAGIC], then a one byte game count, then an index
+//! with a 4 byte entry per game: the game's offset from the start of the file and its length, both
+//! big-endian `u16`s. The game data follows the index.
+
+use eyre::{eyre, Result};
+
+const MAGIC: &[u8] = b"CH8MULTI";
+
+/// Returns the data of the game at `index` if `data` is a multi-game container, or `data` itself
+/// if it's a plain ROM.
+pub fn select_game(data: &[u8], index: usize) -> Result<&[u8]> {
+    let Some(rest) = data.strip_prefix(MAGIC) else {
+        return Ok(data);
+    };
+
+    let (&count, index_table) = rest
+        .split_first()
+        .ok_or_else(|| eyre!("multi-game file is missing its game count"))?;
+    let count = count as usize;
+    if index >= count {
+        return Err(eyre!(
+            "multi-game file only has {count} games, cannot select game {}",
+            index + 1,
+        ));
+    }
+
+    let entry = index_table
+        .get(index * 4..index * 4 + 4)
+        .ok_or_else(|| eyre!("multi-game file index is truncated"))?;
+    let offset = u16::from_be_bytes([entry[0], entry[1]]) as usize;
+    let len = u16::from_be_bytes([entry[2], entry[3]]) as usize;
+
+    tracing::info!(
+        "selected game {} of {count} from multi-game file",
+        index + 1
+    );
+    data.get(offset..offset + len)
+        .ok_or_else(|| eyre!("multi-game file entry {} is out of bounds", index + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a multi-game container holding `games`.
+    fn container(games: &[&[u8]]) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.push(games.len() as u8);
+        let mut offset = MAGIC.len() + 1 + 4 * games.len();
+        for game in games {
+            data.extend((offset as u16).to_be_bytes());
+            data.extend((game.len() as u16).to_be_bytes());
+            offset += game.len();
+        }
+        data.extend(games.concat());
+        data
+    }
+
+    #[test]
+    fn games_are_selected_from_a_container() {
+        let games: [&[u8]; 3] = [&[0x12, 0x00], &[0x60, 0x01, 0x12, 0x02], &[0x00, 0xE0]];
+        let data = container(&games);
+        for (index, game) in games.iter().enumerate() {
+            assert_eq!(select_game(&data, index).unwrap(), *game);
+        }
+        assert!(select_game(&data, 3).is_err());
+    }
+
+    #[test]
+    fn plain_roms_are_loaded_as_is() {
+        let rom = [0x12, 0x00, 0x60, 0x01];
+        assert_eq!(select_game(&rom, 0).unwrap(), rom);
+        // The game selection doesn't matter for a plain ROM
+        assert_eq!(select_game(&rom, 5).unwrap(), rom);
+        // Nor does a partial match of the magic bytes
+        let almost = b"CH8MULT\x01";
+        assert_eq!(select_game(almost, 0).unwrap(), almost);
+    }
+
+    #[test]
+    fn malformed_containers_are_rejected() {
+        let mut truncated_index = container(&[&[0x12, 0x00], &[0x12, 0x00]]);
+        truncated_index.truncate(MAGIC.len() + 1 + 6);
+        let mut out_of_bounds = container(&[&[0x12, 0x00]]);
+        out_of_bounds.pop();
+
+        for (data, index) in [
+            (MAGIC.to_vec(), 0),
+            (container(&[]), 0),
+            (truncated_index, 1),
+            (out_of_bounds, 0),
+        ] {
+            assert!(select_game(&data, index).is_err(), "{data:02x?}");
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Static analysis of a ROM's instructions to guess which Chip-8 variant it was written for.
+//!
+//! The analysis doesn't execute the ROM. It scans every aligned pair of bytes for opcodes that only
+//! exist in later variants, so data mixed in with the code can cause false positives, but a ROM
+//! which really does use a variant's features will almost always be flagged.
+
+use std::fmt;
+
+/// Chip-8 extension features found in a ROM.
+#[derive(Debug, Default)]
+pub struct RomAnalysis {
+    /// SCHIP 128x64 display mode (00FE/00FF)
+    pub hires: bool,
+    /// SCHIP scroll instructions (00Cn/00FB/00FC)
+    pub scroll: bool,
+    /// SCHIP big font or RPL flags (Fx30/Fx75/Fx85)
+    pub big_font: bool,
+    /// XO-CHIP instructions (5xy2/5xy3, Fn01, F002, Fx3A, 00Dn)
+    pub xo_chip: bool,
+    /// XO-CHIP 16-bit address loads (F000 nnnn)
+    pub long_loads: bool,
+}
+
+impl RomAnalysis {
+    /// Returns the name of the simplest variant that supports every feature found.
+    pub fn suggested_profile(&self) -> &'static str {
+        if self.xo_chip || self.long_loads {
+            "XO-CHIP"
+        } else if self.hires || self.scroll || self.big_font {
+            "SCHIP"
+        } else {
+            "Chip-8"
+        }
+    }
+
+    /// Returns true if the ROM appears to need features this core doesn't emulate.
+    pub fn needs_unsupported_features(&self) -> bool {
+        self.suggested_profile() != "Chip-8"
+    }
+}
+
+/// Summarizes the features found, e.g. "SCHIP (hires, scroll)".
+impl fmt::Display for RomAnalysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let features: Vec<_> = [
+            (self.hires, "hires"),
+            (self.scroll, "scroll"),
+            (self.big_font, "big font"),
+            (self.xo_chip, "XO-CHIP opcodes"),
+            (self.long_loads, "long loads"),
+        ]
+        .into_iter()
+        .filter_map(|(found, name)| found.then_some(name))
+        .collect();
+
+        match features.is_empty() {
+            true => write!(f, "{}", self.suggested_profile()),
+            false => write!(f, "{} ({})", self.suggested_profile(), features.join(", ")),
+        }
+    }
+}
+
+/// Scans a ROM for opcodes from later Chip-8 variants.
+pub fn analyze_rom(rom: &[u8]) -> RomAnalysis {
+    let mut analysis = RomAnalysis::default();
+
+    for opcode in rom
+        .chunks_exact(2)
+        .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    {
+        let low_byte = opcode & 0xFF;
+        match opcode >> 12 {
+            0x0 => match opcode & 0xFFF {
+                0x0FE | 0x0FF => analysis.hires = true,
+                0x0FB | 0x0FC => analysis.scroll = true,
+                n if n & 0xFF0 == 0x0C0 => analysis.scroll = true,
+                n if n & 0xFF0 == 0x0D0 => analysis.xo_chip = true,
+                _ => (),
+            },
+            0x5 if matches!(opcode & 0xF, 0x2 | 0x3) => analysis.xo_chip = true,
+            0xF if opcode == 0xF000 => analysis.long_loads = true,
+            0xF => match low_byte {
+                0x30 | 0x75 | 0x85 => analysis.big_font = true,
+                0x01 | 0x02 | 0x3A => analysis.xo_chip = true,
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+    analysis
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assembles opcodes into a ROM.
+    fn rom(opcodes: &[u16]) -> Vec<u8> {
+        opcodes
+            .iter()
+            .flat_map(|opcode| opcode.to_be_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn plain_chip8_rom_needs_nothing_more() {
+        let analysis = analyze_rom(&rom(&[0x00E0, 0x6005, 0xA22A, 0xD015, 0xF01E, 0x1200]));
+        assert!(!analysis.needs_unsupported_features());
+        assert_eq!(analysis.to_string(), "Chip-8");
+    }
+
+    #[test]
+    fn variant_opcodes_are_detected() {
+        // (opcodes, suggested profile, summary)
+        let cases: [(&[u16], _, _); 7] = [
+            (&[0x00FF], "SCHIP", "SCHIP (hires)"),
+            (&[0x00C4, 0x00FB], "SCHIP", "SCHIP (scroll)"),
+            (&[0xF130, 0xF275], "SCHIP", "SCHIP (big font)"),
+            (&[0x5122], "XO-CHIP", "XO-CHIP (XO-CHIP opcodes)"),
+            (&[0xF000, 0x1234], "XO-CHIP", "XO-CHIP (long loads)"),
+            (&[0x00D1], "XO-CHIP", "XO-CHIP (XO-CHIP opcodes)"),
+            (
+                &[0x00FE, 0xF201],
+                "XO-CHIP",
+                "XO-CHIP (hires, XO-CHIP opcodes)",
+            ),
+        ];
+        for (opcodes, profile, summary) in cases {
+            let analysis = analyze_rom(&rom(opcodes));
+            assert_eq!(analysis.suggested_profile(), profile, "{opcodes:04x?}");
+            assert!(analysis.needs_unsupported_features());
+            assert_eq!(analysis.to_string(), summary);
+        }
+    }
+
+    #[test]
+    fn only_aligned_opcodes_are_scanned() {
+        // 00FF straddling two instructions, and a trailing odd byte
+        assert!(!analyze_rom(&[0x60, 0x00, 0xFF, 0x00, 0x00]).hires);
+        assert!(analyze_rom(&[0x60, 0x00, 0x00, 0xFF, 0x00]).hires);
+    }
+}
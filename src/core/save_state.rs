@@ -0,0 +1,446 @@
+//! Save states, as read and written by `retro_unserialize` and `retro_serialize`.
+//!
+//! A save state has a fixed size, since libretro requires the reported size never to grow while a
+//! game is loaded. It's laid out as follows, with multi-byte values in little endian:
+//!
+//! | Bytes                   | Contents                                                   |
+//! |-------------------------|------------------------------------------------------------|
+//! | 4                       | [MAGIC], which also identifies the format version          |
+//! | `TOTAL_MEMORY`          | Memory                                                     |
+//! | `NUM_PIXELS`            | Screen, one byte per pixel (0 for unlit, 1 for lit)        |
+//! | 1                       | Stack depth                                                |
+//! | 2 * [MAX_STACK_DEPTH]   | Return addresses on the stack, with unused entries zeroed  |
+//! | 16                      | V0-VF                                                      |
+//! | 1                       | Delay timer                                                |
+//! | 1                       | Sound timer                                                |
+//! | 2                       | I                                                          |
+//! | 2                       | Program counter                                            |
+//! | 4                       | Buzzer waveform position, in audio frames                  |
+//! | 4                       | Video frames the buzzer keeps sounding after the timer     |
+//! | 4                       | Audio frames owed, in units of 1/frame rate                |
+//! | 4                       | Ticks owed, in units of 1/`TIMER_CYCLE_RATE`               |
+//! | 2                       | Keys pressed during the current Fx0A wait, as a bitmask    |
+//! | 16                      | Key skip cooldowns for each key, in timer cycles           |
+//! | 1                       | Flags: [RETRIGGERED] and [PAUSED]                          |
+
+use super::state::ChipState;
+use crate::constants::*;
+use eyre::{eyre, Result};
+use smallvec::SmallVec;
+
+/// Bytes a save state starts with. The digit is the format version.
+const MAGIC: &[u8; 4] = b"TCS2";
+
+/// Deepest stack a save state can hold. This is well beyond the 16 levels most games expect, so it
+/// only limits games which recurse much deeper with an unlimited stack.
+const MAX_STACK_DEPTH: usize = 64;
+
+/// Flag set if the sound timer was set while it was already running
+const RETRIGGERED: u8 = 1 << 0;
+/// Flag set if execution is paused at a breakpoint
+const PAUSED: u8 = 1 << 1;
+
+/// Size of a save state in bytes
+pub const SIZE: usize = MAGIC.len()
+    + TOTAL_MEMORY
+    + NUM_PIXELS
+    + 1
+    + 2 * MAX_STACK_DEPTH
+    + 16
+    + 1
+    + 1
+    + 2
+    + 2
+    + 4 * 4
+    + 2
+    + 16
+    + 1;
+
+/// Writes a save state of `state` into the start of `buf`.
+pub fn serialize(state: &ChipState, buf: &mut [u8]) -> Result<()> {
+    if buf.len() < SIZE {
+        return Err(eyre!(
+            "buffer of {} bytes is too small for a save state of {SIZE} bytes",
+            buf.len(),
+        ));
+    }
+    if state.stack.len() > MAX_STACK_DEPTH {
+        return Err(eyre!(
+            "stack depth {} exceeds the {MAX_STACK_DEPTH} levels a save state can hold",
+            state.stack.len(),
+        ));
+    }
+
+    let mut bytes = Vec::with_capacity(SIZE);
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&state.mem);
+    bytes.extend(state.screen.iter().map(|&pixel| bool::from(pixel) as u8));
+    bytes.push(state.stack.len() as u8);
+    for depth in 0..MAX_STACK_DEPTH {
+        let addr = state.stack.get(depth).copied().unwrap_or(0);
+        bytes.extend_from_slice(&(addr as u16).to_le_bytes());
+    }
+    bytes.extend_from_slice(&state.v);
+    bytes.push(state.dt);
+    bytes.push(state.st);
+    bytes.extend_from_slice(&state.i.to_le_bytes());
+    bytes.extend_from_slice(&(state.pc as u16).to_le_bytes());
+    for value in [
+        state.audio.step,
+        state.audio.holdover_frames,
+        state.audio.frame_remainder,
+        state.tick_remainder,
+    ] {
+        bytes.extend_from_slice(&(value as u32).to_le_bytes());
+    }
+    bytes.extend_from_slice(&state.key_wait_pressed.to_le_bytes());
+    bytes.extend_from_slice(&state.key_skip_cooldowns);
+    let flags = [
+        (state.sound_retriggered, RETRIGGERED),
+        (state.paused, PAUSED),
+    ];
+    bytes.push(
+        flags
+            .iter()
+            .fold(0, |bits, &(set, flag)| bits | if set { flag } else { 0 }),
+    );
+
+    debug_assert_eq!(bytes.len(), SIZE);
+    buf[..SIZE].copy_from_slice(&bytes);
+    Ok(())
+}
+
+/// Restores `state` from a save state at the start of `data`.
+///
+/// The whole save state is validated first, so that `state` is left unchanged if it's invalid.
+pub fn unserialize(state: &mut ChipState, data: &[u8]) -> Result<()> {
+    let data = data.get(..SIZE).ok_or_else(|| {
+        eyre!(
+            "save state of {} bytes is shorter than {SIZE} bytes",
+            data.len(),
+        )
+    })?;
+
+    let (magic, rest) = data.split_at(MAGIC.len());
+    if magic != MAGIC {
+        return Err(eyre!(
+            "not a save state of this format (found {magic:02x?})"
+        ));
+    }
+    let (mem, rest) = rest.split_at(TOTAL_MEMORY);
+    let (screen, rest) = rest.split_at(NUM_PIXELS);
+    let (&depth, rest) = rest.split_first().unwrap();
+    let (stack, rest) = rest.split_at(2 * MAX_STACK_DEPTH);
+    let (v, rest) = rest.split_at(16);
+    let (registers, rest) = rest.split_at(6);
+    let [dt, st, i_lo, i_hi, pc_lo, pc_hi] = <[u8; 6]>::try_from(registers).unwrap();
+    let (counters, rest) = rest.split_at(4 * 4);
+    let (key_wait_pressed, rest) = rest.split_at(2);
+    let (key_skip_cooldowns, rest) = rest.split_at(16);
+    let [flags] = <[u8; 1]>::try_from(rest).unwrap();
+
+    let mut counters = counters
+        .chunks_exact(4)
+        .map(|value| u32::from_le_bytes(value.try_into().unwrap()) as usize);
+    let mut next_counter = || counters.next().unwrap();
+    let (step, holdover_frames, frame_remainder, tick_remainder) = (
+        next_counter(),
+        next_counter(),
+        next_counter(),
+        next_counter(),
+    );
+
+    let depth = depth as usize;
+    if depth > MAX_STACK_DEPTH {
+        return Err(eyre!("invalid stack depth {depth}"));
+    }
+    let stack: SmallVec<[usize; 16]> = stack
+        .chunks_exact(2)
+        .take(depth)
+        .map(|addr| u16::from_le_bytes([addr[0], addr[1]]) as usize)
+        .collect();
+    let pc = u16::from_le_bytes([pc_lo, pc_hi]) as usize;
+    if let Some(addr) = stack.iter().find(|&&addr| addr >= TOTAL_MEMORY) {
+        return Err(eyre!("address {addr:#x} is outside of memory"));
+    }
+    if pc > TOTAL_MEMORY - 2 {
+        return Err(eyre!("program counter {pc:#x} is outside of memory"));
+    }
+    if step >= AUDIO_SAMPLE_RATE {
+        return Err(eyre!("invalid buzzer waveform position {step}"));
+    }
+    if holdover_frames > MAX_BEEP_GAP_BRIDGE {
+        return Err(eyre!("invalid buzzer holdover of {holdover_frames} frames"));
+    }
+    // The remainder is always less than the frame rate it was accumulated at
+    if SUPPORTED_FRAME_RATES
+        .iter()
+        .all(|&frame_rate| frame_rate <= frame_remainder)
+    {
+        return Err(eyre!("invalid audio frame remainder {frame_remainder}"));
+    }
+    if tick_remainder >= TIMER_CYCLE_RATE {
+        return Err(eyre!("invalid tick remainder {tick_remainder}"));
+    }
+    if flags & !(RETRIGGERED | PAUSED) != 0 {
+        return Err(eyre!("invalid flags {flags:#04x}"));
+    }
+    if let Some(&pixel) = screen.iter().find(|&&pixel| pixel > 1) {
+        return Err(eyre!("invalid pixel value {pixel}"));
+    }
+
+    state.mem.copy_from_slice(mem);
+    for (pixel, &value) in state.screen.iter_mut().zip(screen) {
+        *pixel = (value != 0).into();
+    }
+    state.stack = stack;
+    state.v.copy_from_slice(v);
+    state.dt = dt;
+    state.st = st;
+    state.i = u16::from_le_bytes([i_lo, i_hi]);
+    state.pc = pc;
+    state.audio.step = step;
+    state.audio.holdover_frames = holdover_frames;
+    state.audio.frame_remainder = frame_remainder;
+    state.tick_remainder = tick_remainder;
+    state.key_wait_pressed = u16::from_le_bytes([key_wait_pressed[0], key_wait_pressed[1]]);
+    state.key_skip_cooldowns.copy_from_slice(key_skip_cooldowns);
+    state.sound_retriggered = flags & RETRIGGERED != 0;
+    state.paused = flags & PAUSED != 0;
+    // Whatever execution was halted on belongs to the replaced state
+    state.halt = None;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{DrawMode, InvalidInstructionAction, Options, SpriteOverrunAction};
+    use bitvec::prelude::*;
+
+    /// Returns everything a save state holds about `state`, in a comparable form.
+    #[allow(clippy::type_complexity)]
+    fn snapshot(
+        state: &ChipState,
+    ) -> (
+        (Vec<u8>, Vec<bool>, Vec<usize>),
+        ([u8; 16], u8, u8, u16, usize),
+        (usize, usize, usize, usize),
+        (u16, [u8; 16], bool, bool),
+    ) {
+        (
+            (
+                state.mem.to_vec(),
+                state.screen.iter().map(|&pixel| pixel.into()).collect(),
+                state.stack.to_vec(),
+            ),
+            (state.v, state.dt, state.st, state.i, state.pc),
+            (
+                state.audio.step,
+                state.audio.holdover_frames,
+                state.audio.frame_remainder,
+                state.tick_remainder,
+            ),
+            (
+                state.key_wait_pressed,
+                state.key_skip_cooldowns,
+                state.sound_retriggered,
+                state.paused,
+            ),
+        )
+    }
+
+    /// Returns a machine which has run a few instructions of a game that sets the timers, draws
+    /// and calls a subroutine, with the rest of its runtime state set to distinctive values.
+    fn running_machine() -> ChipState {
+        let program: [u16; 9] = [
+            0x6005, 0xF015, 0xF018, 0xF029, 0xD015, 0x220E, 0x120C, // Main
+            0xF01E, 0x1210, // Subroutine
+        ];
+        let mut state = ChipState {
+            pc: GAME_ADDRESS,
+            ..Default::default()
+        };
+        for (addr, opcode) in (GAME_ADDRESS..).step_by(2).zip(program) {
+            state.mem[addr..addr + 2].copy_from_slice(&opcode.to_be_bytes());
+        }
+        let opts = Options::default();
+        for _ in 0..10 {
+            state.tick(bits![0; 16], &opts);
+        }
+
+        state.audio.step = 123;
+        state.audio.holdover_frames = 2;
+        state.audio.frame_remainder = 3;
+        state.tick_remainder = 17;
+        state.key_wait_pressed = 0b101;
+        state.key_skip_cooldowns[3] = 4;
+        state.sound_retriggered = true;
+        state.paused = true;
+        state
+    }
+
+    #[test]
+    fn state_round_trips_through_a_save_state() {
+        let mut state = running_machine();
+        assert_eq!((state.pc, state.stack.len()), (0x210, 1));
+        let expected = snapshot(&state);
+        let mut buf = vec![0; SIZE];
+        serialize(&state, &mut buf).unwrap();
+
+        state.reset(true);
+        state.mem.fill(0xFF);
+        state.audio = Default::default();
+        state.tick_remainder = 0;
+        state.key_skip_cooldowns = [0; 16];
+        state.sound_retriggered = false;
+        state.paused = false;
+        assert_ne!(snapshot(&state), expected);
+
+        unserialize(&mut state, &buf).unwrap();
+        assert_eq!(snapshot(&state), expected);
+    }
+
+    #[test]
+    fn fields_are_at_their_documented_offsets() {
+        let mut state = ChipState {
+            i: 0x0ABC,
+            pc: 0x0234,
+            dt: 3,
+            st: 4,
+            ..Default::default()
+        };
+        state.stack.extend([0x0202, 0x0346]);
+        state.v[0] = 0x11;
+        state.v[0xF] = 0xFF;
+        state.audio.step = 0x1234;
+        state.paused = true;
+        let mut buf = vec![0; SIZE];
+        serialize(&state, &mut buf).unwrap();
+
+        assert_eq!(buf[..4], *b"TCS2");
+        let stack_offset = 4 + TOTAL_MEMORY + NUM_PIXELS;
+        assert_eq!(buf[stack_offset], 2);
+        let stack = &buf[stack_offset + 1..stack_offset + 1 + 2 * MAX_STACK_DEPTH];
+        assert_eq!(stack[..4], [0x02, 0x02, 0x46, 0x03]);
+        assert!(stack[4..].iter().all(|&byte| byte == 0));
+
+        let v_offset = stack_offset + 1 + 2 * MAX_STACK_DEPTH;
+        assert_eq!((buf[v_offset], buf[v_offset + 0xF]), (0x11, 0xFF));
+        let timers_offset = v_offset + 16;
+        assert_eq!(buf[timers_offset..timers_offset + 2], [3, 4]);
+        let i_offset = timers_offset + 2;
+        assert_eq!(buf[i_offset..i_offset + 2], [0xBC, 0x0A]);
+        assert_eq!(buf[i_offset + 2..i_offset + 4], [0x34, 0x02]);
+        assert_eq!(buf[i_offset + 4..i_offset + 8], [0x34, 0x12, 0, 0]);
+        assert_eq!(buf[SIZE - 1], PAUSED);
+    }
+
+    #[test]
+    fn quirks_behave_the_same_after_restoring() {
+        let quirk_cases: [(&[u16], Vec<Options>); 3] = [
+            // Shift V1 into V0
+            (
+                &[0x6103, 0x8016],
+                [false, true]
+                    .into_iter()
+                    .map(|shift_quirk| Options {
+                        shift_quirk,
+                        ..Options::default()
+                    })
+                    .collect(),
+            ),
+            // Draw the start of the program twice
+            (
+                &[0xA200, 0xD005, 0xD005],
+                [DrawMode::Xor, DrawMode::Overwrite]
+                    .into_iter()
+                    .map(|draw_mode| Options {
+                        draw_mode,
+                        ..Options::default()
+                    })
+                    .collect(),
+            ),
+            // Store 0xFF at 0xFFC-0xFFF and 0x000, then draw 5 rows from 0xFFC
+            (
+                &[
+                    0x60FF, 0x61FF, 0x62FF, 0x63FF, 0xAFFC, 0xF355, 0xA000, 0xF055, 0xAFFC, 0xD455,
+                ],
+                [
+                    SpriteOverrunAction::Invalid,
+                    SpriteOverrunAction::Clamp,
+                    SpriteOverrunAction::Wrap,
+                ]
+                .into_iter()
+                .map(|sprite_overrun| Options {
+                    sprite_overrun,
+                    invalid_instruction: InvalidInstructionAction::Pause,
+                    ..Options::default()
+                })
+                .collect(),
+            ),
+        ];
+
+        for (program, quirk_opts) in quirk_cases {
+            let mut results = Vec::new();
+            for opts in quirk_opts {
+                let mut uninterrupted = ChipState {
+                    pc: GAME_ADDRESS,
+                    ..Default::default()
+                };
+                for (addr, opcode) in (GAME_ADDRESS..).step_by(2).zip(program) {
+                    uninterrupted.mem[addr..addr + 2].copy_from_slice(&opcode.to_be_bytes());
+                }
+                for _ in 1..program.len() {
+                    uninterrupted.tick(bits![0; 16], &opts);
+                }
+
+                // Save just before the instruction which depends on the quirk
+                let mut buf = vec![0; SIZE];
+                serialize(&uninterrupted, &mut buf).unwrap();
+                let mut restored = ChipState::default();
+                unserialize(&mut restored, &buf).unwrap();
+
+                uninterrupted.tick(bits![0; 16], &opts);
+                restored.tick(bits![0; 16], &opts);
+                let expected = snapshot(&uninterrupted);
+                assert_eq!(snapshot(&restored), expected, "{opts:?}");
+                results.push(expected);
+            }
+
+            // Each setting gives a different result, so the last instruction depends on it
+            for (n, result) in results.iter().enumerate() {
+                assert!(!results[n + 1..].contains(result), "{program:04X?}");
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_save_states_are_rejected() {
+        let mut buf = vec![0; SIZE];
+        serialize(&running_machine(), &mut buf).unwrap();
+        assert!(serialize(&running_machine(), &mut buf[..SIZE - 1]).is_err());
+
+        let pc_offset = SIZE - (4 * 4 + 2 + 16 + 1) - 2;
+        let flags_offset = SIZE - 1;
+        let corruptions: [(usize, &[u8]); 6] = [
+            (0, b"TCS0"),
+            (pc_offset, &(TOTAL_MEMORY as u16 - 1).to_le_bytes()),
+            (pc_offset + 2, &(AUDIO_SAMPLE_RATE as u32).to_le_bytes()),
+            (
+                pc_offset + 6,
+                &(MAX_BEEP_GAP_BRIDGE as u32 + 1).to_le_bytes(),
+            ),
+            (pc_offset + 10, &u32::MAX.to_le_bytes()),
+            (flags_offset, &[1 << 2]),
+        ];
+        for (offset, bytes) in corruptions {
+            let mut data = buf.clone();
+            data[offset..offset + bytes.len()].copy_from_slice(bytes);
+            let mut state = ChipState::default();
+            assert!(unserialize(&mut state, &data).is_err(), "{offset}");
+            assert_eq!(state.pc, 0, "state changed despite the error");
+        }
+        assert!(unserialize(&mut ChipState::default(), &buf[..SIZE - 1]).is_err());
+    }
+}
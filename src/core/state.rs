@@ -1,17 +1,38 @@
-use crate::{callbacks as cb, constants::*, utils::BitSliceExt};
+use super::{
+    audio::AudioState, breakpoints::Breakpoints, display_filter::DisplayFilterState,
+    timer_clock::TimerClock,
+};
+use crate::{
+    callbacks as cb,
+    constants::*,
+    options::{
+        DrawMode, InvalidInstructionAction, Options, Palette, SpriteOverrunAction,
+        StackOverflowAction,
+    },
+    utils::{BitSliceExt, Fnv1aHasher},
+};
 use bitvec::prelude::*;
 use parking_lot::{const_mutex, Mutex};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use smallvec::SmallVec;
 use std::{
-    cmp, mem,
-    ops::{Deref, DerefMut},
+    cell::RefCell,
+    cmp, fmt,
+    hash::Hasher,
+    mem,
+    ops::{Deref, DerefMut, Range},
 };
 
 static CHIP_STATE: Mutex<Option<Box<ChipState>>> = const_mutex(None);
 
+thread_local! {
+    /// Random number generator for Cxkk, which tests can seed to make it deterministic
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+}
+
 type DigitSprite = [u8; 5];
 type FontStore = [DigitSprite; 16];
-const FONT_DATA: FontStore = [
+pub(super) const FONT_DATA: FontStore = [
     [0xF0, 0x90, 0x90, 0x90, 0xF0], // Digit 0
     [0x20, 0x60, 0x20, 0x20, 0x70], // Digit 1
     [0xF0, 0x10, 0xF0, 0x80, 0xF0], // Digit 2
@@ -33,6 +54,8 @@ const FONT_DATA: FontStore = [
 #[derive(Default)]
 pub struct ChipState {
     pub mem: ChipMem,
+    /// The game as it was loaded, for restoring it into memory on reset
+    pub rom: Box<[u8]>,
     pub screen: ChipScreen,
     pub stack: SmallVec<[usize; 16]>,
     pub v: [u8; 16],
@@ -40,6 +63,51 @@ pub struct ChipState {
     pub st: u8,
     pub i: u16,
     pub pc: usize,
+    /// Set once the game has executed a draw or screen clear instruction
+    pub has_drawn: bool,
+    /// Number of video frames run since the game was loaded
+    pub frame_count: usize,
+    /// Number of video frames in which the game executed, leaving out the startup delay, boot splash
+    /// and any time spent paused
+    pub executed_frames: usize,
+    /// Set once the draw watchdog has warned that the game hasn't drawn anything
+    pub draw_watchdog_fired: bool,
+    pub audio: AudioState,
+    /// Set when Fx18 sets the sound timer while it's already running
+    pub sound_retriggered: bool,
+    pub display_filter: DisplayFilterState,
+    /// The video output scaled up to the internal resolution, kept to avoid reallocating it every
+    /// frame
+    pub scaled_frame: Vec<u16>,
+    /// Set if execution has been paused due to an invalid instruction
+    pub halt: Option<Halt>,
+    pub breakpoints: Breakpoints,
+    /// Set while execution is paused at a breakpoint. Clearing it resumes execution.
+    pub paused: bool,
+    /// Number of instructions executed for each opcode family (the first hex digit), if enabled
+    pub opcode_counts: [u64; 16],
+    /// Number of sprites drawn, if the wrap diagnostic is enabled
+    pub sprite_draws: u64,
+    /// Number of sprites drawn with lit pixels clipped at the edge of the screen, if the wrap
+    /// diagnostic is enabled
+    pub clipped_sprite_draws: u64,
+    /// Bitmask of the keys which have been pressed during the current Fx0A wait
+    pub(super) key_wait_pressed: u16,
+    /// Clock for decrementing the timers by real time, if enabled
+    pub timer_clock: TimerClock,
+    /// Timer cycles remaining before each held key can register as pressed again for Ex9E/ExA1
+    pub(super) key_skip_cooldowns: [u8; 16],
+    /// Ticks owed from previous timer cycles, in units of 1/TIMER_CYCLE_RATE of a tick
+    pub(super) tick_remainder: usize,
+    /// The CPU speed that the effective tick rate was last logged for, if it has been
+    pub logged_cpu_speed: Option<Option<usize>>,
+}
+
+/// The invalid instruction that caused execution to be paused.
+#[derive(Clone, Copy)]
+pub struct Halt {
+    pub pc: usize,
+    pub opcode: u16,
 }
 
 impl ChipState {
@@ -50,6 +118,95 @@ impl ChipState {
         }
     }
 
+    /// Resets the machine like a console's reset button. The registers, timers and stack are
+    /// cleared, and the font and game are copied back into memory. The screen is cleared only if
+    /// `clear_screen` is set.
+    ///
+    /// The rest of memory is left as is, and debugging aids like breakpoints are kept.
+    pub fn reset(&mut self, clear_screen: bool) {
+        if clear_screen {
+            self.screen = ChipScreen::default();
+        }
+        self.stack.clear();
+        self.v = [0; 16];
+        self.dt = 0;
+        self.st = 0;
+        self.i = 0;
+        self.pc = GAME_ADDRESS;
+        self.halt = None;
+        self.sound_retriggered = false;
+        self.key_wait_pressed = 0;
+        self.key_skip_cooldowns = [0; 16];
+
+        load_font(&mut self.mem);
+        self.mem[GAME_ADDRESS..GAME_ADDRESS + self.rom.len()].copy_from_slice(&self.rom);
+    }
+
+    /// Decrements the delay and sound timers (and the key skip cooldowns) by one cycle, stopping at
+    /// zero.
+    pub fn decrement_timers(&mut self) {
+        self.dt = self.dt.saturating_sub(1);
+        self.st = self.st.saturating_sub(1);
+        for cooldown in self.key_skip_cooldowns.iter_mut() {
+            *cooldown = cooldown.saturating_sub(1);
+        }
+    }
+
+    /// Returns the number of instructions to execute during the current timer cycle.
+    ///
+    /// If `tick_rate` isn't a multiple of the timer cycle rate, the fractional part is carried over
+    /// to later cycles, so that on average exactly `tick_rate` instructions execute per second.
+    pub fn ticks_for_timer_cycle(&mut self, tick_rate: usize) -> usize {
+        let total = tick_rate + self.tick_remainder;
+        self.tick_remainder = total % TIMER_CYCLE_RATE;
+        total / TIMER_CYCLE_RATE
+    }
+
+    /// Returns whether a key counts as pressed for Ex9E/ExA1.
+    ///
+    /// If a key skip interval is set, a held key only counts as pressed once per interval, so that
+    /// at high tick rates a held key doesn't make menus race past. Releasing the key resets the
+    /// interval so that the next press always registers.
+    fn key_pressed_for_skip(&mut self, key: usize, user_input: &BitSlice, opts: &Options) -> bool {
+        let held = user_input[key];
+        let Some(interval) = opts.key_skip_interval else {
+            return held;
+        };
+
+        let cooldown = &mut self.key_skip_cooldowns[key];
+        match (held, *cooldown) {
+            (true, 0) => {
+                *cooldown = interval;
+                true
+            }
+            (true, _) => false,
+            (false, _) => {
+                *cooldown = 0;
+                false
+            }
+        }
+    }
+
+    /// Computes a deterministic hash of the screen and CPU state.
+    ///
+    /// Every field is hashed in a fixed byte order, so two emulators that are in sync produce the
+    /// same hash regardless of platform. Comparing hashes frame-by-frame is a cheap way to find
+    /// where two runs (e.g. netplay peers or regression runs) diverge.
+    pub fn frame_hash(&self) -> u64 {
+        let mut hasher = Fnv1aHasher::default();
+        for &pixel in self.screen.iter() {
+            hasher.write(&[bool::from(pixel) as u8]);
+        }
+        hasher.write(&self.v);
+        hasher.write(&[self.dt, self.st]);
+        hasher.write(&self.i.to_le_bytes());
+        hasher.write(&(self.pc as u16).to_le_bytes());
+        for &addr in self.stack.iter() {
+            hasher.write(&(addr as u16).to_le_bytes());
+        }
+        hasher.finish()
+    }
+
     /// Executes one Chip-8 instruction and updates the state appropriately.
     ///
     /// One challenge of writing this emulator is the difference between the original Chip-8 and
@@ -66,25 +223,49 @@ impl ChipState {
     /// A helpful straightforward overview of Chip-8, though there are multiple subtle instruction
     /// differences that are actually from subsequent modifications of the Chip-8 interpreter. So
     /// I would not rely too much on the instruction reference there.
-    pub fn tick(&mut self, user_input: &BitSlice) {
+    pub fn tick(&mut self, user_input: &BitSlice, opts: &Options) {
         // If this flag is set, the program counter (pc) will not be incremented at the end
         // of this function (important for returns, jumps, etc.)
         let mut preserve_pc = false;
 
+        // Do nothing if execution was paused due to an invalid instruction or a breakpoint
+        if self.halt.is_some() || self.paused {
+            return;
+        }
+
+        // A jump or skip near the end of memory can leave pc where no whole instruction fits
+        if self.pc > TOTAL_MEMORY - 2 {
+            let opcode = u16::from_be_bytes([
+                self.mem[self.pc % TOTAL_MEMORY],
+                self.mem[(self.pc + 1) % TOTAL_MEMORY],
+            ]);
+            return self.invalid_instruction(opcode, opts);
+        }
+
         let instr_bits = self.mem[self.pc..self.pc + 2].view_bits::<Msb0>();
         let (prefix, stem) = instr_bits.split_at(4);
+        let opcode: u16 = instr_bits.load_be();
+
+        #[cfg(debug_assertions)]
+        cross_check_decode(instr_bits);
+
+        if opts.opcode_stats {
+            self.opcode_counts[(opcode >> 12) as usize] += 1;
+        }
 
         match prefix.load::<u8>() {
             0x0 => match stem.load_be::<u16>() {
                 // 00E0 - Clear the display
                 0x0E0 => {
                     self.screen = Default::default();
+                    self.has_drawn = true;
                 }
                 // 00EE - Return from a subroutine
                 0x0EE => {
                     self.pc = self.stack.pop().unwrap_or_else(|| {
-                        tracing::error!("tick: cannot pop from empty Chip8 stack");
-                        panic!();
+                        cb::env_shutdown(
+                            "stack underflow: return with no subroutine to return from",
+                        )
                     });
                     preserve_pc = true;
                 }
@@ -99,11 +280,23 @@ impl ChipState {
             }
 
             // 2nnn - Call a subroutine
-            0x2 => {
-                self.stack.push(self.pc + 2);
-                self.pc = stem.load_be();
-                preserve_pc = true;
-            }
+            0x2 => match opts.stack_size.filter(|&size| self.stack.len() >= size) {
+                None => {
+                    self.stack.push(self.pc + 2);
+                    self.pc = stem.load_be();
+                    preserve_pc = true;
+                }
+                Some(size) => {
+                    let message = format!(
+                        "stack overflow: subroutine call at {:#05x} exceeds {} levels",
+                        self.pc, size,
+                    );
+                    match opts.stack_overflow {
+                        StackOverflowAction::Shutdown => cb::env_shutdown(message),
+                        StackOverflowAction::Ignore => tracing::warn!("{message}; ignoring it"),
+                    }
+                }
+            },
 
             // 3xkk - Skip next instruction if Vx = kk
             0x3 => {
@@ -130,7 +323,7 @@ impl ChipState {
                 let (x, y, suffix) = stem.split_at_two(4, 8);
 
                 if suffix.load::<u8>() != 0 {
-                    invalid_instruction_shutdown(instr_bits);
+                    return self.invalid_instruction(opcode, opts);
                 }
 
                 let x: usize = x.load_be();
@@ -159,6 +352,13 @@ impl ChipState {
                 let (x, y, suffix) = stem.split_at_two(4, 8);
                 let x: usize = x.load_be();
                 let y: usize = y.load_be();
+
+                // The register shifted by 8xy6/8xyE (CHIP-48 and SCHIP ignore Vy)
+                let shift_src = match opts.shift_quirk {
+                    false => y,
+                    true => x,
+                };
+
                 match suffix.load_be::<u8>() {
                     // 8xy0 - Set Vx = Vy
                     0x0 => self.v[x] = self.v[y],
@@ -172,42 +372,45 @@ impl ChipState {
                     // 8xy3 - Set Vx = Vx XOR Vy
                     0x3 => self.v[x] ^= self.v[y],
 
+                    // Note that for the following instructions VF must be written last, so that
+                    // when x is 0xF the flag wins over the result (as on the original hardware).
+
                     // 8xy4 - Set Vx = Vx + Vy, set VF = carry
                     0x4 => {
-                        let sum = self.v[x] as u32 + self.v[y] as u32;
-                        self.v[0xF] = (sum > 0xFF) as u8;
-                        self.v[x] = sum as u8;
+                        let (sum, carry) = self.v[x].overflowing_add(self.v[y]);
+                        self.v[x] = sum;
+                        self.v[0xF] = carry as u8;
                     }
 
                     // 8xy5 - Set Vx = Vx - Vy, set VF = NOT borrow
                     0x5 => {
-                        let borrow = self.v[y] > self.v[x];
+                        let (difference, borrow) = self.v[x].overflowing_sub(self.v[y]);
+                        self.v[x] = difference;
                         self.v[0xF] = !borrow as u8;
-                        self.v[x] = self.v[x].wrapping_sub(self.v[y]);
                     }
 
                     // 8xy6 - Set Vx = Vy >> 1, set VF to least sig bit before shift
                     0x6 => {
-                        self.v[0xF] = self.v[y] & 1;
-                        self.v[x] = self.v[y] >> 1;
+                        let shifted_out = self.v[shift_src] & 1;
+                        self.v[x] = self.v[shift_src] >> 1;
+                        self.v[0xF] = shifted_out;
                     }
 
                     // 8xy7 - Set Vx = Vy - Vx, set VF = NOT borrow
                     0x7 => {
-                        let borrow = self.v[x] > self.v[y];
+                        let (difference, borrow) = self.v[y].overflowing_sub(self.v[x]);
+                        self.v[x] = difference;
                         self.v[0xF] = !borrow as u8;
-                        self.v[x] = self.v[y].wrapping_sub(self.v[x]);
                     }
 
                     // 8xyE - Set Vx = Vy << 1, set VF to most sig bit before shift
                     0xE => {
-                        self.v[0xF] = self.v[y] >> 7;
-                        self.v[x] = self.v[y] << 1;
+                        let shifted_out = self.v[shift_src] >> 7;
+                        self.v[x] = self.v[shift_src] << 1;
+                        self.v[0xF] = shifted_out;
                     }
 
-                    _ => {
-                        invalid_instruction_shutdown(instr_bits);
-                    }
+                    _ => return self.invalid_instruction(opcode, opts),
                 }
             }
 
@@ -216,7 +419,7 @@ impl ChipState {
                 let (x, y, suffix) = stem.split_at_two(4, 8);
 
                 if suffix.load::<u8>() != 0 {
-                    invalid_instruction_shutdown(instr_bits);
+                    return self.invalid_instruction(opcode, opts);
                 }
 
                 let x: usize = x.load_be();
@@ -229,7 +432,8 @@ impl ChipState {
             // Annn - Set I = nnn
             0xA => self.i = stem.load_be(),
 
-            // Bnnn - Jump to location V0 + nnn
+            // Bnnn - Jump to location V0 + nnn (which may be past the end of memory, in which case
+            // the next fetch fails)
             0xB => {
                 self.pc = self.v[0] as usize + stem.load_be::<usize>();
                 preserve_pc = true;
@@ -237,14 +441,13 @@ impl ChipState {
 
             // Cxkk - Set Vx = random byte AND kk
             0xC => {
-                use rand::{thread_rng, Rng};
-                let mut rng = thread_rng();
-
                 let (x, kk) = stem.split_at(4);
                 let x: usize = x.load_be();
                 let kk: u8 = kk.load_be();
 
-                self.v[x] = rng.gen::<u8>() & kk;
+                // A random byte is drawn even when kk is 0 (so Cx00 always zeroes Vx), so that
+                // every Cxkk advances the RNG by the same amount regardless of its mask
+                self.v[x] = RNG.with(|rng| rng.borrow_mut().gen::<u8>()) & kk;
             }
 
             // Dxyn - Draw a sprite at position Vx, Vy with n bytes of sprite data starting at the
@@ -255,25 +458,50 @@ impl ChipState {
                 let y_pos = self.v[y.load_be::<usize>()];
                 let n: usize = n.load_be();
                 let sprite_addr = self.i as usize;
-                assert!(
-                    sprite_addr + n - 1 < TOTAL_MEMORY,
-                    "tick: invalid Chip-8 memory address in instruction {:x?}",
-                    instr_bits.load_be::<u16>(),
-                );
-                let sprite_data = &self.mem[sprite_addr..sprite_addr + n];
-                self.v[0xF] = self.screen.render_sprite(sprite_data, x_pos, y_pos) as u8;
+                let sprite_end = sprite_addr + n;
+                let in_bounds = sprite_end <= TOTAL_MEMORY;
+                let sprite_data: SmallVec<[u8; 15]> = match (in_bounds, opts.sprite_overrun) {
+                    (true, _) => SmallVec::from_slice(&self.mem[sprite_addr..sprite_end]),
+                    (false, SpriteOverrunAction::Invalid) => {
+                        tracing::error!(
+                            "sprite of {} bytes at {:#05x} reads past end of memory",
+                            n,
+                            sprite_addr,
+                        );
+                        return self.invalid_instruction(opcode, opts);
+                    }
+                    (false, SpriteOverrunAction::Clamp) => {
+                        SmallVec::from_slice(&self.mem[sprite_addr.min(TOTAL_MEMORY)..])
+                    }
+                    (false, SpriteOverrunAction::Wrap) => (sprite_addr..sprite_end)
+                        .map(|addr| self.mem[addr % TOTAL_MEMORY])
+                        .collect(),
+                };
+                self.check_read(sprite_addr..sprite_end, opcode);
+                if opts.wrap_diag {
+                    self.sprite_draws += 1;
+                    if ChipScreen::sprite_clipped(&sprite_data, x_pos, y_pos) {
+                        self.clipped_sprite_draws += 1;
+                    }
+                }
+                let collision =
+                    self.screen
+                        .render_sprite(&sprite_data, x_pos, y_pos, opts.draw_mode);
+                self.v[0xF] = collision as u8;
+                self.has_drawn = true;
             }
 
             // Ex9E and ExA1 (see comments below)
             0xE => {
                 let (x, suffix) = stem.split_at(4);
-                let key = self.v[x.load_be::<usize>()] as usize;
+                // Only the low nibble selects a key, so that a value over 0xF can't index past them
+                let key = (self.v[x.load_be::<usize>()] & 0xF) as usize;
 
                 match suffix.load_be::<u8>() {
                     // Ex9E - Skip the next instruction if the key corresponding to the hex
                     // value in register VX is pressed
                     0x9E => {
-                        if user_input[key] {
+                        if self.key_pressed_for_skip(key, user_input, opts) {
                             self.pc += 2;
                         }
                     }
@@ -281,12 +509,12 @@ impl ChipState {
                     // ExA1 - Skip the next instruction if the key corresponding to the hex
                     // value in register VX is NOT pressed
                     0xA1 => {
-                        if !user_input[key] {
+                        if !self.key_pressed_for_skip(key, user_input, opts) {
                             self.pc += 2;
                         }
                     }
 
-                    _ => invalid_instruction_shutdown(instr_bits),
+                    _ => return self.invalid_instruction(opcode, opts),
                 }
             }
 
@@ -300,22 +528,43 @@ impl ChipState {
                     0x07 => self.v[x] = self.dt,
 
                     // Fx0A - Wait for a key press, store the value of the key in Vx
-                    0x0A => match user_input.first_one() {
-                        // Get the lowest key number that's pressed, if any, and return that
-                        Some(key) => self.v[x] = key as u8,
-                        // Otherwise, preserve the current pc so that this instruction is repeated
-                        // until the user presses a key.
-                        None => preserve_pc = true,
-                    },
+                    //
+                    // As on the COSMAC VIP, the wait only ends once a pressed key is released
+                    // again. Input only changes between frames, so this instruction repeats
+                    // (preserving pc) across as many frames as needed, remembering which keys have
+                    // been pressed since the wait began.
+                    0x0A => {
+                        let held = user_input
+                            .iter_ones()
+                            .fold(0u16, |mask, key| mask | 1 << key);
+                        self.key_wait_pressed |= held;
+                        let released = self.key_wait_pressed & !held;
+
+                        match released {
+                            // Keep waiting until a pressed key is released
+                            0 => preserve_pc = true,
+                            // If several keys were released at once, take the lowest
+                            _ => {
+                                self.v[x] = released.trailing_zeros() as u8;
+                                self.key_wait_pressed = 0;
+                            }
+                        }
+                    }
 
                     // Fx15 - Set delay timer = Vx
                     0x15 => self.dt = self.v[x],
 
                     // Fx18 - Set sound timer = Vx
-                    0x18 => self.st = self.v[x],
+                    0x18 => {
+                        self.sound_retriggered |= self.st > 0 && self.v[x] > 0;
+                        self.st = self.v[x];
+                    }
 
                     // Fx1E - Set I = I + Vx
-                    0x1E => self.i += self.v[x] as u16,
+                    0x1E => match self.i.checked_add(self.v[x] as u16) {
+                        Some(i) => self.i = i,
+                        None => return self.invalid_instruction(opcode, opts),
+                    },
 
                     // Fx29 - Set I = location of sprite for digit Vx
                     0x29 => {
@@ -327,11 +576,15 @@ impl ChipState {
 
                     // Fx33 - Store the BCD equivalent of Vx at addresses I, I + 1, and I + 2
                     0x33 => {
+                        let Some(addrs) = self.i_range(3) else {
+                            return self.invalid_instruction(opcode, opts);
+                        };
+                        self.check_write(addrs.clone(), opcode);
                         let ones = self.v[x] % 10;
                         let tens = (self.v[x] / 10) % 10;
                         let hundreds = self.v[x] / 100; // This is sufficient, max Vx is 255
 
-                        let dst = &mut self.mem[self.i as usize..self.i as usize + 3];
+                        let dst = &mut self.mem[addrs];
                         dst[0] = hundreds;
                         dst[1] = tens;
                         dst[2] = ones;
@@ -340,7 +593,11 @@ impl ChipState {
                     // Fx55 - Store V0 to Vx inclusive in memory starting at address I.
                     // I is set to I + X + 1 after operation.
                     0x55 => {
-                        let dst = &mut self.mem[self.i as usize..self.i as usize + x + 1];
+                        let Some(addrs) = self.i_range(x + 1) else {
+                            return self.invalid_instruction(opcode, opts);
+                        };
+                        self.check_write(addrs.clone(), opcode);
+                        let dst = &mut self.mem[addrs];
                         let src = &self.v[..x + 1];
                         dst.copy_from_slice(src);
                         self.i += x as u16 + 1;
@@ -349,13 +606,17 @@ impl ChipState {
                     // Fx65 - Fill V0 to Vx inclusive with the memory starting at address I.
                     // I is set to I + X + 1 after operation.
                     0x65 => {
+                        let Some(addrs) = self.i_range(x + 1) else {
+                            return self.invalid_instruction(opcode, opts);
+                        };
+                        self.check_read(addrs.clone(), opcode);
                         let dst = &mut self.v[..x + 1];
-                        let src = &self.mem[self.i as usize..self.i as usize + x + 1];
+                        let src = &self.mem[addrs];
                         dst.copy_from_slice(src);
                         self.i += x as u16 + 1;
                     }
 
-                    _ => invalid_instruction_shutdown(instr_bits),
+                    _ => return self.invalid_instruction(opcode, opts),
                 }
             }
 
@@ -365,6 +626,96 @@ impl ChipState {
         if !preserve_pc {
             self.pc += 2;
         }
+
+        #[cfg(test)]
+        tests::record_opcode_family(opcode);
+
+        // Checked after moving to the next instruction, so that resuming from a pc breakpoint
+        // executes the instruction there rather than immediately hitting the breakpoint again
+        if self.breakpoints.pc.contains(&self.pc) {
+            self.pause(format_args!("pc breakpoint"), opcode);
+        }
+    }
+
+    /// Pauses execution if an instruction reads from a watched address.
+    fn check_read(&mut self, addrs: Range<usize>, opcode: u16) {
+        if let Some(addr) = self.breakpoints.watched_read(addrs) {
+            self.pause(
+                format_args!("read from watched address {addr:#05x}"),
+                opcode,
+            );
+        }
+    }
+
+    /// Returns the `len` addresses starting at I, or `None` if they run past the end of memory.
+    fn i_range(&self, len: usize) -> Option<Range<usize>> {
+        let start = self.i as usize;
+        (start + len <= TOTAL_MEMORY).then(|| start..start + len)
+    }
+
+    /// Pauses execution if an instruction writes to a watched address.
+    fn check_write(&mut self, addrs: Range<usize>, opcode: u16) {
+        if let Some(addr) = self.breakpoints.watched_write(addrs) {
+            self.pause(format_args!("write to watched address {addr:#05x}"), opcode);
+        }
+    }
+
+    /// Pauses execution at a breakpoint and logs the CPU state.
+    ///
+    /// `opcode` is the instruction that was executing when the breakpoint was hit.
+    fn pause(&mut self, reason: fmt::Arguments, opcode: u16) {
+        tracing::info!(
+            "paused on {} (last instruction {:04x}): pc={:#05x} i={:#05x} v={:02x?} dt={} st={}",
+            reason,
+            opcode,
+            self.pc,
+            self.i,
+            self.v,
+            self.dt,
+            self.st,
+        );
+        self.paused = true;
+    }
+
+    /// Handles an invalid instruction by shutting down, pausing execution or skipping the
+    /// instruction (with or without a warning), depending on the configured action.
+    ///
+    /// If the error beep is enabled, it starts playing first, unless the instruction is to be
+    /// silently treated as a no-op. When shutting down, the whole beep is sent to the frontend right
+    /// away since no further frames will run.
+    fn invalid_instruction(&mut self, opcode: u16, opts: &Options) {
+        if opts.error_beep && opts.invalid_instruction != InvalidInstructionAction::Nop {
+            self.audio.start_error_beep();
+        }
+
+        match opts.invalid_instruction {
+            InvalidInstructionAction::Shutdown => {
+                if self.audio.error_beep_active() {
+                    cb::audio_sample_batch(self.audio.generate_error_beep_batch(ERROR_BEEP_FRAMES));
+                }
+                invalid_instruction_shutdown(opcode)
+            }
+            InvalidInstructionAction::Pause => {
+                tracing::error!(
+                    "invalid instruction {:04x} at {:#05x}; pausing execution",
+                    opcode,
+                    self.pc,
+                );
+                self.halt = Some(Halt {
+                    pc: self.pc,
+                    opcode,
+                });
+            }
+            InvalidInstructionAction::Skip => {
+                tracing::warn!(
+                    "invalid instruction {:04x} at {:#05x}; skipping",
+                    opcode,
+                    self.pc,
+                );
+                self.pc = (self.pc + 2) % TOTAL_MEMORY;
+            }
+            InvalidInstructionAction::Nop => self.pc = (self.pc + 2) % TOTAL_MEMORY,
+        }
     }
 }
 
@@ -390,11 +741,10 @@ impl DerefMut for ChipMem {
     }
 }
 
-#[derive(Clone, Copy, PartialEq)]
-#[repr(u16)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum PixelState {
-    Black = 0,
-    White = 0xFFFF,
+    Black,
+    White,
 }
 
 impl PixelState {
@@ -428,23 +778,32 @@ impl From<PixelState> for bool {
     }
 }
 
+#[derive(Clone)]
 pub struct ChipScreen([PixelState; NUM_PIXELS]);
 
 impl ChipScreen {
     /// Loads a sprite into the screen buffer.
     ///
     /// This function renders a sprite into the screen buffer with its upper left pixel at the
-    /// specified location. Sprites are rendered over the existing screen buffer using XOR.
+    /// specified location. Sprites are normally rendered over the existing screen buffer using XOR,
+    /// but [DrawMode::Overwrite] instead only turns pixels on.
     /// Each byte in sprite_data represents one 8-pixel-wide row, up to a max of 15 rows.
     /// Sprites are always 8 pixels wide.
     ///
     /// See [here](https://github.com/mattmikolay/chip-8/wiki/CHIP%E2%80%908-Technical-Reference)
     /// for more information.
     ///
-    /// This function returns true if any set pixels are changed to unset.
-    fn render_sprite(&mut self, sprite_data: &[u8], x_pos: u8, y_pos: u8) -> bool {
+    /// This function returns true if any set pixels are changed to unset, which is never the case
+    /// in overwrite mode.
+    pub(super) fn render_sprite(
+        &mut self,
+        sprite_data: &[u8],
+        x_pos: u8,
+        y_pos: u8,
+        mode: DrawMode,
+    ) -> bool {
         let n_bytes = sprite_data.len();
-        assert!(n_bytes <= 15, "invalid sprite size: {}", n_bytes);
+        debug_assert!(n_bytes <= 15, "invalid sprite size: {}", n_bytes);
 
         // Ensure top left coordinate will wrap modulo screen dimensions:
         let x_pos = x_pos as usize % SCREEN_WIDTH;
@@ -461,11 +820,65 @@ impl ChipScreen {
         {
             for col_num in 0..cols_used {
                 let index = (y_pos + row_num) * SCREEN_WIDTH + x_pos + col_num;
-                flag |= self[index].xor_mut_and_did_unset(row_bits[col_num].into());
+                match mode {
+                    DrawMode::Xor => {
+                        flag |= self[index].xor_mut_and_did_unset(row_bits[col_num].into())
+                    }
+                    DrawMode::Overwrite if row_bits[col_num] => self[index] = PixelState::White,
+                    DrawMode::Overwrite => (),
+                }
             }
         }
         flag
     }
+
+    /// Returns whether any lit pixels of a sprite drawn at the given position fall off the right or
+    /// bottom edge of the screen, and so are clipped rather than wrapped by [render_sprite].
+    ///
+    /// [render_sprite]: Self::render_sprite
+    fn sprite_clipped(sprite_data: &[u8], x_pos: u8, y_pos: u8) -> bool {
+        let x_pos = x_pos as usize % SCREEN_WIDTH;
+        let y_pos = y_pos as usize % SCREEN_HEIGHT;
+
+        let cols_used = cmp::min(SCREEN_WIDTH - x_pos, 8);
+        let rows_used = cmp::min(SCREEN_HEIGHT - y_pos, sprite_data.len());
+        // Bits of each row which are drawn, with the leftmost pixel in the most significant bit
+        let visible_cols = !(0xFF_u16 >> cols_used) as u8;
+
+        let (visible, clipped) = sprite_data.split_at(rows_used);
+        visible.iter().any(|&row| row & !visible_cols != 0) || clipped.iter().any(|&row| row != 0)
+    }
+
+    /// Returns a copy of the screen with the address and opcode of the instruction that paused
+    /// execution written across the top in hex digits.
+    pub fn with_halt_overlay(&self, halt: Halt) -> ChipScreen {
+        const GLYPH_SPACING: u8 = 5;
+
+        let mut screen = self.clone();
+        screen[..SCREEN_WIDTH * 7].fill(PixelState::Black);
+
+        let pc_digits = (0..3).rev().map(|n| (halt.pc >> (4 * n)) & 0xF);
+        let opcode_digits = (0..4)
+            .rev()
+            .map(|n| (halt.opcode as usize >> (4 * n)) & 0xF);
+        let digit_positions = (0..3).chain(4..8).map(|n| 1 + n * GLYPH_SPACING);
+
+        for (digit, x_pos) in pc_digits.chain(opcode_digits).zip(digit_positions) {
+            screen.render_sprite(&FONT_DATA[digit], x_pos, 1, DrawMode::Xor);
+        }
+        screen
+    }
+
+    /// Converts the screen into an RGB565 framebuffer.
+    ///
+    /// Each pixel's value (0 for unlit, 1 for lit) is mapped to its color through the `palette`.
+    pub fn to_rgb565(&self, palette: &Palette) -> [u16; NUM_PIXELS] {
+        let mut frame = [0; NUM_PIXELS];
+        for (out, &pixel) in frame.iter_mut().zip(self.iter()) {
+            *out = palette.color(bool::from(pixel) as usize);
+        }
+        frame
+    }
 }
 
 impl Default for ChipScreen {
@@ -488,14 +901,7 @@ impl DerefMut for ChipScreen {
     }
 }
 
-impl AsRef<[u16; NUM_PIXELS]> for ChipScreen {
-    fn as_ref(&self) -> &[u16; NUM_PIXELS] {
-        static_assertions::assert_eq_size!(PixelState, u16);
-        unsafe { &*(&self.0 as *const [PixelState; NUM_PIXELS] as *const [u16; NUM_PIXELS]) }
-    }
-}
-
-pub fn _with<F, R>(func: F) -> R
+pub fn with<F, R>(func: F) -> R
 where
     F: FnOnce(&ChipState) -> R,
 {
@@ -520,18 +926,32 @@ where
 pub fn init() {
     tracing::info!("initializing core state");
     let mut state = Box::new(ChipState::new());
+    load_font(&mut state.mem);
 
+    // Put the new state into the global variable
+    let mut guard = CHIP_STATE.lock();
+    *guard = Some(state);
+}
+
+/// Copies the hex font data into Chip-8 memory.
+fn load_font(mem: &mut ChipMem) {
     // Make sure hex font data won't overlap with where the game will be loaded
     const FONT_SIZE: usize = mem::size_of::<FontStore>();
     static_assertions::const_assert!(FONT_ADDRESS + FONT_SIZE <= GAME_ADDRESS);
 
-    // Copy hex font data into Chip-8 memory
     let font_bytes: Vec<u8> = FONT_DATA.iter().flatten().copied().collect();
-    state.mem[FONT_ADDRESS..FONT_ADDRESS + FONT_SIZE].copy_from_slice(font_bytes.as_slice());
-
-    // Put the new state into the global variable
-    let mut guard = CHIP_STATE.lock();
-    *guard = Some(state);
+    mem[FONT_ADDRESS..FONT_ADDRESS + FONT_SIZE].copy_from_slice(font_bytes.as_slice());
+    debug_assert_eq!(
+        &mem[FONT_ADDRESS..FONT_ADDRESS + FONT_SIZE],
+        font_bytes.as_slice(),
+        "font data was not loaded correctly",
+    );
+    tracing::debug!(
+        "loaded font data at {:#05x}..{:#05x}: {:02x?}",
+        FONT_ADDRESS,
+        FONT_ADDRESS + FONT_SIZE,
+        &mem[FONT_ADDRESS..FONT_ADDRESS + FONT_SIZE],
+    );
 }
 
 pub fn deinit() {
@@ -540,15 +960,905 @@ pub fn deinit() {
     *guard = None;
 }
 
+/// Checks that the bitvec-based instruction field extraction used by [ChipState::tick] agrees with
+/// plain integer math on the raw opcode. Only compiled into debug builds.
+#[cfg(debug_assertions)]
+fn cross_check_decode(instr_bits: &BitSlice<u8, Msb0>) {
+    let opcode = u16::from_be_bytes([instr_bits[..8].load_be(), instr_bits[8..].load_be()]);
+    let (prefix, stem) = instr_bits.split_at(4);
+    let (x, y, n) = stem.split_at_two(4, 8);
+    let (_, kk) = stem.split_at(4);
+
+    debug_assert_eq!(prefix.load::<u8>(), (opcode >> 12) as u8);
+    debug_assert_eq!(stem.load_be::<u16>(), opcode & 0x0FFF);
+    debug_assert_eq!(x.load_be::<usize>(), ((opcode >> 8) & 0xF) as usize);
+    debug_assert_eq!(y.load_be::<usize>(), ((opcode >> 4) & 0xF) as usize);
+    debug_assert_eq!(n.load_be::<u8>(), (opcode & 0xF) as u8);
+    debug_assert_eq!(kk.load_be::<u8>(), (opcode & 0xFF) as u8);
+}
+
 /// Log an invalid instruction and then shutdown the frontend.
 ///
 /// Note: this function must never return!
-fn invalid_instruction_shutdown<T>(instr_bits: &T) -> !
-where
-    T: ?Sized + bitvec::field::BitField,
-{
-    cb::env_shutdown(format!(
-        "invalid instruction {:x?}",
-        instr_bits.load_be::<u16>()
-    ));
+fn invalid_instruction_shutdown(opcode: u16) -> ! {
+    cb::env_shutdown(format!("invalid instruction {:x?}", opcode));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        env,
+        process::Command,
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    /// Patterns of the implemented instruction families, where lowercase letters match any digit.
+    /// 0nnn is left out, since it's ignored rather than implemented.
+    const OPCODE_FAMILIES: [&str; 34] = [
+        "00E0", "00EE", "1nnn", "2nnn", "3xkk", "4xkk", "5xy0", "6xkk", "7xkk", "8xy0", "8xy1",
+        "8xy2", "8xy3", "8xy4", "8xy5", "8xy6", "8xy7", "8xyE", "9xy0", "Annn", "Bnnn", "Cxkk",
+        "Dxyn", "Ex9E", "ExA1", "Fx07", "Fx0A", "Fx15", "Fx18", "Fx1E", "Fx29", "Fx33", "Fx55",
+        "Fx65",
+    ];
+
+    /// Bits indexed like [OPCODE_FAMILIES] of the families executed so far in this process
+    static EXECUTED_FAMILIES: AtomicU64 = AtomicU64::new(0);
+
+    /// Set in the child process of [every_implemented_opcode_family_is_executed]
+    const COVERAGE_RUN: &str = "TRUSTYCHIP_OPCODE_COVERAGE";
+
+    /// Printed before each newly executed family in a coverage run
+    const COVERAGE_PREFIX: &str = "opcode family executed: ";
+
+    /// Records that the instruction `opcode` has been executed.
+    pub(super) fn record_opcode_family(opcode: u16) {
+        let digits = format!("{opcode:04X}");
+        let Some(family) = OPCODE_FAMILIES.iter().position(|pattern| {
+            pattern
+                .chars()
+                .zip(digits.chars())
+                .all(|(p, digit)| p.is_ascii_lowercase() || p == digit)
+        }) else {
+            return;
+        };
+
+        let executed = EXECUTED_FAMILIES.fetch_or(1 << family, Ordering::Relaxed);
+        if executed & 1 << family == 0 && env::var_os(COVERAGE_RUN).is_some() {
+            println!("{COVERAGE_PREFIX}{}", OPCODE_FAMILIES[family]);
+        }
+    }
+
+    /// Returns a freshly loaded machine with `program` at the game address.
+    fn machine(program: &[u16]) -> ChipState {
+        let mut state = ChipState::new();
+        load_font(&mut state.mem);
+        state.rom = program
+            .iter()
+            .flat_map(|opcode| opcode.to_be_bytes())
+            .collect();
+        state.mem[GAME_ADDRESS..GAME_ADDRESS + state.rom.len()].copy_from_slice(&state.rom);
+        state
+    }
+
+    /// Returns the key states with the keys in the `keys` bitmask held down.
+    fn keys(keys: u16) -> BitVec {
+        (0..16).map(|key| keys >> key & 1 != 0).collect()
+    }
+
+    /// Executes `count` instructions with no keys held down.
+    fn run(state: &mut ChipState, count: usize, opts: &Options) {
+        for _ in 0..count {
+            state.tick(&keys(0), opts);
+        }
+    }
+
+    #[test]
+    fn skips_logic_and_control_flow() {
+        let opts = Options::default();
+        // (program, instructions to run, V0 and pc afterwards)
+        let cases = [
+            (vec![0x6005, 0x3005], 2, (0x05, 0x206)), // Equal, skipped
+            (vec![0x6005, 0x3006], 2, (0x05, 0x204)), // Not equal, not skipped
+            (vec![0x6005, 0x4006], 2, (0x05, 0x206)), // Not equal, skipped
+            (vec![0x6005, 0x6105, 0x5010], 3, (0x05, 0x208)), // Registers equal, skipped
+            (vec![0x6005, 0x6106, 0x9010], 3, (0x05, 0x208)), // Registers differ, skipped
+            (vec![0x6105, 0x8010], 2, (0x05, 0x204)), // Load
+            (vec![0x600C, 0x610A, 0x8011], 3, (0x0E, 0x206)), // OR
+            (vec![0x600C, 0x610A, 0x8012], 3, (0x08, 0x206)), // AND
+            (vec![0x600C, 0x610A, 0x8013], 3, (0x06, 0x206)), // XOR
+            (vec![0x6107, 0xF115, 0xF007], 3, (0x07, 0x206)), // Delay timer
+            (vec![0x2204, 0x0000, 0x00EE], 2, (0x00, 0x202)), // Call and return
+        ];
+
+        for (program, count, expected) in cases {
+            let mut state = machine(&program);
+            run(&mut state, count, &opts);
+            assert_eq!((state.v[0], state.pc), expected, "{program:04X?}");
+        }
+    }
+
+    #[test]
+    fn clear_blanks_the_screen() {
+        let mut state = machine(&[0x00E0]);
+        state.screen[0] = PixelState::White;
+        run(&mut state, 1, &Options::default());
+        assert!(state.screen.iter().all(|&pixel| pixel == PixelState::Black));
+        assert!(state.has_drawn);
+    }
+
+    #[test]
+    fn arithmetic_flag_wins_when_vx_is_vf() {
+        let opts = Options::default();
+        // (opcode, VF, VE, expected VF)
+        let cases = [
+            (0x8FE4, 0xFF, 0x01, 1), // Carry, though the sum is 0
+            (0x8FE4, 0x01, 0x01, 0), // No carry, though the sum is 2
+            (0x8FE5, 0x01, 0x02, 0), // Borrow
+            (0x8FE5, 0x05, 0x02, 1), // No borrow, though the difference is 3
+            (0x8FE7, 0x03, 0x02, 0), // Borrow
+            (0x8FE7, 0x02, 0x05, 1), // No borrow, though the difference is 3
+        ];
+
+        for (opcode, vf, ve, expected) in cases {
+            let mut state = machine(&[opcode]);
+            state.v[0xF] = vf;
+            state.v[0xE] = ve;
+            run(&mut state, 1, &opts);
+            assert_eq!(state.v[0xF], expected, "{opcode:04x} with VF={vf} VE={ve}");
+        }
+    }
+
+    #[test]
+    fn shift_flag_wins_when_vx_is_vf() {
+        // (opcode, VF, VE, expected VF without and with the shift quirk)
+        let cases = [
+            (0x8FF6, 0x03, 0x00, [1, 1]), // Result 1
+            (0x8FF6, 0x02, 0x00, [0, 0]), // Result 1
+            (0x8FE6, 0x02, 0x03, [1, 0]), // Results 1 and 1
+            (0x8FFE, 0x81, 0x00, [1, 1]), // Result 2
+            (0x8FFE, 0x41, 0x00, [0, 0]), // Result 0x82
+            (0x8FEE, 0x01, 0x80, [1, 0]), // Results 0 and 2
+        ];
+
+        for (opcode, vf, ve, expected) in cases {
+            for (shift_quirk, expected) in [false, true].into_iter().zip(expected) {
+                let opts = Options {
+                    shift_quirk,
+                    ..Options::default()
+                };
+                let mut state = machine(&[opcode]);
+                state.v[0xF] = vf;
+                state.v[0xE] = ve;
+                run(&mut state, 1, &opts);
+                assert_eq!(
+                    state.v[0xF], expected,
+                    "{opcode:04x} with VF={vf:#04x} VE={ve:#04x} shift_quirk={shift_quirk}",
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_instruction_pauses_execution() {
+        let opts = Options {
+            invalid_instruction: InvalidInstructionAction::Pause,
+            ..Options::default()
+        };
+        let mut state = machine(&[0x6001, 0x800F, 0x6002]);
+        run(&mut state, 10, &opts);
+
+        let halt = state.halt.expect("execution should be halted");
+        assert_eq!((halt.pc, halt.opcode), (GAME_ADDRESS + 2, 0x800F));
+        assert_eq!(state.pc, GAME_ADDRESS + 2);
+        assert_eq!(state.v[0], 1);
+
+        state.reset(true);
+        assert!(state.halt.is_none());
+        run(&mut state, 1, &opts);
+        assert_eq!(state.pc, GAME_ADDRESS + 2);
+    }
+
+    /// Runs a program with an invalid instruction between two valid ones, with the error beep
+    /// enabled.
+    fn run_invalid_instruction(action: InvalidInstructionAction) -> ChipState {
+        let opts = Options {
+            invalid_instruction: action,
+            error_beep: true,
+            ..Options::default()
+        };
+        let mut state = machine(&[0x6001, 0x801F, 0x6102]);
+        run(&mut state, 3, &opts);
+        state
+    }
+
+    #[test]
+    fn accesses_past_the_end_of_memory_are_invalid_instructions() {
+        let opts = Options {
+            invalid_instruction: InvalidInstructionAction::Pause,
+            ..Options::default()
+        };
+        // (program, initial I, address and opcode of the invalid instruction)
+        let cases = [
+            (vec![0x1FFF], 0, (0xFFF, 0x0000)), // Fetch of the last byte
+            (vec![0x60FF, 0xBFFF], 0, (0x10FE, 0x0000)), // Jump past the end
+            (vec![0xAFFE, 0xF033], 0, (0x202, 0xF033)), // BCD
+            (vec![0xAFF8, 0xFF55], 0, (0x202, 0xFF55)), // Store
+            (vec![0xAFF8, 0xFF65], 0, (0x202, 0xFF65)), // Load
+            (vec![0x60FF, 0xF01E], 0xFF80, (0x202, 0xF01E)), // I overflow
+            (vec![0xAFFC, 0xD005], 0, (0x202, 0xD005)), // Sprite
+        ];
+        for (program, i, (pc, opcode)) in cases {
+            let mut state = machine(&program);
+            state.i = i;
+            run(&mut state, 3, &opts);
+            let halt = state.halt.expect("execution should be halted");
+            assert_eq!((halt.pc, halt.opcode), (pc, opcode), "{program:04x?}");
+            assert!(state.mem[0xFF0..].iter().all(|&byte| byte == 0));
+        }
+
+        // Accesses which just fit are fine
+        let mut state = machine(&[0xAFF0, 0xFF55, 0xAFFD, 0xFF33]);
+        run(&mut state, 4, &opts);
+        assert!(state.halt.is_none());
+
+        // Skipping a bad fetch wraps around to the start of memory
+        let mut state = machine(&[0x1FFF]);
+        let opts = Options {
+            invalid_instruction: InvalidInstructionAction::Skip,
+            ..Options::default()
+        };
+        run(&mut state, 2, &opts);
+        assert_eq!(state.pc, 0x001);
+    }
+
+    /// Measures how long the interpreter takes per instruction, for comparing changes to the hot
+    /// path. Run it in release mode.
+    #[test]
+    #[ignore = "benchmark: cargo test --release -- --ignored --nocapture instruction_throughput"]
+    fn instruction_throughput() {
+        const INSTRUCTIONS: u32 = 10_000_000;
+
+        let opts = Options::default();
+        let input = keys(0);
+        // Arithmetic, a draw and register stores and loads in a loop
+        let mut state = machine(&[
+            0xA300, 0x7001, 0x8014, 0x8126, 0xD015, 0xF255, 0xF265, 0x1200,
+        ]);
+        let start = std::time::Instant::now();
+        for _ in 0..INSTRUCTIONS {
+            state.tick(&input, &opts);
+        }
+        let elapsed = start.elapsed();
+
+        assert!(state.halt.is_none());
+        println!(
+            "{:.2} ns/instruction",
+            elapsed.as_nanos() as f64 / INSTRUCTIONS as f64
+        );
+    }
+
+    #[test]
+    fn invalid_instruction_is_skipped_with_a_warning() {
+        let state = run_invalid_instruction(InvalidInstructionAction::Skip);
+        assert!(state.halt.is_none());
+        assert_eq!(state.pc, GAME_ADDRESS + 6);
+        assert_eq!(state.v[..2], [1, 2]);
+        assert!(state.audio.error_beep_active());
+    }
+
+    #[test]
+    fn invalid_instruction_is_silently_a_nop() {
+        let state = run_invalid_instruction(InvalidInstructionAction::Nop);
+        assert!(state.halt.is_none());
+        assert_eq!(state.pc, GAME_ADDRESS + 6);
+        assert_eq!(state.v[..2], [1, 2]);
+        assert!(!state.audio.error_beep_active());
+    }
+
+    #[test]
+    fn invalid_instruction_pauses_with_error_beep() {
+        let state = run_invalid_instruction(InvalidInstructionAction::Pause);
+        let halt = state.halt.expect("execution should be halted");
+        assert_eq!((halt.pc, halt.opcode), (GAME_ADDRESS + 2, 0x801F));
+        assert_eq!(state.v[..2], [1, 0]);
+        assert!(state.audio.error_beep_active());
+    }
+
+    #[test]
+    fn invalid_instruction_shuts_down() {
+        let _guard = cb::mock::install();
+        let result = std::panic::catch_unwind(|| {
+            run_invalid_instruction(InvalidInstructionAction::Shutdown);
+        });
+        assert!(result.is_err());
+        assert!(cb::mock::shutdown_requested());
+    }
+
+    #[test]
+    fn pc_breakpoint_pauses_before_the_instruction() {
+        let opts = Options::default();
+        let mut state = machine(&[0x6001, 0x6102, 0x6203]);
+        state.breakpoints.pc.insert(GAME_ADDRESS + 2);
+
+        run(&mut state, 5, &opts);
+        assert!(state.paused);
+        assert_eq!(state.pc, GAME_ADDRESS + 2);
+        assert_eq!(state.v[..3], [1, 0, 0]);
+
+        state.paused = false;
+        run(&mut state, 1, &opts);
+        assert_eq!(state.v[..3], [1, 2, 0]);
+    }
+
+    #[test]
+    fn watchpoints_pause_after_the_access() {
+        let opts = Options::default();
+        // Store V0-V2 at 0x300, then load them back
+        let program = [0xA300, 0xF255, 0xA300, 0xF265, 0x6001];
+
+        let mut state = machine(&program);
+        state.breakpoints.write.insert(0x302);
+        run(&mut state, 5, &opts);
+        assert!(state.paused);
+        assert_eq!(state.pc, GAME_ADDRESS + 4);
+
+        // Accesses which miss the watched address don't pause
+        let mut state = machine(&program);
+        state.breakpoints.write.insert(0x303);
+        state.breakpoints.read.insert(0x2FF);
+        run(&mut state, 5, &opts);
+        assert!(!state.paused);
+        assert_eq!(state.pc, GAME_ADDRESS + 10);
+
+        let mut state = machine(&program);
+        state.breakpoints.read.insert(0x300);
+        run(&mut state, 5, &opts);
+        assert!(state.paused);
+        assert_eq!(state.pc, GAME_ADDRESS + 8);
+    }
+
+    #[test]
+    fn palette_entries_color_the_output() {
+        let mut screen = ChipScreen::default();
+        screen[1] = PixelState::White;
+        let mut palette = Options::default().palette;
+        palette.0[Palette::BACKGROUND] = 0x1234;
+        palette.0[Palette::FOREGROUND] = 0xABCD;
+
+        let frame = screen.to_rgb565(&palette);
+        assert_eq!(frame[..3], [0x1234, 0xABCD, 0x1234]);
+
+        palette.0[Palette::FOREGROUND] = 0x0F0F;
+        assert_eq!(screen.to_rgb565(&palette)[1], 0x0F0F);
+    }
+
+    #[test]
+    fn drawing_a_sprite_twice_erases_it_and_sets_vf() {
+        let opts = Options::default();
+        // Point I at the digit 0 glyph and draw it twice at (V0, V1)
+        let mut state = machine(&[0xF029, 0xD015, 0xD015]);
+        state.v[1] = 3;
+
+        run(&mut state, 2, &opts);
+        assert_eq!(state.v[0xF], 0);
+        assert_eq!(state.screen[3 * SCREEN_WIDTH], PixelState::White);
+
+        run(&mut state, 1, &opts);
+        assert_eq!(state.v[0xF], 1);
+        assert!(state.screen.iter().all(|&pixel| pixel == PixelState::Black));
+    }
+
+    #[test]
+    fn sprites_collide_within_the_screen_and_clip_at_its_edges() {
+        let sprite = [0xFF; 4];
+        // (x, y, lit pixels, first lit pixel)
+        let cases = [
+            (0, 0, 32, 0),
+            (56, 0, 32, 56),
+            (60, 0, 16, 60),
+            (0, 30, 16, 30 * SCREEN_WIDTH),
+            (63, 31, 1, NUM_PIXELS - 1),
+            (64, 32, 32, 0),
+            (130, 70, 32, 6 * SCREEN_WIDTH + 2),
+        ];
+
+        for (x, y, lit, first) in cases {
+            for mode in [DrawMode::Xor, DrawMode::Overwrite] {
+                let mut screen = ChipScreen::default();
+                assert!(!screen.render_sprite(&sprite, x, y, mode));
+                let lit_pixels: Vec<_> = (0..NUM_PIXELS)
+                    .filter(|&i| screen[i] == PixelState::White)
+                    .collect();
+                assert_eq!(lit_pixels.len(), lit, "({x}, {y}) {mode:?}");
+                assert_eq!(lit_pixels[0], first, "({x}, {y}) {mode:?}");
+
+                let collided = screen.render_sprite(&sprite, x, y, mode);
+                let lit_after = screen.iter().filter(|&&p| p == PixelState::White).count();
+                match mode {
+                    DrawMode::Xor => assert_eq!((collided, lit_after), (true, 0), "({x}, {y})"),
+                    DrawMode::Overwrite => {
+                        assert_eq!((collided, lit_after), (false, lit), "({x}, {y})")
+                    }
+                }
+            }
+        }
+
+        // Clipped pixels don't wrap around, so they can't collide with the opposite edge
+        let mut screen = ChipScreen::default();
+        screen[60] = PixelState::White;
+        screen[30 * SCREEN_WIDTH] = PixelState::White;
+        assert!(!screen.render_sprite(&sprite, 60, 30, DrawMode::Xor));
+        assert_eq!(screen[60], PixelState::White);
+        assert_eq!(screen[30 * SCREEN_WIDTH], PixelState::White);
+        assert!(screen.render_sprite(&[0x80], 0, 30, DrawMode::Xor));
+    }
+
+    #[test]
+    fn key_wait_ends_when_a_pressed_key_is_released() {
+        let opts = Options::default();
+        let mut state = machine(&[0xF50A, 0xF60A]);
+        state.v[5] = 0xFF;
+
+        // (keys held, expected pc)
+        let steps = [
+            (0, GAME_ADDRESS),
+            (0, GAME_ADDRESS),
+            (1 << 7, GAME_ADDRESS),
+            (1 << 7, GAME_ADDRESS),
+            (0, GAME_ADDRESS + 2),
+        ];
+        for (step, (held, pc)) in steps.into_iter().enumerate() {
+            state.tick(&keys(held), &opts);
+            assert_eq!(state.pc, pc, "step {step}");
+        }
+        assert_eq!(state.v[5], 7);
+
+        // Keys held when the wait begins count too, and the released one is stored even if others
+        // are still held
+        state.tick(&keys(1 << 3 | 1 << 9), &opts);
+        state.tick(&keys(1 << 3), &opts);
+        assert_eq!(state.pc, GAME_ADDRESS + 4);
+        assert_eq!(state.v[6], 9);
+    }
+
+    #[test]
+    fn key_wait_blocks_until_key_a_is_pressed() {
+        let opts = Options::default();
+        let mut state = machine(&[0xF30A, 0x6101]);
+
+        // Nothing executes past the wait, however many ticks go by without a key
+        run(&mut state, 8, &opts);
+        assert_eq!((state.pc, state.v[3], state.v[1]), (GAME_ADDRESS, 0, 0));
+
+        state.tick(&keys(1 << 0xA), &opts);
+        assert_eq!(state.pc, GAME_ADDRESS);
+        state.tick(&keys(0), &opts);
+        assert_eq!((state.pc, state.v[3]), (GAME_ADDRESS + 2, 0xA));
+
+        state.tick(&keys(0), &opts);
+        assert_eq!(state.v[1], 1);
+    }
+
+    #[test]
+    fn random_byte_is_drawn_even_with_an_empty_mask() {
+        let opts = Options::default();
+        let seed = 0xC8;
+        let mut expected = StdRng::seed_from_u64(seed);
+        let bytes: [u8; 3] = [expected.gen(), expected.gen(), expected.gen()];
+        assert_ne!(bytes[0], bytes[1]);
+
+        RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+        let mut state = machine(&[0xC0FF, 0xC100, 0xC2FF]);
+        state.v[1] = 0xAA;
+        run(&mut state, 3, &opts);
+        assert_eq!(state.v[..3], [bytes[0], 0, bytes[2]]);
+    }
+
+    #[test]
+    fn random_programs_do_not_panic() {
+        for seed in [0xC8, 0x8C, 0xF00D] {
+            for action in [
+                InvalidInstructionAction::Skip,
+                InvalidInstructionAction::Nop,
+            ] {
+                let opts = Options {
+                    invalid_instruction: action,
+                    ..Options::default()
+                };
+                let mut input = StdRng::seed_from_u64(seed);
+                let program: Vec<u16> = (GAME_ADDRESS..TOTAL_MEMORY)
+                    .step_by(2)
+                    .map(|_| input.gen())
+                    .collect();
+                let mut state = machine(&program);
+                RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+
+                for _ in 0..5000 {
+                    // Returning with an empty stack deliberately shuts down, so the run ends there
+                    let returning = state.pc <= TOTAL_MEMORY - 2
+                        && state.mem[state.pc..state.pc + 2] == [0x00, 0xEE];
+                    if returning && state.stack.is_empty() {
+                        break;
+                    }
+                    state.tick(&keys(input.gen()), &opts);
+                }
+            }
+        }
+    }
+
+    /// Reads back `rows` rows of an 8 pixel wide sprite drawn on the screen at (`x`, `y`).
+    fn sprite_at(state: &ChipState, x: usize, y: usize, rows: usize) -> Vec<u8> {
+        state
+            .screen
+            .chunks_exact(SCREEN_WIDTH)
+            .skip(y)
+            .take(rows)
+            .map(|row| (x..x + 8).fold(0, |bits, x| bits << 1 | bool::from(row[x]) as u8))
+            .collect()
+    }
+
+    #[test]
+    fn wrap_diag_counts_clipped_sprite_draws() {
+        let program = [
+            0x603C, 0x6100, 0xF129, // V0 = 60, V1 = 0, I = "0" glyph, which is 4 pixels wide
+            0xD015, // Fits against the right edge
+            0x603E, 0xD015, // Clipped by the right edge
+            0x6000, 0x611E, 0xD015, // Clipped by the bottom edge
+        ];
+        let draws = |wrap_diag| {
+            let opts = Options {
+                wrap_diag,
+                ..Options::default()
+            };
+            let mut state = machine(&program);
+            run(&mut state, program.len(), &opts);
+            (state.sprite_draws, state.clipped_sprite_draws)
+        };
+
+        assert_eq!(draws(true), (3, 2));
+        assert_eq!(draws(false), (0, 0));
+    }
+
+    #[test]
+    fn register_store_and_load_cover_v0_to_vx() {
+        let opts = Options::default();
+        for x in [0, 7, 15] {
+            // Fx55 copies V0..=Vx to I..=I+x and leaves I just past them
+            let mut state = machine(&[0xA300, 0xF055 | (x as u16) << 8]);
+            state.v = std::array::from_fn(|n| 0x10 + n as u8);
+            run(&mut state, 2, &opts);
+            assert_eq!(&state.mem[0x300..=0x300 + x], &state.v[..=x], "x = {x}");
+            assert!(state.mem[0x300 + x + 1..0x310]
+                .iter()
+                .all(|&byte| byte == 0));
+            assert_eq!(state.i as usize, 0x300 + x + 1, "x = {x}");
+
+            // Fx65 fills V0..=Vx from I..=I+x, leaving the other registers alone
+            let mut state = machine(&[0xA300, 0xF065 | (x as u16) << 8]);
+            for (n, byte) in state.mem[0x300..0x310].iter_mut().enumerate() {
+                *byte = 0x80 + n as u8;
+            }
+            run(&mut state, 2, &opts);
+            assert_eq!(&state.v[..=x], &state.mem[0x300..=0x300 + x], "x = {x}");
+            assert!(state.v[x + 1..].iter().all(|&v| v == 0));
+            assert_eq!(state.i as usize, 0x300 + x + 1, "x = {x}");
+        }
+    }
+
+    #[test]
+    fn key_skips_advance_pc_by_pressed_state() {
+        let opts = Options::default();
+        // (opcode, value in V0, keys held, pc advance)
+        let cases = [
+            (0xE09E, 0x5, 1 << 0x5, 4),
+            (0xE09E, 0x5, 1 << 0x6, 2),
+            (0xE09E, 0x5, 0, 2),
+            (0xE09E, 0x15, 1 << 0x5, 4), // Only the low nibble selects the key
+            (0xE0A1, 0x5, 1 << 0x5, 2),
+            (0xE0A1, 0x5, 1 << 0x6, 4),
+            (0xE0A1, 0x5, 0, 4),
+            (0xE0A1, 0xFF, 1 << 0xF, 2),
+        ];
+        for (opcode, value, held, advance) in cases {
+            let mut state = machine(&[0x6000 | value, opcode]);
+            state.tick(&keys(held), &opts);
+            state.tick(&keys(held), &opts);
+            assert_eq!(
+                state.pc,
+                GAME_ADDRESS + 2 + advance,
+                "{opcode:04x} with V0 = {value:#x} and keys {held:#06x}",
+            );
+        }
+    }
+
+    #[test]
+    fn font_glyphs_are_drawn_for_each_digit() {
+        let opts = Options::default();
+        // Draw the glyph for the digit in V0 at (V1, V2)
+        for digit in [0x0, 0x1, 0x7, 0x8, 0xA, 0xF, 0x1C] {
+            let mut state = machine(&[0xF029, 0xD125]);
+            state.v[0] = digit;
+            state.v[1] = 10;
+            state.v[2] = 4;
+            run(&mut state, 2, &opts);
+
+            let glyph = FONT_DATA[digit as usize % 16];
+            assert_eq!(state.i as usize, FONT_ADDRESS + 5 * (digit as usize % 16));
+            assert_eq!(sprite_at(&state, 10, 4, 5), glyph, "digit {digit:#x}");
+            let lit = state
+                .screen
+                .iter()
+                .filter(|&&p| p == PixelState::White)
+                .count();
+            let glyph_lit: u32 = glyph.iter().map(|row| row.count_ones()).sum();
+            assert_eq!(lit, glyph_lit as usize, "digit {digit:#x}");
+        }
+    }
+
+    #[test]
+    fn overwrite_mode_only_turns_pixels_on() {
+        // Draw the digit 0 glyph, then the digit 1 glyph over it at (V0, V0)
+        let program = [0xF029, 0xD005, 0x6101, 0xF129, 0xD005];
+
+        let mut state = machine(&program);
+        run(&mut state, 5, &Options::default());
+        let xor: Vec<u8> = (0..5)
+            .map(|row| FONT_DATA[0][row] ^ FONT_DATA[1][row])
+            .collect();
+        assert_eq!(sprite_at(&state, 0, 0, 5), xor);
+        assert_eq!(state.v[0xF], 1);
+
+        let opts = Options {
+            draw_mode: DrawMode::Overwrite,
+            ..Options::default()
+        };
+        let mut state = machine(&program);
+        state.v[0xF] = 1;
+        run(&mut state, 5, &opts);
+        let or: Vec<u8> = (0..5)
+            .map(|row| FONT_DATA[0][row] | FONT_DATA[1][row])
+            .collect();
+        assert_eq!(sprite_at(&state, 0, 0, 5), or);
+        assert_eq!(state.v[0xF], 0);
+    }
+
+    #[test]
+    fn held_key_registers_once_per_skip_interval() {
+        let opts = Options {
+            key_skip_interval: Some(4),
+            ..Options::default()
+        };
+        let mut state = machine(&[]);
+        let held = keys(1 << 5);
+
+        // Checked twice per timer cycle while held, then released and pressed again
+        let mut pressed = Vec::new();
+        for cycle in 0..10 {
+            let input = match cycle {
+                6 => keys(0),
+                _ => held.clone(),
+            };
+            pressed.push(state.key_pressed_for_skip(5, &input, &opts));
+            pressed.push(state.key_pressed_for_skip(5, &input, &opts));
+            state.decrement_timers();
+        }
+        let expected = [
+            true, false, false, false, false, false, false, false, true, false, false, false,
+            false, false, true, false, false, false, false, false,
+        ];
+        assert_eq!(pressed, expected);
+
+        // Other keys have their own interval
+        assert!(state.key_pressed_for_skip(6, &keys(1 << 6), &opts));
+
+        // Without an interval, a held key is always pressed
+        let opts = Options::default();
+        assert!((0..4).all(|_| state.key_pressed_for_skip(5, &held, &opts)));
+    }
+
+    #[test]
+    fn skip_interval_applies_to_ex9e_and_exa1() {
+        let opts = Options {
+            key_skip_interval: Some(2),
+            ..Options::default()
+        };
+        // Skip if key V0 is pressed, then skip if it's not pressed
+        let mut state = machine(&[0xE09E, 0x0000, 0xE0A1]);
+        let held = keys(1);
+        state.tick(&held, &opts);
+        assert_eq!(state.pc, GAME_ADDRESS + 4);
+        // Still within the interval, so ExA1 sees the held key as not pressed
+        state.tick(&held, &opts);
+        assert_eq!(state.pc, GAME_ADDRESS + 8);
+    }
+
+    #[test]
+    fn font_is_loaded_at_its_address() {
+        let font: Vec<u8> = FONT_DATA.iter().flatten().copied().collect();
+        assert_eq!(font.len(), 16 * 5);
+
+        let mut state = machine(&[0x00E0]);
+        assert_eq!(state.mem[FONT_ADDRESS..FONT_ADDRESS + font.len()], font);
+
+        // Reset restores it after the game overwrites it
+        state.mem[FONT_ADDRESS..FONT_ADDRESS + font.len()].fill(0xAA);
+        state.reset(true);
+        assert_eq!(state.mem[FONT_ADDRESS..FONT_ADDRESS + font.len()], font);
+    }
+
+    #[test]
+    fn code_written_by_fx33_is_executed() {
+        let opts = Options::default();
+        // Store the BCD of 147 over the second byte of `V1 = 0xFF` and the instruction after it,
+        // then jump there
+        let mut state = machine(&[
+            0x6093, 0xA20B, 0xF033, 0x120A, 0x0000, 0x61FF, 0xFFFF, 0x120E,
+        ]);
+        run(&mut state, 4, &opts);
+        assert_eq!(state.mem[0x20A..0x20E], [0x61, 1, 4, 7]);
+        assert_eq!(state.pc, 0x20A);
+
+        // V1 = 1, then 0407 is an ignored machine code call rather than the invalid FFFF
+        run(&mut state, 3, &opts);
+        assert_eq!(state.v[1], 1);
+        assert_eq!(state.pc, 0x20E);
+        assert!(state.halt.is_none());
+    }
+
+    #[test]
+    fn sprite_overrun_is_clamped_or_wrapped() {
+        // Draw a 15 byte sprite from 8 bytes before the end of memory
+        let program = [0xAFF8, 0xD00F];
+        for (action, expected_rows) in [
+            (SpriteOverrunAction::Clamp, 8),
+            (SpriteOverrunAction::Wrap, 15),
+        ] {
+            let opts = Options {
+                sprite_overrun: action,
+                ..Options::default()
+            };
+            let mut state = machine(&program);
+            state.mem[TOTAL_MEMORY - 8..].fill(0xFF);
+            // Mark the otherwise unused start of memory, which wrapping reads from
+            state.mem[..FONT_ADDRESS].fill(0x81);
+            run(&mut state, 2, &opts);
+
+            assert_eq!(state.pc, GAME_ADDRESS + 4, "{action:?}");
+            let mut expected = vec![0xFF; 8];
+            expected.resize(expected_rows, 0x81);
+            expected.resize(15, 0);
+            assert_eq!(sprite_at(&state, 0, 0, 15), expected, "{action:?}");
+        }
+    }
+
+    /// Returns a program which calls itself recursively.
+    fn recursion() -> ChipState {
+        machine(&[0x2000 | GAME_ADDRESS as u16])
+    }
+
+    #[test]
+    fn stack_holds_exactly_stack_size_levels() {
+        for size in [12, 16] {
+            let opts = Options {
+                stack_size: Some(size),
+                stack_overflow: StackOverflowAction::Ignore,
+                ..Options::default()
+            };
+            let mut state = recursion();
+
+            run(&mut state, size, &opts);
+            assert_eq!(state.stack.len(), size);
+            assert_eq!(state.pc, GAME_ADDRESS);
+
+            // One past the limit, the call is skipped
+            run(&mut state, 1, &opts);
+            assert_eq!(state.stack.len(), size);
+            assert_eq!(state.pc, GAME_ADDRESS + 2);
+        }
+    }
+
+    #[test]
+    fn unlimited_stack_keeps_growing() {
+        let opts = Options {
+            stack_size: None,
+            ..Options::default()
+        };
+        let mut state = recursion();
+        run(&mut state, 100, &opts);
+        assert_eq!(state.stack.len(), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "stack overflow")]
+    fn stack_overflow_shuts_down() {
+        let _guard = cb::mock::install();
+        let opts = Options {
+            stack_size: Some(12),
+            ..Options::default()
+        };
+        let mut state = recursion();
+        run(&mut state, 12, &opts);
+        assert_eq!(state.stack.len(), 12);
+        run(&mut state, 1, &opts);
+    }
+
+    #[test]
+    fn frame_hash_matches_in_sync_and_differs_on_divergence() {
+        let opts = Options::default();
+        // Draws a random digit each loop, moving right unless key 5 is held
+        let program = [0xC0FF, 0xF029, 0x6105, 0xE19E, 0x7201, 0xD235, 0x1200];
+        // Runs two machines with their RNGs seeded with `seeds` for a loop each frame, holding down
+        // the keys in the `frame_keys` bitmasks, and returns their hashes after each frame
+        let frame_hashes = |seeds: [u64; 2], frame_keys: &[[u16; 2]]| {
+            let mut machines = [machine(&program), machine(&program)];
+            let mut rngs = seeds.map(StdRng::seed_from_u64);
+            frame_keys
+                .iter()
+                .map(|held| {
+                    let mut hashes = [0; 2];
+                    for n in 0..2 {
+                        RNG.with(|rng| mem::swap(&mut *rng.borrow_mut(), &mut rngs[n]));
+                        for _ in 0..program.len() {
+                            machines[n].tick(&keys(held[n]), &opts);
+                        }
+                        RNG.with(|rng| mem::swap(&mut *rng.borrow_mut(), &mut rngs[n]));
+                        hashes[n] = machines[n].frame_hash();
+                    }
+                    hashes
+                })
+                .collect::<Vec<_>>()
+        };
+        let key = 1 << 5;
+
+        let in_sync = frame_hashes([1, 1], &[[0, 0], [key, key], [0, 0], [key, key]]);
+        assert!(in_sync.iter().all(|hashes| hashes[0] == hashes[1]));
+
+        let diverged = frame_hashes([1, 1], &[[0, 0], [key, key], [0, key], [0, 0]]);
+        let matching: Vec<bool> = diverged
+            .iter()
+            .map(|hashes| hashes[0] == hashes[1])
+            .collect();
+        assert_eq!(matching, [true, true, false, false]);
+
+        let reseeded = frame_hashes([1, 2], &[[0, 0]]);
+        assert_ne!(reseeded[0][0], reseeded[0][1]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn decoders_agree_on_every_opcode() {
+        for opcode in 0..=u16::MAX {
+            cross_check_decode(opcode.to_be_bytes().view_bits::<Msb0>());
+        }
+    }
+
+    #[test]
+    fn every_implemented_opcode_family_is_executed() {
+        if env::var_os(COVERAGE_RUN).is_some() {
+            return;
+        }
+
+        // The other tests run concurrently with this one, so the bitset isn't complete until
+        // they've all finished. Instead, run them again in a child process, which reports each
+        // family the first time it's executed.
+        let output = Command::new(env::current_exe().unwrap())
+            .args(["--nocapture", "--test-threads=1"])
+            .env(COVERAGE_RUN, "1")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "the coverage run failed");
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let executed: Vec<&str> = stdout
+            .lines()
+            .filter_map(|line| line.split_once(COVERAGE_PREFIX))
+            .map(|(_, family)| family)
+            .collect();
+        let missing: Vec<&str> = OPCODE_FAMILIES
+            .into_iter()
+            .filter(|family| !executed.contains(family))
+            .collect();
+        assert!(missing.is_empty(), "never executed: {missing:?}");
+    }
 }
@@ -0,0 +1,104 @@
+//! A built-in calibration pattern for checking colors, aspect ratio and scaling in the frontend.
+
+use crate::{constants::*, options::Palette};
+
+/// Size of each palette swatch, including a one pixel gap on its right and bottom edges
+const SWATCH_SIZE: usize = 7;
+
+/// Renders the test pattern: a one pixel border around the screen, a checkerboard filling the
+/// left half, and a 4x4 grid of swatches showing every palette entry in the right half.
+pub fn render(palette: &Palette) -> [u16; NUM_PIXELS] {
+    let background = palette.color(Palette::BACKGROUND);
+    let foreground = palette.color(Palette::FOREGROUND);
+    let swatches_x = SCREEN_WIDTH / 2 + 1;
+    let swatches_y = 2;
+
+    let mut frame = [background; NUM_PIXELS];
+    for (index, pixel) in frame.iter_mut().enumerate() {
+        let (x, y) = (index % SCREEN_WIDTH, index / SCREEN_WIDTH);
+
+        let on_border = x == 0 || y == 0 || x == SCREEN_WIDTH - 1 || y == SCREEN_HEIGHT - 1;
+        let in_checkerboard = (2..SCREEN_WIDTH / 2 - 1).contains(&x)
+            && (2..SCREEN_HEIGHT - 2).contains(&y)
+            && (x + y) % 2 == 0;
+
+        if on_border || in_checkerboard {
+            *pixel = foreground;
+        } else if let (Some(col), Some(row)) =
+            (x.checked_sub(swatches_x), y.checked_sub(swatches_y))
+        {
+            let (col, row) = (col / SWATCH_SIZE, row / SWATCH_SIZE);
+            let in_gap = (x - swatches_x) % SWATCH_SIZE == SWATCH_SIZE - 1
+                || (y - swatches_y) % SWATCH_SIZE == SWATCH_SIZE - 1;
+            if col < 4 && row < 4 && !in_gap {
+                *pixel = palette.color(row * 4 + col);
+            }
+        }
+    }
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern_has_border_checkerboard_and_swatches() {
+        let mut palette = Palette([0; 16]);
+        for (index, color) in palette.0.iter_mut().enumerate() {
+            *color = 0x1000 + index as u16;
+        }
+        let background = palette.color(Palette::BACKGROUND);
+        let foreground = palette.color(Palette::FOREGROUND);
+        let frame = render(&palette);
+        let pixel = |x: usize, y: usize| frame[y * SCREEN_WIDTH + x];
+
+        for x in 0..SCREEN_WIDTH {
+            assert_eq!(pixel(x, 0), foreground, "top border at x={x}");
+            assert_eq!(
+                pixel(x, SCREEN_HEIGHT - 1),
+                foreground,
+                "bottom border at x={x}"
+            );
+        }
+        for y in 0..SCREEN_HEIGHT {
+            assert_eq!(pixel(0, y), foreground, "left border at y={y}");
+            assert_eq!(
+                pixel(SCREEN_WIDTH - 1, y),
+                foreground,
+                "right border at y={y}"
+            );
+        }
+        // The border is separated from what's inside it by a one pixel gap
+        assert_eq!(pixel(1, 1), background);
+        assert_eq!(pixel(SCREEN_WIDTH - 2, SCREEN_HEIGHT - 2), background);
+
+        for y in 2..SCREEN_HEIGHT - 2 {
+            for x in 2..SCREEN_WIDTH / 2 - 1 {
+                let expected = match (x + y) % 2 {
+                    0 => foreground,
+                    _ => background,
+                };
+                assert_eq!(pixel(x, y), expected, "checkerboard at ({x}, {y})");
+            }
+        }
+
+        let swatches_x = SCREEN_WIDTH / 2 + 1;
+        for index in 0..16 {
+            let (left, top) = (
+                swatches_x + index % 4 * SWATCH_SIZE,
+                2 + index / 4 * SWATCH_SIZE,
+            );
+            for y in top..top + SWATCH_SIZE {
+                for x in left..left + SWATCH_SIZE {
+                    let in_gap = x == left + SWATCH_SIZE - 1 || y == top + SWATCH_SIZE - 1;
+                    let expected = match in_gap {
+                        true => background,
+                        false => palette.color(index),
+                    };
+                    assert_eq!(pixel(x, y), expected, "swatch {index} at ({x}, {y})");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::time::{Duration, Instant};
+
+use crate::constants::*;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+/// Counts timer cycles by elapsed wall-clock time, so that the delay and sound timers can count
+/// down at 60 Hz regardless of how evenly the frontend paces frames.
+#[derive(Default)]
+pub struct TimerClock {
+    last: Option<Instant>,
+    /// Time elapsed but not yet counted as a whole cycle, in units of 1/TIMER_CYCLE_RATE ns
+    remainder: u128,
+}
+
+impl TimerClock {
+    /// Returns the number of whole timer cycles elapsed since the last call.
+    ///
+    /// If the frontend reported the `frame_time` since the previous frame, that is counted instead
+    /// of the measured wall-clock time. The first call after creating or [stopping](Self::stop) the
+    /// clock only starts it and returns 0. Partial cycles are carried over to later calls so that no
+    /// time is lost.
+    pub fn elapsed_cycles(&mut self, frame_time: Option<Duration>) -> usize {
+        let now = Instant::now();
+        let elapsed = match (self.last, frame_time) {
+            (None, _) => 0,
+            (Some(_), Some(frame_time)) => frame_time.as_nanos(),
+            (Some(last), None) => (now - last).as_nanos(),
+        };
+        self.last = Some(now);
+
+        let total = elapsed * TIMER_CYCLE_RATE as u128 + self.remainder;
+        self.remainder = total % NANOS_PER_SECOND;
+        (total / NANOS_PER_SECOND).try_into().unwrap_or(usize::MAX)
+    }
+
+    /// Stops the clock, so that time elapsed until the next call to
+    /// [elapsed_cycles](Self::elapsed_cycles) isn't counted (e.g. while execution is paused).
+    pub fn stop(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the timer cycles counted for each of the given frame times, after starting the clock.
+    fn cycles(frame_times: impl IntoIterator<Item = Duration>) -> Vec<usize> {
+        let mut clock = TimerClock::default();
+        assert_eq!(clock.elapsed_cycles(None), 0);
+        frame_times
+            .into_iter()
+            .map(|frame_time| clock.elapsed_cycles(Some(frame_time)))
+            .collect()
+    }
+
+    #[test]
+    fn timers_count_60_cycles_per_second() {
+        let frame_rates = [60, 50, 30, 144, 7];
+        for frame_rate in frame_rates {
+            let frame_time = Duration::from_nanos(NANOS_PER_SECOND as u64 / frame_rate);
+            let per_frame = cycles((0..frame_rate).map(|_| frame_time));
+            // Frame times rounded down to the nanosecond may fall just short of a whole second
+            let total: usize = per_frame.iter().sum();
+            assert!(
+                (TIMER_CYCLE_RATE - 1..=TIMER_CYCLE_RATE).contains(&total),
+                "{total} cycles at {frame_rate} fps"
+            );
+        }
+
+        // Uneven frame times still add up
+        let frame_times = [5, 20, 12, 3, 60, 400, 500].map(Duration::from_millis);
+        assert_eq!(cycles(frame_times).iter().sum::<usize>(), TIMER_CYCLE_RATE);
+
+        // Partial cycles carry over, so 10 ms frames count 3 cycles every 5 frames
+        let per_frame = cycles((0..100).map(|_| Duration::from_millis(10)));
+        assert!(per_frame.chunks(5).all(|chunk| chunk == [0, 1, 0, 1, 1]));
+    }
+
+    #[test]
+    fn stopped_clock_does_not_count_time() {
+        let mut clock = TimerClock::default();
+        clock.elapsed_cycles(None);
+        assert_eq!(clock.elapsed_cycles(Some(Duration::from_millis(10))), 0);
+        clock.stop();
+        // The partial cycle is dropped, and restarting doesn't count the time stopped
+        assert_eq!(clock.elapsed_cycles(Some(Duration::from_secs(1))), 0);
+        assert_eq!(clock.elapsed_cycles(Some(Duration::from_millis(10))), 0);
+        assert_eq!(clock.elapsed_cycles(Some(Duration::from_millis(10))), 1);
+    }
+}
@@ -23,12 +23,15 @@ mod callbacks;
 mod constants;
 mod core;
 mod log;
+mod options;
 
 use self::{callbacks as cb, constants::*};
 use eyre::eyre;
 use libretro_defs as lr;
 use std::{
+    ffi::CStr,
     os::raw::{c_char, c_uint, c_void},
+    path::PathBuf,
     slice,
 };
 
@@ -75,16 +78,17 @@ pub unsafe extern "C" fn retro_get_system_info(dest: *mut lr::retro_system_info)
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn retro_get_system_av_info(dest: *mut lr::retro_system_av_info) {
     assert!(!dest.is_null());
+    let scale = options::get().internal_scale;
     let av_info = lr::retro_system_av_info {
         timing: lr::retro_system_timing {
-            fps: FRAME_RATE as f64,
+            fps: core::frame_rate() as f64,
             sample_rate: AUDIO_SAMPLE_RATE as f64,
         },
         geometry: lr::retro_game_geometry {
-            base_width: SCREEN_WIDTH as c_uint,
-            base_height: SCREEN_HEIGHT as c_uint,
-            max_width: SCREEN_WIDTH as c_uint,
-            max_height: SCREEN_HEIGHT as c_uint,
+            base_width: (SCREEN_WIDTH * scale) as c_uint,
+            base_height: (SCREEN_HEIGHT * scale) as c_uint,
+            max_width: (SCREEN_WIDTH * MAX_INTERNAL_SCALE) as c_uint,
+            max_height: (SCREEN_HEIGHT * MAX_INTERNAL_SCALE) as c_uint,
             aspect_ratio: (SCREEN_WIDTH as f32) / (SCREEN_HEIGHT as f32),
         },
     };
@@ -100,13 +104,32 @@ pub unsafe extern "C" fn retro_get_system_av_info(dest: *mut lr::retro_system_av
 /// Returns true to indicate successful loading and false to indicate load failure.
 #[no_mangle]
 pub extern "C" fn retro_load_game(game_info_ptr: Option<&lr::retro_game_info>) -> bool {
-    game_info_ptr
+    options::load_persisted();
+    options::update();
+
+    // Per-game settings may be configured in a companion file next to the game, which is imported
+    // first so that settings applied on load (e.g. which game to run from a multi-game file) take
+    // effect
+    let game_path = game_info_ptr
+        .filter(|info| !info.path.is_null())
+        .map(|info| PathBuf::from(&*unsafe { CStr::from_ptr(info.path) }.to_string_lossy()));
+    if let Some(path) = &game_path {
+        options::load_companion_file(path);
+    }
+
+    let loaded = game_info_ptr
         .ok_or_else(|| eyre!("retro_game_info pointer is null"))
-        .and_then(|game_info| match game_info.data.is_null() {
-            false => Ok(unsafe {
-                slice::from_raw_parts(game_info.data as *const u8, game_info.size as usize)
-            }),
-            true => Err(eyre!("data pointer is null")),
+        .and_then(|game_info| {
+            // The data can't be checked against its actual allocation, but an absurd size means the
+            // frontend passed garbage, and creating a slice from it would be unsound
+            let size = game_info.size as usize;
+            match (game_info.data.is_null(), size > MAX_CONTENT_SIZE) {
+                (true, _) => Err(eyre!("data pointer is null")),
+                (false, true) => Err(eyre!("implausible content size {size}")),
+                (false, false) => {
+                    Ok(unsafe { slice::from_raw_parts(game_info.data as *const u8, size) })
+                }
+            }
         })
         .and_then(core::load_game)
         .map_or_else(
@@ -115,7 +138,33 @@ pub extern "C" fn retro_load_game(game_info_ptr: Option<&lr::retro_game_info>) -
                 false
             },
             |()| true,
-        )
+        );
+
+    // Debugging aids may also be configured in companion files next to the game
+    if let Some(path) = game_path.filter(|_| loaded) {
+        core::load_companion_files(&path);
+    }
+
+    if loaded {
+        // A per-game frame rate may have been imported, so this is only chosen now
+        core::select_frame_rate(cb::env_get_target_refresh_rate().ok());
+        core::register_frame_time_callback();
+
+        tracing::info!(
+            "loaded game at {} fps with options: {}",
+            core::frame_rate(),
+            options::get(),
+        );
+        tracing::debug!("configuration string: {}", options::export());
+        tracing::debug!(
+            "system directory: {:?}, save directory: {:?}",
+            cb::env_get_system_directory(),
+            cb::env_get_save_directory(),
+        );
+        core::report_rom_analysis();
+        core::show_shader_hint();
+    }
+    loaded
 }
 
 /// Unloads the currently loaded game.
@@ -149,6 +198,7 @@ pub extern "C" fn retro_load_game_special(
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn retro_set_environment(funcptr: lr::retro_environment_t) {
     cb::init_environment_cb(funcptr);
+    options::set_variables();
 }
 
 /// TrustyChip callback that receives the LibRetro video refresh callback from the frontend.
@@ -212,7 +262,12 @@ pub unsafe extern "C" fn retro_set_input_state(funcptr: lr::retro_input_state_t)
 pub extern "C" fn retro_init() {
     log::init_log_interface();
     cb::env_set_input_descriptors();
+    cb::init_input_bitmasks();
+    if let Err(e) = cb::env_set_controller_info() {
+        tracing::warn!("{:#}", e);
+    }
     core::init();
+    tracing::debug!("capabilities: {}", core::capabilities());
     log::forward_retro_logs();
 }
 
@@ -240,14 +295,17 @@ pub extern "C" fn retro_deinit() {
 /// frontend if the descriptions for any controls have changed as a
 /// result of changing the device type.
 #[no_mangle]
-pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {
-    // TODO: figure out what this is even about
+pub extern "C" fn retro_set_controller_port_device(port: c_uint, device: c_uint) {
+    // The input descriptors only cover the keyboard, which is read whatever the device, so they
+    // don't need updating here
+    cb::set_controller_device(port, device);
+    log::forward_retro_logs();
 }
 
 /// Resets the current game.
 #[no_mangle]
 pub extern "C" fn retro_reset() {
-    tracing::warn!("retro_reset not implemented");
+    core::reset();
     log::forward_retro_logs();
 }
 
@@ -262,6 +320,7 @@ pub extern "C" fn retro_reset() {
 /// can take a NULL argument for data.
 #[no_mangle]
 pub extern "C" fn retro_run() {
+    options::update_if_changed();
     core::run();
     log::forward_retro_logs();
 }
@@ -275,7 +334,7 @@ pub extern "C" fn retro_run() {
 /// value, to ensure that the frontend can allocate a save state buffer once.
 #[no_mangle]
 pub extern "C" fn retro_serialize_size() -> lr::size_t {
-    0
+    core::SAVE_STATE_SIZE as lr::size_t
 }
 
 /// Serializes internal state.
@@ -283,14 +342,36 @@ pub extern "C" fn retro_serialize_size() -> lr::size_t {
 /// If failed, or size argument is lower than `retro_serialize_size`, should return false.
 /// Returns true on success.
 #[no_mangle]
-pub extern "C" fn retro_serialize(_data: *mut c_void, _size: lr::size_t) -> bool {
-    false
+pub extern "C" fn retro_serialize(data: *mut c_void, size: lr::size_t) -> bool {
+    if data.is_null() {
+        tracing::error!("failed to save state: data pointer is null");
+        return false;
+    }
+    let buf = unsafe { slice::from_raw_parts_mut(data as *mut u8, size as usize) };
+    core::serialize(buf).map_or_else(
+        |e| {
+            tracing::error!("failed to save state: {:#}", e);
+            false
+        },
+        |()| true,
+    )
 }
 
 /// Unserializes (restores) emulator state from a save state.
 #[no_mangle]
-pub extern "C" fn retro_unserialize(_data: *const c_void, _size: lr::size_t) -> bool {
-    false
+pub extern "C" fn retro_unserialize(data: *const c_void, size: lr::size_t) -> bool {
+    if data.is_null() {
+        tracing::error!("failed to load state: data pointer is null");
+        return false;
+    }
+    let data = unsafe { slice::from_raw_parts(data as *const u8, size as usize) };
+    core::unserialize(data).map_or_else(
+        |e| {
+            tracing::error!("failed to load state: {:#}", e);
+            false
+        },
+        |()| true,
+    )
 }
 
 /// Disables any cheats.
@@ -320,3 +401,146 @@ pub extern "C" fn retro_get_memory_data(_id: c_uint) -> *mut c_void {
 pub extern "C" fn retro_get_memory_size(_id: c_uint) -> lr::size_t {
     0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{ffi::CString, fs};
+
+    /// Loads `data` through the frontend API from a temporary file named `name`, with the companion
+    /// options file next to it containing `companion` if given.
+    fn load_game_file(name: &str, data: &[u8], companion: Option<&str>) -> bool {
+        let path =
+            std::env::temp_dir().join(format!("trustychip-test-{}-{name}.ch8", std::process::id()));
+        let companion_path = path.with_extension("options");
+        fs::write(&path, data).unwrap();
+        if let Some(companion) = companion {
+            fs::write(&companion_path, companion).unwrap();
+        }
+
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let game_info = lr::retro_game_info {
+            path: c_path.as_ptr(),
+            data: data.as_ptr() as *const c_void,
+            size: data.len() as lr::size_t,
+            meta: std::ptr::null(),
+        };
+        let loaded = retro_load_game(Some(&game_info));
+
+        fs::remove_file(&path).unwrap();
+        if companion.is_some() {
+            fs::remove_file(&companion_path).unwrap();
+        }
+        loaded
+    }
+
+    #[test]
+    fn companion_file_selects_the_game_from_a_multi_game_file() {
+        let _guard = cb::mock::install();
+        let games = [[0x12, 0x00], [0x60, 0x01]];
+        let mut container = b"CH8MULTI\x02".to_vec();
+        container.extend([0, 17, 0, 2, 0, 19, 0, 2]);
+        container.extend(games.concat());
+
+        assert!(load_game_file("multi", &container, Some("game_select=2")));
+        assert_eq!(
+            core::state::with(|emustate| emustate.rom.to_vec()),
+            games[1]
+        );
+
+        // The selection doesn't stick to games without a companion file
+        assert!(load_game_file("multi", &container, None));
+        assert_eq!(
+            core::state::with(|emustate| emustate.rom.to_vec()),
+            games[0]
+        );
+    }
+
+    #[test]
+    fn implausible_content_is_rejected() {
+        let _guard = cb::mock::install();
+        let game = [0x12, 0x00];
+        assert!(load_game_file("plausible", &game, None));
+
+        // The data isn't read, so any non-null pointer will do
+        for (data, size) in [
+            (game.as_ptr() as *const c_void, MAX_CONTENT_SIZE + 1),
+            (game.as_ptr() as *const c_void, usize::MAX),
+            (std::ptr::null(), game.len()),
+        ] {
+            let game_info = lr::retro_game_info {
+                path: std::ptr::null(),
+                data,
+                size: size as lr::size_t,
+                meta: std::ptr::null(),
+            };
+            assert!(!retro_load_game(Some(&game_info)), "size {size}");
+        }
+        assert!(!retro_load_game(None));
+
+        // The previously loaded game is left alone
+        assert_eq!(core::state::with(|emustate| emustate.rom.to_vec()), game);
+    }
+
+    #[test]
+    fn reset_restarts_the_game_with_a_blank_screen() {
+        let _guard = cb::mock::install();
+        // Draws the "5" glyph then loops
+        let game = [0x60, 0x05, 0xF0, 0x29, 0xD1, 0x15, 0x12, 0x06];
+        assert!(load_game_file("reset", &game, None));
+        for _ in 0..3 {
+            retro_run();
+        }
+        core::state::with(|emustate| {
+            assert_eq!(emustate.pc, GAME_ADDRESS + 6);
+            assert!(emustate.screen.contains(&core::state::PixelState::White));
+        });
+
+        retro_reset();
+        core::state::with(|emustate| {
+            assert_eq!(emustate.pc, GAME_ADDRESS);
+            assert_eq!(emustate.v, [0; 16]);
+            assert!(emustate
+                .screen
+                .iter()
+                .all(|&pixel| pixel == core::state::PixelState::Black));
+            assert_eq!(&emustate.mem[GAME_ADDRESS..GAME_ADDRESS + game.len()], game);
+        });
+    }
+
+    /// Returns the frame rate reported to the frontend.
+    fn av_info_fps() -> f64 {
+        let mut av_info = std::mem::MaybeUninit::uninit();
+        unsafe {
+            retro_get_system_av_info(av_info.as_mut_ptr());
+            av_info.assume_init().timing.fps
+        }
+    }
+
+    #[test]
+    fn companion_frame_rate_is_reported_to_the_frontend() {
+        let _guard = cb::mock::install();
+        // A frontend with a 30 Hz display
+        cb::mock::set_environment(|cmd, data| unsafe {
+            match cmd {
+                lr::RETRO_ENVIRONMENT_GET_TARGET_REFRESH_RATE => {
+                    *(data as *mut f32) = 30.0;
+                    true
+                }
+                lr::RETRO_ENVIRONMENT_SET_PIXEL_FORMAT => true,
+                _ => false,
+            }
+        });
+        let game = [0x12, 0x00];
+
+        assert!(load_game_file("fps", &game, None));
+        assert_eq!(av_info_fps(), 30.0);
+
+        assert!(load_game_file("fps", &game, Some("frame_rate=60")));
+        assert_eq!(av_info_fps(), 60.0);
+
+        // A failed load leaves the frame rate alone
+        assert!(!load_game_file("fps", &[], None));
+        assert_eq!(av_info_fps(), 60.0);
+    }
+}
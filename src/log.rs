@@ -1,8 +1,7 @@
 use std::{cell::Cell, ffi::CString, io};
 
-use crate::callbacks::env_get;
+use crate::callbacks as cb;
 use crossbeam_queue::SegQueue;
-use eyre::{Result, WrapErr};
 use libretro_defs as lr;
 use tracing::Metadata;
 use tracing_subscriber::fmt::{writer::EitherWriter, MakeWriter};
@@ -18,10 +17,7 @@ thread_local! {
 /// Attempts to get the retro logging function from the frontend and initialize tracing with it.
 /// If unable to do so, it will fall back on stderr. Will panic if called more than once.
 pub fn init_log_interface() {
-    let result: Result<lr::retro_log_callback> = unsafe {
-        env_get(lr::RETRO_ENVIRONMENT_GET_LOG_INTERFACE)
-            .wrap_err("failed to get retro log interface")
-    };
+    let result = cb::env_get_log_interface();
 
     let subscriber = tracing_subscriber::fmt().without_time();
 
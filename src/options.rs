@@ -0,0 +1,1274 @@
+//! Core options exposed to the frontend.
+//!
+//! Options are registered with `RETRO_ENVIRONMENT_SET_CORE_OPTIONS_V2` (falling back to
+//! `RETRO_ENVIRONMENT_SET_VARIABLES` on older frontends) and read back one at a time with
+//! `RETRO_ENVIRONMENT_GET_VARIABLE`. The parsed values are cached in [Options] so that the rest of
+//! the core can cheaply query them, and are only re-read when the frontend reports a change.
+
+use std::{
+    cell::{Cell, RefCell},
+    fmt, fs, mem,
+    path::Path,
+    str::FromStr,
+};
+
+use crate::{
+    callbacks as cb,
+    constants::{MAX_BEEP_GAP_BRIDGE, MAX_INTERNAL_SCALE, SUPPORTED_FRAME_RATES},
+    utils::rgb888_to_rgb565,
+};
+use eyre::{eyre, Result, WrapErr};
+
+const DRAW_WATCHDOG: &str = "trustychip_draw_watchdog";
+const FOREGROUND_COLOR: &str = "trustychip_foreground_color";
+const BACKGROUND_COLOR: &str = "trustychip_background_color";
+const BEEP_GAP_BRIDGE: &str = "trustychip_beep_gap_bridge";
+const TICK_BUDGET_MS: &str = "trustychip_tick_budget_ms";
+const INVERT: &str = "trustychip_invert";
+const STACK_SIZE: &str = "trustychip_stack_size";
+const STACK_OVERFLOW: &str = "trustychip_stack_overflow";
+const LOG_FRAME_HASH: &str = "trustychip_log_frame_hash";
+const SHIFT_QUIRK: &str = "trustychip_shift_quirk";
+const INVALID_INSTRUCTION: &str = "trustychip_invalid_instruction";
+const INTERNAL_SCALE: &str = "trustychip_internal_scale";
+const STARTUP_DELAY: &str = "trustychip_startup_delay";
+const OPCODE_STATS: &str = "trustychip_opcode_stats";
+const PAN: &str = "trustychip_pan";
+const TEST_PATTERN: &str = "trustychip_test_pattern";
+const REALTIME_TIMERS: &str = "trustychip_realtime_timers";
+const GAME_SELECT: &str = "trustychip_game_select";
+const DRAW_MODE: &str = "trustychip_draw_mode";
+const KEY_SKIP_INTERVAL: &str = "trustychip_key_skip_interval";
+const DISPLAY_FILTER: &str = "trustychip_display_filter";
+const BEEP_RETRIGGER: &str = "trustychip_beep_retrigger";
+const FLIP_VERTICAL: &str = "trustychip_flip_vertical";
+const FLIP_HORIZONTAL: &str = "trustychip_flip_horizontal";
+const ERROR_BEEP: &str = "trustychip_error_beep";
+const FRAME_RATE: &str = "trustychip_frame_rate";
+const SPRITE_OVERRUN: &str = "trustychip_sprite_overrun";
+const SHADER_HINT: &str = "trustychip_shader_hint";
+const BOOT_SPLASH: &str = "trustychip_boot_splash";
+const CLEAR_ON_RESET: &str = "trustychip_clear_on_reset";
+const WRAP_DIAG: &str = "trustychip_wrap_diag";
+const TOUCH_KEYPAD: &str = "trustychip_touch_keypad";
+const PERSIST_OPTIONS: &str = "trustychip_persist_options";
+const CPU_SPEED: &str = "trustychip_cpu_speed";
+#[cfg(feature = "debug-server")]
+const DEBUG_SERVER: &str = "trustychip_debug_server";
+
+/// Option definitions given to the frontend as (key, "Description; value1|value2|...") pairs.
+/// The first listed value is the default.
+const DEFINITIONS: &[(&str, &str)] = &[
+    (DRAW_WATCHDOG, "Warn if game never draws; disabled|enabled"),
+    (
+        FOREGROUND_COLOR,
+        "Foreground color; white|light gray|green|amber|cyan|black|dark gray",
+    ),
+    (
+        BACKGROUND_COLOR,
+        "Background color; black|dark gray|dark green|dark blue|white|light gray",
+    ),
+    (
+        BEEP_GAP_BRIDGE,
+        "Merge beeps separated by gaps up to this many frames; 0|1|2|3|4|6|8",
+    ),
+    (
+        TICK_BUDGET_MS,
+        "Max CPU time per frame (ms); disabled|4|8|12|16",
+    ),
+    (INVERT, "Invert display colors; disabled|enabled"),
+    (STACK_SIZE, "Subroutine stack size; unlimited|16|12"),
+    (
+        STACK_OVERFLOW,
+        "On subroutine stack overflow; shutdown|ignore",
+    ),
+    (
+        LOG_FRAME_HASH,
+        "Log a state hash every frame; disabled|enabled",
+    ),
+    (
+        SHIFT_QUIRK,
+        "8xy6/8xyE shift Vx in place (CHIP-48/SCHIP quirk); disabled|enabled",
+    ),
+    (
+        INVALID_INSTRUCTION,
+        "On invalid instruction; shutdown|pause|skip|nop",
+    ),
+    (INTERNAL_SCALE, "Internal resolution scale; 1|2|4"),
+    (
+        STARTUP_DELAY,
+        "Frames to wait before starting the game; 0|1|2|5|10",
+    ),
+    (
+        OPCODE_STATS,
+        "Count executed instructions and log a histogram on unload; disabled|enabled",
+    ),
+    (
+        PAN,
+        "Buzzer stereo pan (negative is left); 0|-100|-75|-50|-25|25|50|75|100",
+    ),
+    (
+        TEST_PATTERN,
+        "Show display test pattern instead of the game; disabled|enabled",
+    ),
+    (
+        REALTIME_TIMERS,
+        "Count down timers by real time instead of per frame; disabled|enabled",
+    ),
+    (
+        GAME_SELECT,
+        "Game to run from multi-game files (applies on load); 1|2|3|4|5|6|7|8",
+    ),
+    (DRAW_MODE, "Sprite drawing mode; xor|overwrite"),
+    (
+        KEY_SKIP_INTERVAL,
+        "Min timer cycles between held key presses seen by Ex9E/ExA1; disabled|2|4|8|15|30",
+    ),
+    (
+        DISPLAY_FILTER,
+        "Display filter to reduce flicker; none|frame blend",
+    ),
+    (
+        BEEP_RETRIGGER,
+        "Restart the beep when the sound timer is set while sounding; disabled|enabled",
+    ),
+    (
+        FLIP_VERTICAL,
+        "Flip video output vertically (bottom-to-top rows); disabled|enabled",
+    ),
+    (
+        FLIP_HORIZONTAL,
+        "Flip video output horizontally (mirrored displays); disabled|enabled",
+    ),
+    (
+        ERROR_BEEP,
+        "Play a low beep on invalid instructions; disabled|enabled",
+    ),
+    (FRAME_RATE, "Frame rate (applies on load); auto|60|30|20|15"),
+    (
+        SPRITE_OVERRUN,
+        "On sprite reading past end of memory; invalid|clamp|wrap",
+    ),
+    (
+        SHADER_HINT,
+        "Suggest a CRT shader preset on load; disabled|enabled",
+    ),
+    (
+        BOOT_SPLASH,
+        "Scroll the hex font across the screen before starting the game; disabled|enabled",
+    ),
+    (
+        CLEAR_ON_RESET,
+        "Clear the screen when a game is loaded or reset; enabled|disabled",
+    ),
+    (
+        WRAP_DIAG,
+        "Periodically log how many sprites were clipped at the screen edge; disabled|enabled",
+    ),
+    (
+        TOUCH_KEYPAD,
+        "On-screen keypad for touch or mouse input; disabled|enabled",
+    ),
+    (
+        PERSIST_OPTIONS,
+        "Save options to the save directory as a backup; disabled|enabled",
+    ),
+    (
+        CPU_SPEED,
+        "Instructions per second; 500|420|600|700|1000|1500|2000|unlimited",
+    ),
+];
+
+/// Categories that options are grouped into in the frontend's menu, as (key, description, options)
+/// triples. Options not listed in any category are shown at the top level.
+///
+/// Categories are only supported by frontends with core options version 2 or later.
+const CATEGORIES: &[(&str, &str, &[&str])] = &[
+    (
+        "video",
+        "Video",
+        &[
+            FOREGROUND_COLOR,
+            BACKGROUND_COLOR,
+            INVERT,
+            INTERNAL_SCALE,
+            DISPLAY_FILTER,
+            FLIP_VERTICAL,
+            FLIP_HORIZONTAL,
+            FRAME_RATE,
+            SHADER_HINT,
+            BOOT_SPLASH,
+            CLEAR_ON_RESET,
+            STARTUP_DELAY,
+            TEST_PATTERN,
+        ],
+    ),
+    ("audio", "Audio", &[BEEP_GAP_BRIDGE, BEEP_RETRIGGER, PAN]),
+    ("input", "Input", &[KEY_SKIP_INTERVAL, TOUCH_KEYPAD]),
+    (
+        "compatibility",
+        "Compatibility",
+        &[
+            CPU_SPEED,
+            SHIFT_QUIRK,
+            STACK_SIZE,
+            STACK_OVERFLOW,
+            DRAW_MODE,
+            INVALID_INSTRUCTION,
+            SPRITE_OVERRUN,
+            REALTIME_TIMERS,
+            TICK_BUDGET_MS,
+        ],
+    ),
+    (
+        "debugging",
+        "Debugging",
+        &[
+            DRAW_WATCHDOG,
+            LOG_FRAME_HASH,
+            OPCODE_STATS,
+            ERROR_BEEP,
+            WRAP_DIAG,
+        ],
+    ),
+];
+
+/// Option definitions which are only available when their cargo feature is enabled.
+#[cfg(feature = "debug-server")]
+const FEATURE_DEFINITIONS: &[(&str, &str)] = &[(
+    DEBUG_SERVER,
+    "Debug server on localhost:6502; disabled|enabled",
+)];
+#[cfg(not(feature = "debug-server"))]
+const FEATURE_DEFINITIONS: &[(&str, &str)] = &[];
+
+/// Named colors available to the color options, as 24-bit RGB values.
+const COLORS: &[(&str, [u8; 3])] = &[
+    ("white", [0xFF, 0xFF, 0xFF]),
+    ("light gray", [0xAA, 0xAA, 0xAA]),
+    ("dark gray", [0x55, 0x55, 0x55]),
+    ("black", [0x00, 0x00, 0x00]),
+    ("green", [0x33, 0xFF, 0x66]),
+    ("dark green", [0x0F, 0x38, 0x0F]),
+    ("amber", [0xFF, 0xB0, 0x00]),
+    ("cyan", [0x00, 0xE5, 0xFF]),
+    ("dark blue", [0x10, 0x18, 0x40]),
+];
+
+/// Prefix shared by all option keys, which is left out of exported configuration strings
+const KEY_PREFIX: &str = "trustychip_";
+
+/// File extension of the optional companion file that a configuration string is imported from.
+///
+/// The file sits next to the ROM (e.g. `pong.options` for `pong.ch8`) and contains a string
+/// produced by [export].
+const COMPANION_FILE_EXTENSION: &str = "options";
+
+/// Name of the file in the save directory that options are persisted to, which contains a string
+/// produced by [export].
+const PERSISTED_FILE_NAME: &str = "trustychip.options";
+
+thread_local! {
+    static OPTIONS: Cell<Options> = const { Cell::new(Options::DEFAULT) };
+    /// Option values imported from a configuration string, which take precedence over the values
+    /// set in the frontend
+    static OVERRIDES: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+    /// Option values loaded from the save directory, which are used for options the frontend
+    /// doesn't provide a value for
+    static PERSISTED: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The parsed values of all core options.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    /// Log a warning if no draw or screen clear instruction executes within the first few seconds
+    pub draw_watchdog: bool,
+    /// Colors of the video output, indexed by pixel value
+    pub palette: Palette,
+    /// Number of frames the buzzer keeps sounding after the sound timer runs out
+    pub beep_gap_bridge: usize,
+    /// Maximum wall-clock time in milliseconds to spend executing instructions each frame
+    pub tick_budget_ms: Option<u64>,
+    /// Swap the foreground and background palette entries in the video output
+    pub invert: bool,
+    /// Maximum subroutine nesting depth, or None for unlimited
+    pub stack_size: Option<usize>,
+    /// What to do when a subroutine call would exceed the maximum nesting depth
+    pub stack_overflow: StackOverflowAction,
+    /// Log a hash of the emulator state at the end of every frame
+    pub log_frame_hash: bool,
+    /// Make 8xy6/8xyE shift Vx in place instead of shifting Vy into Vx
+    pub shift_quirk: bool,
+    /// What to do when the game executes an invalid instruction
+    pub invalid_instruction: InvalidInstructionAction,
+    /// Factor to scale the video output by, duplicating each pixel into a square block
+    pub internal_scale: usize,
+    /// Number of frames after loading to show a blank screen before starting execution
+    pub startup_delay: usize,
+    /// Count executed instructions by opcode family and log a histogram when the game is unloaded
+    pub opcode_stats: bool,
+    /// Stereo position of the buzzer from -100 (left only) to 100 (right only)
+    pub pan: i32,
+    /// Show a calibration test pattern instead of the game, which is paused meanwhile
+    pub test_pattern: bool,
+    /// Decrement the delay and sound timers at 60 Hz of wall-clock time instead of a fixed number of
+    /// times per frame
+    pub realtime_timers: bool,
+    /// Which game to load from a multi-game file, counting from 0
+    pub game_select: usize,
+    /// How sprites are combined with the existing screen contents
+    pub draw_mode: DrawMode,
+    /// Minimum number of timer cycles between a held key registering as pressed for Ex9E/ExA1, or
+    /// None to always report held keys as pressed
+    pub key_skip_interval: Option<u8>,
+    /// Post-processing applied to the video output to reduce flicker
+    pub display_filter: DisplayFilter,
+    /// Restart the buzzer waveform when the sound timer is set again while the buzzer is sounding,
+    /// rather than sustaining the current tone
+    pub beep_retrigger: bool,
+    /// Reverse the order of the rows in the video output
+    pub flip_vertical: bool,
+    /// Reverse the order of the pixels within each row of the video output
+    pub flip_horizontal: bool,
+    /// Play a short error tone when the game executes an invalid instruction
+    pub error_beep: bool,
+    /// Video frame rate to run at, or None to pick the one closest to the display refresh rate
+    pub frame_rate: Option<usize>,
+    /// What to do when Dxyn reads sprite data past the end of memory
+    pub sprite_overrun: SpriteOverrunAction,
+    /// Recommend a CRT shader preset when a game is loaded, as the core can't apply one itself
+    pub shader_hint: bool,
+    /// Show a splash scrolling the hex font's digits across the screen after loading, before the
+    /// game starts
+    pub boot_splash: bool,
+    /// Clear the screen when a game is loaded or reset, rather than keeping the previous screen
+    /// until the game draws
+    pub clear_on_reset: bool,
+    /// Count sprite draws which were clipped at the edge of the screen and periodically log the
+    /// tally, as a hint that the game expects sprites to wrap
+    pub wrap_diag: bool,
+    /// Show a hex keypad over the video output which can be pressed with the pointer device
+    pub touch_keypad: bool,
+    /// Save the options to the save directory whenever they change, for frontends and embedders
+    /// which don't persist them
+    pub persist_options: bool,
+    /// Number of instructions to execute per second, or `None` to execute as many as fit in the tick
+    /// budget
+    pub cpu_speed: Option<usize>,
+    /// Run the debug server
+    #[cfg(feature = "debug-server")]
+    pub debug_server: bool,
+}
+
+/// RGB565 colors for each pixel value.
+///
+/// Plain Chip-8 pixels are either unlit ([BACKGROUND](Self::BACKGROUND)) or lit
+/// ([FOREGROUND](Self::FOREGROUND)). The remaining entries are there for multi-plane display modes,
+/// where a pixel's value combines the bits of each plane.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette(pub [u16; 16]);
+
+impl Palette {
+    /// Index of the color of unlit pixels
+    pub const BACKGROUND: usize = 0;
+    /// Index of the color of lit pixels
+    pub const FOREGROUND: usize = 1;
+
+    const DEFAULT: Palette = {
+        let mut colors = [rgb888_to_rgb565(0xFF, 0xFF, 0xFF); 16];
+        colors[Self::BACKGROUND] = rgb888_to_rgb565(0x00, 0x00, 0x00);
+        Palette(colors)
+    };
+
+    /// Returns the color for a pixel value.
+    pub fn color(&self, index: usize) -> u16 {
+        self.0[index]
+    }
+
+    /// Returns the palette with the foreground and background colors swapped.
+    pub fn inverted(mut self) -> Palette {
+        self.0.swap(Self::BACKGROUND, Self::FOREGROUND);
+        self
+    }
+}
+
+/// What to do when the game executes an invalid instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvalidInstructionAction {
+    /// Log the instruction and shut down the frontend
+    Shutdown,
+    /// Stop executing instructions and show the instruction on screen until reset
+    Pause,
+    /// Log a warning and continue with the next instruction as if it were a no-op
+    Skip,
+    /// Silently continue with the next instruction, for games which deliberately execute data
+    Nop,
+}
+
+impl FromStr for InvalidInstructionAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shutdown" => Ok(Self::Shutdown),
+            "pause" => Ok(Self::Pause),
+            "skip" => Ok(Self::Skip),
+            "nop" => Ok(Self::Nop),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What to do when a subroutine call would exceed the maximum nesting depth
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StackOverflowAction {
+    /// Log the call and shut down the frontend
+    Shutdown,
+    /// Log the call and continue with the next instruction without calling the subroutine
+    Ignore,
+}
+
+impl FromStr for StackOverflowAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shutdown" => Ok(Self::Shutdown),
+            "ignore" => Ok(Self::Ignore),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What to do when Dxyn reads sprite data past the end of memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpriteOverrunAction {
+    /// Handle the instruction like any other invalid instruction (see [InvalidInstructionAction])
+    Invalid,
+    /// Only draw the rows of the sprite which are within memory
+    Clamp,
+    /// Continue reading the sprite from the start of memory
+    Wrap,
+}
+
+impl FromStr for SpriteOverrunAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "invalid" => Ok(Self::Invalid),
+            "clamp" => Ok(Self::Clamp),
+            "wrap" => Ok(Self::Wrap),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How sprites drawn by Dxyn are combined with the existing screen contents
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DrawMode {
+    /// XOR sprite pixels onto the screen, setting VF if any lit pixel is turned off (standard)
+    Xor,
+    /// Turn on the pixels set in the sprite and leave the rest unchanged, always setting VF to 0
+    Overwrite,
+}
+
+impl FromStr for DrawMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xor" => Ok(Self::Xor),
+            "overwrite" => Ok(Self::Overwrite),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Post-processing applied to the video output to reduce flicker
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DisplayFilter {
+    /// Output each frame as is
+    None,
+    /// Average each frame with the previous one
+    FrameBlend,
+}
+
+impl FromStr for DisplayFilter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "frame blend" => Ok(Self::FrameBlend),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Options {
+    const DEFAULT: Options = Options {
+        draw_watchdog: false,
+        palette: Palette::DEFAULT,
+        beep_gap_bridge: 0,
+        tick_budget_ms: None,
+        invert: false,
+        stack_size: None,
+        stack_overflow: StackOverflowAction::Shutdown,
+        log_frame_hash: false,
+        shift_quirk: false,
+        invalid_instruction: InvalidInstructionAction::Shutdown,
+        internal_scale: 1,
+        startup_delay: 0,
+        opcode_stats: false,
+        pan: 0,
+        test_pattern: false,
+        realtime_timers: false,
+        game_select: 0,
+        draw_mode: DrawMode::Xor,
+        key_skip_interval: None,
+        display_filter: DisplayFilter::None,
+        beep_retrigger: false,
+        flip_vertical: false,
+        flip_horizontal: false,
+        error_beep: false,
+        frame_rate: None,
+        sprite_overrun: SpriteOverrunAction::Invalid,
+        shader_hint: false,
+        boot_splash: false,
+        clear_on_reset: true,
+        wrap_diag: false,
+        touch_keypad: false,
+        persist_options: false,
+        cpu_speed: Some(500),
+        #[cfg(feature = "debug-server")]
+        debug_server: false,
+    };
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Formats a one-line summary of the settings, for logging.
+impl fmt::Display for Options {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let optional = |value: Option<usize>| match value {
+            Some(value) => value.to_string(),
+            None => "none".to_string(),
+        };
+
+        write!(
+            f,
+            "foreground={:#06x} background={:#06x} invert={} shift_quirk={} stack_size={} \
+            stack_overflow={:?} invalid_instruction={:?} tick_budget_ms={} beep_gap_bridge={} draw_watchdog={} \
+            log_frame_hash={} internal_scale={} startup_delay={} opcode_stats={} pan={} test_pattern={} \
+            realtime_timers={} game_select={} draw_mode={:?} key_skip_interval={} \
+            display_filter={:?} beep_retrigger={} flip_vertical={} flip_horizontal={} \
+            error_beep={} frame_rate={} \
+            sprite_overrun={:?} shader_hint={} boot_splash={} clear_on_reset={} wrap_diag={} \
+            touch_keypad={} persist_options={} cpu_speed={}",
+            self.palette.color(Palette::FOREGROUND),
+            self.palette.color(Palette::BACKGROUND),
+            self.invert,
+            self.shift_quirk,
+            optional(self.stack_size),
+            self.stack_overflow,
+            self.invalid_instruction,
+            optional(self.tick_budget_ms.map(|ms| ms as usize)),
+            self.beep_gap_bridge,
+            self.draw_watchdog,
+            self.log_frame_hash,
+            self.internal_scale,
+            self.startup_delay,
+            self.opcode_stats,
+            self.pan,
+            self.test_pattern,
+            self.realtime_timers,
+            self.game_select + 1,
+            self.draw_mode,
+            optional(self.key_skip_interval.map(usize::from)),
+            self.display_filter,
+            self.beep_retrigger,
+            self.flip_vertical,
+            self.flip_horizontal,
+            self.error_beep,
+            optional(self.frame_rate),
+            self.sprite_overrun,
+            self.shader_hint,
+            self.boot_splash,
+            self.clear_on_reset,
+            self.wrap_diag,
+            self.touch_keypad,
+            self.persist_options,
+            optional(self.cpu_speed),
+        )?;
+        #[cfg(feature = "debug-server")]
+        write!(f, " debug_server={}", self.debug_server)?;
+        Ok(())
+    }
+}
+
+/// Registers the core options with the frontend.
+///
+/// Frontends which support core options version 2 are given the options grouped into categories.
+/// Otherwise, or if that fails, the legacy variables interface is used.
+pub fn set_variables() {
+    if cb::env_get_core_options_version() >= 2 {
+        match set_core_options_v2() {
+            Ok(()) => return,
+            Err(e) => tracing::warn!("{:#}; falling back to legacy core options", e),
+        }
+    }
+
+    let definitions: Vec<_> = definitions().collect();
+    if let Err(e) = cb::env_set_variables(&definitions) {
+        tracing::warn!("{:#}", e);
+    }
+}
+
+fn set_core_options_v2() -> Result<()> {
+    let categories: Vec<_> = CATEGORIES
+        .iter()
+        .map(|&(key, desc, _)| (key, desc))
+        .collect();
+    let definitions: Vec<_> = definitions()
+        .map(|(key, description)| {
+            let (desc, values) = description.split_once("; ").unwrap_or((description, ""));
+            cb::CoreOptionV2 {
+                key,
+                category: CATEGORIES
+                    .iter()
+                    .find(|(_, _, keys)| keys.contains(&key))
+                    .map(|&(category, _, _)| category),
+                desc,
+                values: values.split('|').collect(),
+            }
+        })
+        .collect();
+    cb::env_set_core_options_v2(&categories, &definitions)
+}
+
+/// Re-reads all core options from the frontend.
+///
+/// Options which the frontend doesn't provide or which can't be parsed keep their default value.
+pub fn update() {
+    let mut options = Options::DEFAULT;
+
+    if let Some(value) = read_enabled(DRAW_WATCHDOG) {
+        options.draw_watchdog = value;
+    }
+    if let Some(value) = read_color(FOREGROUND_COLOR) {
+        options.palette.0[Palette::FOREGROUND] = value;
+    }
+    if let Some(value) = read_color(BACKGROUND_COLOR) {
+        options.palette.0[Palette::BACKGROUND] = value;
+    }
+    if let Some(value) = read_parsed::<usize>(BEEP_GAP_BRIDGE) {
+        options.beep_gap_bridge = value.min(MAX_BEEP_GAP_BRIDGE);
+    }
+    if let Some(value) = read_optional(TICK_BUDGET_MS, "disabled") {
+        options.tick_budget_ms = value;
+    }
+    if let Some(value) = read_enabled(INVERT) {
+        options.invert = value;
+    }
+    if let Some(value) = read_optional(STACK_SIZE, "unlimited") {
+        options.stack_size = value;
+    }
+    if let Some(value) = read_parsed(STACK_OVERFLOW) {
+        options.stack_overflow = value;
+    }
+    if let Some(value) = read_enabled(LOG_FRAME_HASH) {
+        options.log_frame_hash = value;
+    }
+    if let Some(value) = read_enabled(SHIFT_QUIRK) {
+        options.shift_quirk = value;
+    }
+    if let Some(value) = read_parsed(INVALID_INSTRUCTION) {
+        options.invalid_instruction = value;
+    }
+    if let Some(value) = read_parsed(INTERNAL_SCALE).filter(is_valid_internal_scale) {
+        options.internal_scale = value;
+    }
+    if let Some(value) = read_parsed(STARTUP_DELAY) {
+        options.startup_delay = value;
+    }
+    if let Some(value) = read_enabled(OPCODE_STATS) {
+        options.opcode_stats = value;
+    }
+    if let Some(value) = read_parsed(PAN).filter(is_valid_pan) {
+        options.pan = value;
+    }
+    if let Some(value) = read_enabled(TEST_PATTERN) {
+        options.test_pattern = value;
+    }
+    if let Some(value) = read_enabled(REALTIME_TIMERS) {
+        options.realtime_timers = value;
+    }
+    if let Some(value) = read_parsed::<usize>(GAME_SELECT).and_then(|n| n.checked_sub(1)) {
+        options.game_select = value;
+    }
+    if let Some(value) = read_parsed(DRAW_MODE) {
+        options.draw_mode = value;
+    }
+    if let Some(value) = read_optional(KEY_SKIP_INTERVAL, "disabled") {
+        options.key_skip_interval = value;
+    }
+    if let Some(value) = read_parsed(DISPLAY_FILTER) {
+        options.display_filter = value;
+    }
+    if let Some(value) = read_enabled(BEEP_RETRIGGER) {
+        options.beep_retrigger = value;
+    }
+    if let Some(value) = read_enabled(FLIP_VERTICAL) {
+        options.flip_vertical = value;
+    }
+    if let Some(value) = read_enabled(FLIP_HORIZONTAL) {
+        options.flip_horizontal = value;
+    }
+    if let Some(value) = read_enabled(ERROR_BEEP) {
+        options.error_beep = value;
+    }
+    if let Some(value) = read_optional(FRAME_RATE, "auto").filter(is_valid_frame_rate) {
+        options.frame_rate = value;
+    }
+    if let Some(value) = read_parsed(SPRITE_OVERRUN) {
+        options.sprite_overrun = value;
+    }
+    if let Some(value) = read_enabled(SHADER_HINT) {
+        options.shader_hint = value;
+    }
+    if let Some(value) = read_enabled(BOOT_SPLASH) {
+        options.boot_splash = value;
+    }
+    if let Some(value) = read_enabled(CLEAR_ON_RESET) {
+        options.clear_on_reset = value;
+    }
+    if let Some(value) = read_enabled(WRAP_DIAG) {
+        options.wrap_diag = value;
+    }
+    if let Some(value) = read_enabled(TOUCH_KEYPAD) {
+        options.touch_keypad = value;
+    }
+    if let Some(value) = read_enabled(PERSIST_OPTIONS) {
+        options.persist_options = value;
+    }
+    if let Some(value) = read_optional(CPU_SPEED, "unlimited").filter(is_valid_cpu_speed) {
+        options.cpu_speed = value;
+    }
+    #[cfg(feature = "debug-server")]
+    if let Some(value) = read_enabled(DEBUG_SERVER) {
+        options.debug_server = value;
+    }
+
+    OPTIONS.with(|cell| cell.set(options));
+    if options.persist_options {
+        save_persisted();
+    }
+}
+
+/// Re-reads all core options if the frontend reports that any of them have changed.
+pub fn update_if_changed() {
+    if cb::env_get_variable_update() {
+        update();
+    }
+}
+
+/// Returns the current values of all core options.
+pub fn get() -> Options {
+    OPTIONS.with(|cell| cell.get())
+}
+
+/// Replaces the current values of all core options, bypassing the frontend.
+#[cfg(test)]
+pub fn set(options: Options) {
+    OPTIONS.with(|cell| cell.set(options));
+}
+
+/// Forgets any imported and persisted option values, and resets all core options to their defaults.
+#[cfg(test)]
+pub fn reset() {
+    OVERRIDES.with(|cell| cell.borrow_mut().clear());
+    PERSISTED.with(|cell| cell.borrow_mut().clear());
+    set(Options::DEFAULT);
+}
+
+/// Returns the current values of all core options as a compact configuration string, which can be
+/// shared and later applied with [import].
+///
+/// The string is a `;`-separated list of `key=value` pairs, with keys missing their common prefix,
+/// e.g. `invert=enabled;stack_size=12`.
+pub fn export() -> String {
+    config_string(read)
+}
+
+/// Returns a configuration string of the values `read` returns for each option.
+fn config_string(read: impl Fn(&str) -> Option<String>) -> String {
+    definitions()
+        .filter_map(|(key, _)| {
+            let value = read(key)?;
+            Some(format!(
+                "{}={}",
+                key.strip_prefix(KEY_PREFIX).unwrap_or(key),
+                value
+            ))
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Applies all settings in a configuration string produced by [export].
+///
+/// The imported values override the frontend's values until another string is imported. The whole
+/// string is rejected if any key or value is unknown, so that a malformed string can't partially
+/// apply.
+pub fn import(config: &str) -> Result<()> {
+    let overrides = parse_config(config)?;
+    OVERRIDES.with(|cell| *cell.borrow_mut() = overrides);
+    update();
+    Ok(())
+}
+
+/// Parses a configuration string produced by [export] into (key, value) pairs, rejecting the whole
+/// string if any key or value is unknown.
+fn parse_config(config: &str) -> Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    for pair in config.trim().split(';').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| eyre!("expected key=value, found {pair:?}"))?;
+        let key = format!("{KEY_PREFIX}{key}");
+
+        let (_, description) = definitions()
+            .find(|&(name, _)| name == key)
+            .ok_or_else(|| eyre!("unknown option {key}"))?;
+        let valid_values = description
+            .split_once("; ")
+            .map_or("", |(_, values)| values);
+        if !valid_values.split('|').any(|valid| valid == value) {
+            return Err(eyre!("invalid value {value:?} for option {key}"));
+        }
+
+        pairs.push((key, value.to_string()));
+    }
+    Ok(pairs)
+}
+
+/// Loads the options persisted to the save directory, if any, as fallbacks for options the
+/// frontend doesn't provide. Must be called before the options are first read for a game.
+///
+/// A missing or unreadable file just leaves those options at their defaults, as does a file which
+/// doesn't itself have persisting enabled (e.g. one left behind after persisting was turned off).
+pub fn load_persisted() {
+    // Don't keep values persisted for a previous session
+    PERSISTED.with(|cell| cell.borrow_mut().clear());
+
+    let Some(path) = cb::env_get_save_directory().map(|dir| dir.join(PERSISTED_FILE_NAME)) else {
+        return;
+    };
+    if !path.exists() {
+        return;
+    }
+
+    let result = fs::read_to_string(&path)
+        .wrap_err("failed to read file")
+        .and_then(|config| parse_config(&config));
+    match result {
+        Ok(persisted) => {
+            let enabled = persisted
+                .iter()
+                .any(|(key, value)| key == PERSIST_OPTIONS && value == "enabled");
+            if !enabled {
+                tracing::debug!(
+                    "ignoring {} since it has persisting disabled",
+                    path.display()
+                );
+                return;
+            }
+            tracing::debug!("loaded persisted options from {}", path.display());
+            PERSISTED.with(|cell| *cell.borrow_mut() = persisted);
+        }
+        Err(e) => tracing::warn!("{}: {:#}", path.display(), e),
+    }
+}
+
+/// Writes the current options to the save directory, so that [load_persisted] can restore them.
+///
+/// Values imported from a configuration string are left out, since they only apply to the game
+/// they were imported for.
+fn save_persisted() {
+    let Some(path) = cb::env_get_save_directory().map(|dir| dir.join(PERSISTED_FILE_NAME)) else {
+        tracing::warn!("cannot persist options without a save directory");
+        return;
+    };
+    if let Err(e) = fs::write(&path, config_string(read_unimported)) {
+        tracing::warn!("failed to persist options to {}: {:#}", path.display(), e);
+    }
+}
+
+/// Imports the configuration string in the companion file next to the game at `game_path`, if
+/// there is one.
+pub fn load_companion_file(game_path: &Path) {
+    let path = game_path.with_extension(COMPANION_FILE_EXTENSION);
+    if !path.exists() {
+        // Don't keep settings imported for a previously loaded game
+        let cleared = OVERRIDES.with(|cell| mem::take(&mut *cell.borrow_mut()));
+        if !cleared.is_empty() {
+            update();
+        }
+        return;
+    }
+
+    let result = fs::read_to_string(&path)
+        .wrap_err("failed to read file")
+        .and_then(|config| import(&config));
+    match result {
+        Ok(()) => tracing::info!("imported options from {}", path.display()),
+        Err(e) => tracing::warn!("{}: {:#}", path.display(), e),
+    }
+}
+
+/// Returns the definitions of all options, including those for enabled cargo features.
+fn definitions() -> impl Iterator<Item = (&'static str, &'static str)> {
+    DEFINITIONS.iter().chain(FEATURE_DEFINITIONS).copied()
+}
+
+fn read(key: &str) -> Option<String> {
+    let imported = OVERRIDES.with(|cell| {
+        cell.borrow()
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.clone())
+    });
+    imported.or_else(|| read_unimported(key))
+}
+
+/// Reads an option's value from the frontend, falling back to the persisted value, ignoring any
+/// imported value.
+fn read_unimported(key: &str) -> Option<String> {
+    let value = cb::env_get_variable(key).unwrap_or_else(|e| {
+        tracing::warn!("{:#}", e);
+        None
+    });
+    value.or_else(|| {
+        PERSISTED.with(|cell| {
+            cell.borrow()
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value.clone())
+        })
+    })
+}
+
+fn read_parsed<T: FromStr>(key: &str) -> Option<T> {
+    let value = read(key)?;
+    value.parse().ok().or_else(|| {
+        tracing::warn!("invalid value {value:?} for option {key}");
+        None
+    })
+}
+
+/// Reads an option which is either `none_value` or a value to parse.
+fn read_optional<T: FromStr>(key: &str, none_value: &str) -> Option<Option<T>> {
+    let value = read(key)?;
+    if value == none_value {
+        return Some(None);
+    }
+    match value.parse() {
+        Ok(parsed) => Some(Some(parsed)),
+        Err(_) => {
+            tracing::warn!("invalid value {value:?} for option {key}");
+            None
+        }
+    }
+}
+
+fn read_enabled(key: &str) -> Option<bool> {
+    match read(key)?.as_str() {
+        "enabled" => Some(true),
+        "disabled" => Some(false),
+        value => {
+            tracing::warn!("invalid value {value:?} for option {key}");
+            None
+        }
+    }
+}
+
+/// Checks that an internal scale fits within the maximum geometry reported to the frontend.
+fn is_valid_internal_scale(&scale: &usize) -> bool {
+    let valid = scale > 0 && MAX_INTERNAL_SCALE % scale == 0;
+    if !valid {
+        tracing::warn!("invalid value {scale:?} for option {INTERNAL_SCALE}");
+    }
+    valid
+}
+
+fn is_valid_frame_rate(frame_rate: &Option<usize>) -> bool {
+    let valid = frame_rate.map_or(true, |frame_rate| {
+        SUPPORTED_FRAME_RATES.contains(&frame_rate)
+    });
+    if !valid {
+        tracing::warn!("invalid value {frame_rate:?} for option {FRAME_RATE}");
+    }
+    valid
+}
+
+fn is_valid_cpu_speed(&cpu_speed: &Option<usize>) -> bool {
+    let valid = cpu_speed != Some(0);
+    if !valid {
+        tracing::warn!("invalid value {cpu_speed:?} for option {CPU_SPEED}");
+    }
+    valid
+}
+
+fn is_valid_pan(pan: &i32) -> bool {
+    let valid = (-100..=100).contains(pan);
+    if !valid {
+        tracing::warn!("invalid value {pan:?} for option {PAN}");
+    }
+    valid
+}
+
+fn read_color(key: &str) -> Option<u16> {
+    let value = read(key)?;
+    match COLORS.iter().find(|&&(name, _)| name == value) {
+        Some(&(_, [r, g, b])) => Some(rgb888_to_rgb565(r, g, b)),
+        None => {
+            tracing::warn!("invalid value {value:?} for option {key}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libretro_defs as lr;
+    use std::{
+        ffi::{CStr, CString},
+        os::raw::{c_char, c_uint},
+        rc::Rc,
+    };
+
+    #[test]
+    fn summary_lists_the_settings() {
+        let mut options = Options {
+            invert: true,
+            stack_size: Some(12),
+            tick_budget_ms: None,
+            invalid_instruction: InvalidInstructionAction::Pause,
+            game_select: 2,
+            cpu_speed: Some(1000),
+            ..Options::default()
+        };
+        options.palette.0[Palette::FOREGROUND] = 0xFFE0;
+
+        let summary = options.to_string();
+        for setting in [
+            "foreground=0xffe0",
+            "background=0x0000",
+            "invert=true",
+            "stack_size=12",
+            "tick_budget_ms=none",
+            "invalid_instruction=Pause",
+            "game_select=3",
+            "cpu_speed=1000",
+        ] {
+            assert!(
+                summary.contains(setting),
+                "{setting:?} missing from {summary:?}"
+            );
+        }
+        assert!(!summary.contains('\n'));
+    }
+
+    #[test]
+    fn exported_configuration_imports_back() {
+        let _guard = cb::mock::install();
+        let config = "foreground_color=amber;invert=enabled;stack_size=12;draw_mode=overwrite";
+
+        import(config).unwrap();
+        let options = get();
+        assert_eq!(
+            options.palette.color(Palette::FOREGROUND),
+            rgb888_to_rgb565(0xFF, 0xB0, 0x00)
+        );
+        assert!(options.invert);
+        assert_eq!(options.stack_size, Some(12));
+        assert_eq!(options.draw_mode, DrawMode::Overwrite);
+
+        let exported = export();
+        assert_eq!(exported, config);
+        assert_eq!(
+            parse_config(&exported).unwrap(),
+            parse_config(config).unwrap()
+        );
+    }
+
+    #[test]
+    fn malformed_configuration_is_rejected_whole() {
+        let _guard = cb::mock::install();
+        import("invert=enabled").unwrap();
+
+        for config in [
+            "invert=maybe",
+            "stack_size=12;bogus=enabled",
+            "stack_size=12;invert",
+        ] {
+            assert!(import(config).is_err(), "{config:?} was accepted");
+        }
+        assert_eq!(export(), "invert=enabled");
+        assert_eq!(get().stack_size, None);
+    }
+
+    #[test]
+    fn persisted_options_apply_to_the_next_session_without_imported_values() {
+        let _guard = cb::mock::install();
+        let save_dir =
+            std::env::temp_dir().join(format!("trustychip-test-{}-persist", std::process::id()));
+        fs::create_dir_all(&save_dir).unwrap();
+
+        // A frontend with the given option values, as (key, value) pairs
+        let frontend_values = Rc::new(RefCell::new(vec![
+            (PERSIST_OPTIONS, CString::new("enabled").unwrap()),
+            (INVERT, CString::new("enabled").unwrap()),
+        ]));
+        cb::mock::set_environment({
+            let save_dir = CString::new(save_dir.to_str().unwrap()).unwrap();
+            let frontend_values = frontend_values.clone();
+            move |cmd, data| unsafe {
+                match cmd {
+                    lr::RETRO_ENVIRONMENT_GET_SAVE_DIRECTORY => {
+                        *(data as *mut *const c_char) = save_dir.as_ptr();
+                        true
+                    }
+                    lr::RETRO_ENVIRONMENT_GET_VARIABLE => {
+                        let variable = &mut *(data as *mut lr::retro_variable);
+                        let key = CStr::from_ptr(variable.key).to_str().unwrap_or_default();
+                        let values = frontend_values.borrow();
+                        let value = values.iter().find(|&&(name, _)| name == key);
+                        variable.value =
+                            value.map_or(std::ptr::null(), |(_, value)| value.as_ptr());
+                        true
+                    }
+                    _ => false,
+                }
+            }
+        });
+
+        // Persisting happens whenever the options are read
+        import("stack_size=12").unwrap();
+        assert_eq!(get().stack_size, Some(12));
+        let persisted = fs::read_to_string(save_dir.join(PERSISTED_FILE_NAME)).unwrap();
+        assert_eq!(persisted, "invert=enabled;persist_options=enabled");
+
+        // The next session, with a frontend which doesn't keep the values
+        OVERRIDES.with(|cell| cell.borrow_mut().clear());
+        frontend_values.borrow_mut().clear();
+        load_persisted();
+        update();
+        let options = get();
+        assert!(options.invert && options.persist_options);
+        assert_eq!(options.stack_size, None);
+
+        fs::remove_dir_all(&save_dir).unwrap();
+    }
+
+    #[test]
+    fn persisted_options_are_dropped_without_a_file_which_enables_persisting() {
+        let _guard = cb::mock::install();
+        let save_dir =
+            std::env::temp_dir().join(format!("trustychip-test-{}-unpersist", std::process::id()));
+        fs::create_dir_all(&save_dir).unwrap();
+        cb::mock::set_environment({
+            let save_dir = CString::new(save_dir.to_str().unwrap()).unwrap();
+            move |cmd, data| unsafe {
+                match cmd {
+                    lr::RETRO_ENVIRONMENT_GET_SAVE_DIRECTORY => {
+                        *(data as *mut *const c_char) = save_dir.as_ptr();
+                        true
+                    }
+                    _ => false,
+                }
+            }
+        });
+        let path = save_dir.join(PERSISTED_FILE_NAME);
+        // Returns whether the persisted file inverts the colors after loading it
+        let load = || {
+            load_persisted();
+            update();
+            get().invert
+        };
+
+        fs::write(&path, "invert=enabled;persist_options=enabled").unwrap();
+        assert!(load());
+        fs::write(&path, "invert=enabled").unwrap();
+        assert!(!load());
+
+        fs::write(&path, "invert=enabled;persist_options=enabled").unwrap();
+        assert!(load());
+        fs::remove_file(&path).unwrap();
+        assert!(!load());
+
+        fs::remove_dir_all(&save_dir).unwrap();
+    }
+
+    /// Registers the options with a frontend supporting `version` of the core options interface
+    /// which fails to set v2 options if `v2_fails`, returning the commands sent and the number of
+    /// legacy variables set.
+    fn register_options(version: c_uint, v2_fails: bool) -> (Vec<c_uint>, usize) {
+        let _guard = cb::mock::install();
+        let commands = Rc::new(RefCell::new(Vec::new()));
+        let variables = Rc::new(Cell::new(0));
+        cb::mock::set_environment({
+            let (commands, variables) = (commands.clone(), variables.clone());
+            move |cmd, data| unsafe {
+                commands.borrow_mut().push(cmd);
+                match cmd {
+                    lr::RETRO_ENVIRONMENT_GET_CORE_OPTIONS_VERSION => {
+                        *(data as *mut c_uint) = version;
+                        true
+                    }
+                    lr::RETRO_ENVIRONMENT_SET_CORE_OPTIONS_V2 => !v2_fails,
+                    lr::RETRO_ENVIRONMENT_SET_VARIABLES => {
+                        let array = data as *const lr::retro_variable;
+                        let count = (0..)
+                            .take_while(|&i| !(*array.add(i)).key.is_null())
+                            .count();
+                        variables.set(count);
+                        true
+                    }
+                    _ => false,
+                }
+            }
+        });
+
+        set_variables();
+        let commands = commands.borrow().clone();
+        (commands, variables.get())
+    }
+
+    #[test]
+    fn legacy_variables_are_used_without_core_options_v2() {
+        use lr::{
+            RETRO_ENVIRONMENT_GET_CORE_OPTIONS_VERSION as GET_VERSION,
+            RETRO_ENVIRONMENT_SET_CORE_OPTIONS_V2 as SET_V2,
+            RETRO_ENVIRONMENT_SET_VARIABLES as SET_VARIABLES,
+        };
+        let count = definitions().count();
+
+        assert_eq!(register_options(2, false), (vec![GET_VERSION, SET_V2], 0));
+        assert_eq!(
+            register_options(1, false),
+            (vec![GET_VERSION, SET_VARIABLES], count)
+        );
+        // A frontend claiming v2 support but failing to set the options falls back too
+        assert_eq!(
+            register_options(2, true),
+            (vec![GET_VERSION, SET_V2, SET_VARIABLES], count)
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use bitvec::prelude::*;
+use std::hash::Hasher;
 
 // Helper macros
 
@@ -9,11 +10,90 @@ macro_rules! c_str {
     } };
 }
 
+// Color conversion
+
+/// Converts a 24-bit RGB color into the RGB565 pixel format.
+///
+/// Each channel is rounded to the nearest representable value rather than truncated, so that
+/// e.g. mid-range grays don't end up noticeably darker than intended.
+pub const fn rgb888_to_rgb565(r: u8, g: u8, b: u8) -> u16 {
+    const fn scale(channel: u8, max: u32) -> u16 {
+        ((channel as u32 * max + 127) / 255) as u16
+    }
+    (scale(r, 0x1F) << 11) | (scale(g, 0x3F) << 5) | scale(b, 0x1F)
+}
+
+/// Scales up a framebuffer `width` pixels wide by an integer factor into `scaled`, duplicating each
+/// pixel into a `scale` x `scale` block.
+///
+/// `scaled` is cleared first, so the same buffer can be reused every frame without reallocating.
+pub fn scale_framebuffer(frame: &[u16], width: usize, scale: usize, scaled: &mut Vec<u16>) {
+    scaled.clear();
+    scaled.reserve(frame.len() * scale * scale);
+    for row in frame.chunks_exact(width) {
+        let row_start = scaled.len();
+        for &pixel in row {
+            scaled.extend(std::iter::repeat(pixel).take(scale));
+        }
+        for _ in 1..scale {
+            scaled.extend_from_within(row_start..row_start + width * scale);
+        }
+    }
+}
+
+/// Flips a framebuffer `width` pixels wide in place, reversing the order of its rows if `vertical`
+/// and the order of the pixels within each row if `horizontal`.
+pub fn flip_framebuffer(frame: &mut [u16], width: usize, vertical: bool, horizontal: bool) {
+    match (vertical, horizontal) {
+        (false, false) => {}
+        // Reversing the whole buffer reverses both the rows and the pixels within them
+        (true, true) => frame.reverse(),
+        (true, false) => {
+            let height = frame.len() / width;
+            for row in 0..height / 2 {
+                let (top, bottom) = frame.split_at_mut((height - 1 - row) * width);
+                top[row * width..(row + 1) * width].swap_with_slice(&mut bottom[..width]);
+            }
+        }
+        (false, true) => frame.chunks_exact_mut(width).for_each(<[u16]>::reverse),
+    }
+}
+
+// Hashing
+
+/// A 64-bit FNV-1a hasher.
+///
+/// Unlike std's `DefaultHasher`, the output is stable across Rust versions and platforms as long
+/// as the same bytes are written, which makes it suitable for comparing emulator state between
+/// separate runs. Callers should only use [Hasher::write] with explicitly-ordered bytes.
+pub struct Fnv1aHasher(u64);
+
+impl Default for Fnv1aHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1aHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
 // Extension traits
 
 /// An extension trait which adds methods to bitvec's BitSlice.
 pub trait BitSliceExt {
     /// Splits a BitSlice into three BitSlices at the two provided indices.
+    ///
+    /// The indices must be in order and in bounds, which is only checked in debug builds.
     fn split_at_two(&self, first: usize, second: usize) -> (&Self, &Self, &Self);
 }
 
@@ -23,10 +103,116 @@ where
     O: BitOrder,
 {
     fn split_at_two(&self, first: usize, second: usize) -> (&Self, &Self, &Self) {
-        assert!(first <= second, "first index must be <= second");
-        assert!(second <= self.len(), "index out of bounds");
+        debug_assert!(first <= second, "first index must be <= second");
+        debug_assert!(second <= self.len(), "index out of bounds");
         let (a, rest) = unsafe { self.split_at_unchecked(first) };
         let (b, c) = unsafe { rest.split_at_unchecked(second - first) };
         (a, b, c)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Converts an RGB565 color back into 24-bit RGB, rounding each channel to the nearest value.
+    fn rgb565_to_rgb888(color: u16) -> (u8, u8, u8) {
+        let scale = |channel: u16, max: u32| ((channel as u32 * 255 + max / 2) / max) as u8;
+        (
+            scale(color >> 11, 0x1F),
+            scale(color >> 5 & 0x3F, 0x3F),
+            scale(color & 0x1F, 0x1F),
+        )
+    }
+
+    #[test]
+    fn scale_framebuffer_duplicates_pixels_into_reused_buffer() {
+        let mut scaled = Vec::new();
+        scale_framebuffer(&[1, 2, 3, 4], 2, 2, &mut scaled);
+        assert_eq!(scaled, [1, 1, 2, 2, 1, 1, 2, 2, 3, 3, 4, 4, 3, 3, 4, 4]);
+
+        let buffer = scaled.as_ptr();
+        scale_framebuffer(&[5, 6, 7, 8], 2, 2, &mut scaled);
+        assert_eq!(scaled, [5, 5, 6, 6, 5, 5, 6, 6, 7, 7, 8, 8, 7, 7, 8, 8]);
+        assert_eq!(scaled.as_ptr(), buffer, "buffer was reallocated");
+
+        scale_framebuffer(&[1, 2], 1, 1, &mut scaled);
+        assert_eq!(scaled, [1, 2]);
+    }
+
+    #[test]
+    fn flip_framebuffer_reverses_rows_and_columns() {
+        // 3 pixels wide, with odd and even heights to cover a middle row that stays put
+        for height in [4, 5] {
+            let frame: Vec<u16> = (0..3 * height).collect();
+            let rows: Vec<_> = frame.chunks_exact(3).collect();
+            let flipped = |vertical, horizontal| {
+                let mut flipped = frame.clone();
+                flip_framebuffer(&mut flipped, 3, vertical, horizontal);
+                flipped
+            };
+
+            assert_eq!(flipped(false, false), frame);
+
+            let vertical = flipped(true, false);
+            assert_eq!(vertical[..3], *rows[height as usize - 1]);
+            assert_eq!(vertical[vertical.len() - 3..], *rows[0]);
+            assert_eq!(
+                vertical,
+                rows.iter()
+                    .rev()
+                    .copied()
+                    .flatten()
+                    .copied()
+                    .collect::<Vec<_>>()
+            );
+
+            let horizontal = flipped(false, true);
+            assert_eq!(horizontal[..3], [2, 1, 0]);
+            assert_eq!(
+                horizontal,
+                rows.iter()
+                    .flat_map(|row| row.iter().rev().copied())
+                    .collect::<Vec<_>>()
+            );
+
+            let both = flipped(true, true);
+            assert_eq!(both, frame.iter().rev().copied().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn rgb888_to_rgb565_rounds_to_nearest() {
+        assert_eq!(rgb888_to_rgb565(0x00, 0x00, 0x00), 0x0000);
+        assert_eq!(rgb888_to_rgb565(0xFF, 0xFF, 0xFF), 0xFFFF);
+
+        // Truncation would give 31 and 0 here
+        assert_eq!(rgb888_to_rgb565(0xF8, 0x00, 0x00), 30 << 11);
+        assert_eq!(rgb888_to_rgb565(0x00, 0x00, 0x07), 1);
+        assert_eq!(rgb888_to_rgb565(0x00, 0x03, 0x00), 1 << 5);
+        assert_eq!(rgb888_to_rgb565(0x00, 0x01, 0x00), 0);
+    }
+
+    #[test]
+    fn rgb565_round_trips_through_rgb888() {
+        for color in 0..=u16::MAX {
+            let (r, g, b) = rgb565_to_rgb888(color);
+            assert_eq!(rgb888_to_rgb565(r, g, b), color);
+        }
+    }
+
+    #[test]
+    fn rgb888_round_trip_error_is_within_half_a_step() {
+        for channel in 0..=u8::MAX {
+            let (r, g, _) = rgb565_to_rgb888(rgb888_to_rgb565(channel, channel, 0));
+            assert!(
+                r.abs_diff(channel) <= 255 / 0x1F / 2 + 1,
+                "red {channel} became {r}"
+            );
+            assert!(
+                g.abs_diff(channel) <= 255 / 0x3F / 2 + 1,
+                "green {channel} became {g}"
+            );
+        }
+    }
+}